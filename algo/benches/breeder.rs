@@ -6,7 +6,7 @@ fn breeder_benchmark(c: &mut Criterion) {
     let src = fs::read_to_string("tests/fixtures/Breeder.lif").unwrap();
     let mut uni = algo::rle::read(src).unwrap();
 
-    uni.simulate(black_box(100000));
+    uni.simulate(black_box(100000)).unwrap();
   }));
 }
 
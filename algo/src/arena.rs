@@ -0,0 +1,256 @@
+use rustc_hash::FxHashMap;
+use std::hash::Hash;
+use crate::node::*;
+use crate::store::{GcConfig, MemoryLimitExceeded, Stats};
+
+/// A node that can be hash-consed in an [`Arena`].
+pub(crate) trait ArenaNode {
+  type Key: Copy + Eq + Hash;
+
+  fn level(&self) -> u16;
+
+  fn mark_mut(&mut self) -> &mut bool;
+
+  /// Calls `f` with the nodes that this node keeps alive, i.e. its children
+  /// and its result.
+  fn for_each_ref(&self, f: impl FnMut(NodeId));
+}
+
+/// Hash-consed nodes with garbage collection, shared by the stores of
+/// two-state and multi-state universes.
+///
+/// Nodes are collected by mark and sweep. Besides the roots given by the
+/// owner of the arena, the nodes in `gc_roots` survive, which is how nodes in
+/// use by a computation are kept alive by collections in the middle of it.
+pub(crate) struct Arena<N: ArenaNode> {
+  /// Indexed by `NodeId`. Freed slots are recycled through `free_ids`.
+  nodes: Vec<N>,
+  free_ids: Vec<NodeId>,
+  /// Hash-consing table of live nodes.
+  table: FxHashMap<N::Key, NodeId>,
+  gc_roots: Vec<NodeId>,
+  gc_threshold: usize,
+  gc_config: GcConfig,
+  pub(crate) find_hits: u64,
+  pub(crate) find_misses: u64,
+  pub(crate) memo_hits: u64,
+  gc_runs: u64,
+  gc_freed: u64,
+}
+
+impl<N: ArenaNode> Arena<N> {
+  pub(crate) fn new(gc_config: GcConfig) -> Self {
+    Self {
+      nodes: vec![],
+      free_ids: vec![],
+      table: FxHashMap::default(),
+      gc_roots: vec![],
+      gc_threshold: gc_config.initial_threshold,
+      gc_config,
+      find_hits: 0,
+      find_misses: 0,
+      memo_hits: 0,
+      gc_runs: 0,
+      gc_freed: 0,
+    }
+  }
+
+  #[inline]
+  pub(crate) fn node(&self, id: NodeId) -> &N {
+    &self.nodes[id.0 as usize]
+  }
+  #[inline]
+  pub(crate) fn node_mut(&mut self, id: NodeId) -> &mut N {
+    &mut self.nodes[id.0 as usize]
+  }
+  /// All slots, including the freed ones.
+  pub(crate) fn nodes_mut(&mut self) -> &mut [N] {
+    &mut self.nodes
+  }
+  /// Number of live nodes.
+  #[inline]
+  pub(crate) fn num_nodes(&self) -> usize {
+    self.table.len()
+  }
+  /// Number of ids in use, including the ids of freed nodes.
+  pub(crate) fn num_ids(&self) -> usize {
+    self.nodes.len()
+  }
+  /// Ids of freed nodes, which are reused from the last one.
+  pub(crate) fn free_ids(&self) -> &[NodeId] {
+    &self.free_ids
+  }
+  pub(crate) fn gc_config(&self) -> GcConfig {
+    self.gc_config
+  }
+  /// The new settings take effect from the next collection.
+  pub(crate) fn set_gc_config(&mut self, gc_config: GcConfig) {
+    self.gc_config = gc_config;
+  }
+  /// Returns the node of `key` if it exists.
+  #[inline]
+  pub(crate) fn lookup(&self, key: &N::Key) -> Option<NodeId> {
+    self.table.get(key).copied()
+  }
+  /// Returns the node of `key`, or roots the node created by `new` if it
+  /// does not exist.
+  #[inline]
+  pub(crate) fn find(
+    &mut self,
+    key: N::Key,
+    new: impl FnOnce(&Self) -> N,
+  ) -> NodeId {
+    if let Some(&id) = self.table.get(&key) {
+      self.find_hits += 1;
+      return id;
+    }
+
+    self.find_misses += 1;
+    let node = new(self);
+    let id = self.insert(key, node);
+    self.gc_root(id);
+    id
+  }
+  /// Adds a node that does not exist yet.
+  #[inline]
+  pub(crate) fn insert(&mut self, key: N::Key, node: N) -> NodeId {
+    let id = match self.free_ids.pop() {
+      Some(id) => {
+        *self.node_mut(id) = node;
+        id
+      }
+      None => {
+        assert!(self.nodes.len() < INVALID_NODE_ID.0 as usize,
+          "too many nodes");
+        self.nodes.push(node);
+        NodeId(self.nodes.len() as u32 - 1)
+      }
+    };
+    self.table.insert(key, id);
+    id
+  }
+  /// Like `find`, but collects garbage when the memory limit is reached, so
+  /// every node in use must be reachable from `gc_roots` or `roots`. The found
+  /// node is rooted even if it already existed.
+  #[inline]
+  pub(crate) fn try_find<I>(
+    &mut self,
+    key: N::Key,
+    new: impl FnOnce(&Self) -> N,
+    roots: impl FnOnce() -> I,
+  ) -> Result<NodeId, MemoryLimitExceeded>
+  where
+    I: IntoIterator<Item = NodeId>,
+  {
+    if let Some(&id) = self.table.get(&key) {
+      self.find_hits += 1;
+      self.gc_root(id);
+      return Ok(id);
+    }
+
+    if let Some(max_nodes) = self.gc_config.max_nodes {
+      if self.num_nodes() >= max_nodes {
+        self.gc(roots());
+        if self.num_nodes() > max_nodes / 4 * 3 {
+          return Err(MemoryLimitExceeded);
+        }
+      }
+    }
+    Ok(self.find(key, new))
+  }
+  /// Whether garbage should be collected before the next step.
+  pub(crate) fn needs_gc(&self) -> bool {
+    self.num_nodes() >= self.gc_threshold
+  }
+  #[inline]
+  pub(crate) fn gc_root(&mut self, node: NodeId) {
+    self.gc_roots.push(node);
+  }
+  #[inline]
+  pub(crate) fn gc_save(&mut self) -> usize {
+    self.gc_roots.len()
+  }
+  #[inline]
+  pub(crate) fn gc_restore(&mut self, s: usize) {
+    self.gc_roots.truncate(s);
+  }
+  pub(crate) fn clear_gc_roots(&mut self) {
+    self.gc_roots.clear();
+  }
+  /// Frees the nodes unreachable from `gc_roots` and `roots`.
+  pub(crate) fn gc(&mut self, roots: impl IntoIterator<Item = NodeId>) {
+    let num_nodes = self.num_nodes();
+    self.gc_mark(roots);
+    self.gc_sweep();
+    self.gc_runs += 1;
+    self.gc_freed += (num_nodes - self.num_nodes()) as u64;
+    let threshold =
+      self.num_nodes() * self.gc_config.threshold_increment / 100 + 1;
+    self.gc_threshold = match self.gc_config.max_nodes {
+      Some(max_nodes) => threshold.min(max_nodes),
+      None => threshold,
+    };
+  }
+  fn gc_mark(&mut self, roots: impl IntoIterator<Item = NodeId>) {
+    let gc_s = self.gc_save();
+    self.gc_roots.extend(roots);
+
+    let mut i = 0;
+    while i < self.gc_roots.len() {
+      let node = &mut self.nodes[self.gc_roots[i].0 as usize];
+      if *node.mark_mut() {
+        i += 1;
+        continue;
+      }
+
+      *node.mark_mut() = true;
+      let gc_roots = &mut self.gc_roots;
+      node.for_each_ref(|id| gc_roots.push(id));
+    }
+
+    self.gc_restore(gc_s);
+  }
+  fn gc_sweep(&mut self) {
+    let nodes = &mut self.nodes;
+    let free_ids = &mut self.free_ids;
+    self.table.retain(|_, &mut id| {
+      let mark = nodes[id.0 as usize].mark_mut();
+      if *mark {
+        *mark = false;
+        true
+      } else {
+        free_ids.push(id);
+        false
+      }
+    });
+  }
+  /// `heap_bytes` is the memory used by the owner of the arena besides the
+  /// arena.
+  pub(crate) fn stats(&self, heap_bytes: usize) -> Stats {
+    let mut nodes_per_level = vec![];
+    for &id in self.table.values() {
+      let level = self.node(id).level() as usize;
+      if nodes_per_level.len() <= level {
+        nodes_per_level.resize(level + 1, 0);
+      }
+      nodes_per_level[level] += 1;
+    }
+
+    let heap_bytes = heap_bytes +
+      self.nodes.capacity() * std::mem::size_of::<N>() +
+      self.table.capacity() * (std::mem::size_of::<(N::Key, NodeId)>() + 1) +
+      (self.free_ids.capacity() + self.gc_roots.capacity()) *
+        std::mem::size_of::<NodeId>();
+
+    Stats {
+      nodes_per_level,
+      find_hits: self.find_hits,
+      find_misses: self.find_misses,
+      memo_hits: self.memo_hits,
+      gc_runs: self.gc_runs,
+      gc_freed: self.gc_freed,
+      gc_threshold: self.gc_threshold,
+      heap_bytes,
+    }
+  }
+}
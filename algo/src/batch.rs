@@ -0,0 +1,102 @@
+use std::sync::Mutex;
+use std::thread;
+use crate::store::MemoryLimitExceeded;
+use crate::universe::Universe;
+
+/// Applies `f` to every input on `num_threads` worker threads, and returns
+/// the outputs in the order of the inputs.
+///
+/// If `num_threads` is 0, the available parallelism is used.
+pub fn run<T, R, F>(inputs: Vec<T>, num_threads: usize, f: F) -> Vec<R>
+where
+  T: Send,
+  R: Send,
+  F: Fn(T) -> R + Sync,
+{
+  let num_threads = if num_threads == 0 {
+    thread::available_parallelism().map_or(1, |n| n.get())
+  } else {
+    num_threads
+  };
+  let num_inputs = inputs.len();
+  let queue = Mutex::new(inputs.into_iter().enumerate());
+  let results = Mutex::new((0..num_inputs).map(|_| None).collect::<Vec<_>>());
+
+  thread::scope(|scope| {
+    for _ in 0..num_threads.min(num_inputs) {
+      scope.spawn(|| loop {
+        // the lock is released before running the job
+        let job = queue.lock().unwrap().next();
+        let (i, input) = match job {
+          Some(job) => job,
+          None => break,
+        };
+        let output = f(input);
+        results.lock().unwrap()[i] = Some(output);
+      });
+    }
+  });
+
+  results.into_inner().unwrap()
+    .into_iter()
+    .map(Option::unwrap)
+    .collect()
+}
+
+/// Simulates every universe for the paired number of generations on
+/// `num_threads` worker threads.
+///
+/// Universes sharing a store are simulated one at a time, so each universe
+/// should have its own store to run in parallel.
+pub fn simulate(
+  jobs: Vec<(Universe, usize)>,
+  num_threads: usize,
+) -> Vec<(Universe, Result<(), MemoryLimitExceeded>)> {
+  run(jobs, num_threads, |(mut uni, num_gen)| {
+    let result = uni.simulate(num_gen);
+    (uni, result)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::rule::GAME_OF_LIFE;
+  use crate::universe::Boundary;
+
+  fn r_pentomino() -> Universe {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    for &(x, y) in &[(0, -1), (1, -1), (-1, 0), (0, 0), (0, 1)] {
+      uni.set(x, y, true);
+    }
+    uni
+  }
+
+  #[test]
+  fn test_universe_is_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<Universe>();
+  }
+
+  #[test]
+  fn test_run_keeps_order() {
+    let outputs = run((0..100).collect(), 4, |i: u32| i * i);
+    assert_eq!(outputs, (0..100).map(|i| i * i).collect::<Vec<_>>());
+    assert!(run(vec![], 0, |i: u32| i).is_empty());
+  }
+
+  #[test]
+  fn test_simulate() {
+    let jobs = (0..8).map(|i| (r_pentomino(), i * 50)).collect();
+    let results = simulate(jobs, 3);
+
+    let rect = Boundary { left: -64, top: -64, right: 64, bottom: 64 };
+    for (i, (uni, result)) in results.into_iter().enumerate() {
+      assert_eq!(result, Ok(()));
+      let mut expected = r_pentomino();
+      expected.simulate(i * 50).unwrap();
+      assert_eq!(uni.generation(), expected.generation());
+      assert_eq!(uni.get_rect(&rect), expected.get_rect(&rect));
+    }
+  }
+}
@@ -0,0 +1,139 @@
+use crate::multistate::MultiRule;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// A colored variant of Life, in which the alive cells have colors `1..=n`
+/// and a newborn cell takes the majority color of its three parents.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ColorLife {
+  num_colors: u8,
+}
+
+impl ColorLife {
+  /// Two colors.
+  pub const IMMIGRATION: ColorLife = ColorLife { num_colors: 2 };
+  /// Four colors. A cell whose parents have three different colors takes the
+  /// fourth one.
+  pub const QUADLIFE: ColorLife = ColorLife { num_colors: 4 };
+
+  pub fn num_colors(&self) -> u8 {
+    self.num_colors
+  }
+
+  /// Returns the color of a cell born from `parents`.
+  fn birth_color(&self, parents: [u8; 3]) -> u8 {
+    let [a, b, c] = parents;
+    if a == b || a == c {
+      a
+    } else if b == c {
+      b
+    } else {
+      // only with 4 colors
+      10 - a - b - c
+    }
+  }
+}
+
+impl MultiRule for ColorLife {
+  fn num_states(&self) -> usize {
+    self.num_colors as usize + 1
+  }
+
+  fn step(&self, cells: &[u8], size: usize, next: &mut [u8]) {
+    let inner = size - 2;
+    for y in 0..inner {
+      for x in 0..inner {
+        let mut parents = [0; 3];
+        let mut num = 0;
+        for i in (0..9).filter(|&i| i != 4) {
+          let state = cells[(y + i / 3) * size + x + i % 3];
+          if state != 0 {
+            if num < 3 {
+              parents[num] = state;
+            }
+            num += 1;
+          }
+        }
+
+        let state = cells[(y + 1) * size + x + 1];
+        next[y * inner + x] = match (state, num) {
+          (0, 3) => self.birth_color(parents),
+          (_, 2) | (_, 3) => state,
+          _ => 0,
+        };
+      }
+    }
+  }
+}
+
+impl Display for ColorLife {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self.num_colors {
+      2 => f.write_str("Immigration"),
+      _ => f.write_str("QuadLife"),
+    }
+  }
+}
+
+impl FromStr for ColorLife {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match &s.to_ascii_lowercase()[..] {
+      "immigration" => Ok(Self::IMMIGRATION),
+      "quadlife" => Ok(Self::QUADLIFE),
+      _ => Err(format!("unknown rule {:?}", s)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_step() {
+    for rule in &[ColorLife::IMMIGRATION, ColorLife::QUADLIFE] {
+      let size = 24;
+      let mut seed = 1u32;
+      let cells = (0..size * size).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        // mostly dead cells, so that births are frequent
+        let state = (seed >> 16) as usize % (2 * rule.num_states());
+        state.saturating_sub(rule.num_states()) as u8
+      }).collect::<Vec<_>>();
+      let inner = size - 2;
+      let mut next = vec![0; inner * inner];
+      rule.step(&cells, size, &mut next);
+
+      for y in 1..size - 1 {
+        for x in 1..size - 1 {
+          let mut counts = [0; 5];
+          for i in (0..9).filter(|&i| i != 4) {
+            counts[cells[(y + i / 3 - 1) * size + x + i % 3 - 1] as usize] += 1;
+          }
+          let num = counts[1..].iter().sum::<usize>();
+          let state = cells[y * size + x];
+          let expected = match (state, num) {
+            (0, 3) => {
+              match (1..5).find(|&c| counts[c] >= 2) {
+                Some(c) => c as u8,
+                None => (1..5).find(|&c| counts[c] == 0).unwrap() as u8,
+              }
+            }
+            (s, 2) | (s, 3) => s,
+            _ => 0,
+          };
+          assert_eq!(next[(y - 1) * inner + x - 1], expected, "{} ({}, {})", rule, x, y);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_parse() {
+    assert_eq!("Immigration".parse::<ColorLife>().unwrap(), ColorLife::IMMIGRATION);
+    assert_eq!("quadlife".parse::<ColorLife>().unwrap().to_string(), "QuadLife");
+    assert!("Life".parse::<ColorLife>().is_err());
+  }
+}
@@ -1,4 +1,5 @@
 use crate::universe::*;
+use crate::multistate::MultiUniverse;
 
 pub fn write_buffer(uni: &Universe) -> Vec<Vec<u8>> {
   let viewport@Boundary {left, top, right, bottom} = uni.boundary();
@@ -9,7 +10,7 @@ pub fn write_buffer(uni: &Universe) -> Vec<Vec<u8>> {
   let level = uni.level();
   let w = right - left;
   let h = (bottom - top) as usize;
-  let bw = w + 7 >> 3;
+  let bw = (w + 7) >> 3;
   let mut buffer = vec![vec![0u8; bw as usize]; h];
   let shift = (if level == 3 { left + 4 } else { left }).rem_euclid(8);
 
@@ -42,7 +43,7 @@ pub fn write_buffer(uni: &Universe) -> Vec<Vec<u8>> {
             buffer[(y + i) as usize][bx1 as usize] |= b << shift;
           }
           if bx0 >= 0 {
-            buffer[(y + i) as usize][bx0 as usize] |= b >> 8 - shift;
+            buffer[(y + i) as usize][bx0 as usize] |= b >> (8 - shift);
           }
         }
       }
@@ -69,4 +70,19 @@ pub fn write_cells(
   univ.write_cells(viewport, |nw, ne, sw, se, x, y| {
     f(CellData { nw, ne, sw, se, x, y })
   })
-}
\ No newline at end of file
+}
+
+pub struct StateData {
+  pub x: i64,
+  pub y: i64,
+  pub state: u8,
+}
+
+/// Calls `f` with every cell in a non-zero state inside `viewport`.
+pub fn write_states(
+  univ: &MultiUniverse,
+  viewport: &Boundary,
+  mut f: impl FnMut(StateData),
+) {
+  univ.write_states(viewport, |x, y, state| f(StateData { x, y, state }))
+}
@@ -0,0 +1,144 @@
+use crate::multistate::MultiRule;
+use crate::rule::NeighborMask;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// A Generations rule, in which a cell that does not survive decays through
+/// the states 2, 3, ... before it becomes dead.
+///
+/// Only the alive cells (state 1) are counted as neighbors.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Generations {
+  birth: NeighborMask,
+  survival: NeighborMask,
+  num_states: u16,
+}
+
+impl Generations {
+  /// `/2/3`
+  pub const BRIANS_BRAIN: Generations = Generations {
+    birth: 1 << 2,
+    survival: 0,
+    num_states: 3,
+  };
+
+  /// Returns a rule with `num_states` states, which is at least 2 and at most
+  /// 256, in which nothing is born and nothing survives.
+  pub fn new(num_states: u16) -> Self {
+    assert!((2..=256).contains(&num_states), "invalid number of states");
+    Self { birth: 0, survival: 0, num_states }
+  }
+
+  pub fn set_birth(&mut self, num: u8) {
+    assert!(num != 0, "B0 is not supported in Generations rules");
+    self.birth |= 1 << num;
+  }
+
+  pub fn set_survival(&mut self, num: u8) {
+    self.survival |= 1 << num;
+  }
+
+  pub fn is_birth(&self, num: u8) -> bool {
+    self.birth & 1 << num != 0
+  }
+
+  pub fn is_survival(&self, num: u8) -> bool {
+    self.survival & 1 << num != 0
+  }
+}
+
+impl MultiRule for Generations {
+  fn num_states(&self) -> usize {
+    self.num_states as usize
+  }
+
+  fn step(&self, cells: &[u8], size: usize, next: &mut [u8]) {
+    let inner = size - 2;
+    for y in 0..inner {
+      for x in 0..inner {
+        let state = cells[(y + 1) * size + x + 1];
+        next[y * inner + x] = match state {
+          0 | 1 => {
+            let num = (0..3)
+              .flat_map(|dy| (0..3).map(move |dx| (dx, dy)))
+              .filter(|&(dx, dy)| (dx, dy) != (1, 1) && cells[(y + dy) * size + x + dx] == 1)
+              .count() as u8;
+            match state {
+              0 => self.is_birth(num) as u8,
+              _ if self.is_survival(num) => 1,
+              _ => (2 % self.num_states) as u8,
+            }
+          }
+          _ => ((state as u16 + 1) % self.num_states) as u8,
+        };
+      }
+    }
+  }
+}
+
+impl Display for Generations {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let digits = |mask: NeighborMask| {
+      (0..=8).filter(|&i| mask & 1 << i != 0).map(|i| i.to_string()).collect::<String>()
+    };
+    write!(f, "{}/{}/{}", digits(self.survival), digits(self.birth), self.num_states)
+  }
+}
+
+impl FromStr for Generations {
+  type Err = String;
+
+  /// Parses `S/B/C` such as `23/3/8`, or `B3/S23/C8`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let err = || format!("invalid Generations rule {:?}", s);
+    let parts = s.split('/').collect::<Vec<_>>();
+    if parts.len() != 3 {
+      return Err(err());
+    }
+
+    let lower = parts.iter().map(|s| s.to_ascii_lowercase()).collect::<Vec<_>>();
+    let (survival, birth, num_states) = if lower[0].starts_with('b') {
+      match (lower[1].strip_prefix('s'), lower[2].strip_prefix(['c', 'g'])) {
+        (Some(s), Some(c)) => (s.to_owned(), lower[0][1..].to_owned(), c.to_owned()),
+        _ => return Err(err()),
+      }
+    } else {
+      (lower[0].clone(), lower[1].clone(), lower[2].clone())
+    };
+
+    let num_states = num_states.parse::<u16>().map_err(|_| err())?;
+    if !(2..=256).contains(&num_states) {
+      return Err(err());
+    }
+    let mut rule = Self::new(num_states);
+    for c in birth.chars() {
+      match c.to_digit(10) {
+        Some(num @ 1..=8) => rule.set_birth(num as u8),
+        _ => return Err(err()),
+      }
+    }
+    for c in survival.chars() {
+      match c.to_digit(10) {
+        Some(num @ 0..=8) => rule.set_survival(num as u8),
+        _ => return Err(err()),
+      }
+    }
+    Ok(rule)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    assert_eq!("/2/3".parse::<Generations>().unwrap(), Generations::BRIANS_BRAIN);
+    assert_eq!("B2/S/C3".parse::<Generations>().unwrap(), Generations::BRIANS_BRAIN);
+    assert_eq!("b3/s23/g8".parse::<Generations>().unwrap().to_string(), "23/3/8");
+    assert!("23/3".parse::<Generations>().is_err());
+    assert!("23/03/8".parse::<Generations>().is_err());
+    assert!("23/3/1".parse::<Generations>().is_err());
+    assert!("23/3/257".parse::<Generations>().is_err());
+  }
+}
@@ -1,7 +1,16 @@
-#![feature(bindings_after_at, const_eval_limit, box_patterns)]
-
 pub mod universe;
+pub mod store;
+mod arena;
 mod node;
+mod parallel;
 pub mod rle;
 pub mod rule;
-pub mod export;
\ No newline at end of file
+pub mod export;
+pub mod batch;
+pub mod topology;
+pub mod multistate;
+pub mod generations;
+pub mod ruletable;
+pub mod ltl;
+pub mod colorlife;
+pub mod secondorder;
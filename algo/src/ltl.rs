@@ -0,0 +1,218 @@
+use crate::multistate::MultiRule;
+use std::fmt::{self, Display, Formatter};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// The largest radius of a Larger than Life rule, as in Golly.
+const MAX_RADIUS: usize = 500;
+
+/// A Larger than Life rule, in which a cell counts the alive cells within
+/// a radius.
+///
+/// Format: <https://golly.sourceforge.io/Help/Algorithms/Larger_than_Life.html>.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LargerThanLife {
+  radius: usize,
+  /// A cell that does not survive decays through the states 2, 3, ... if
+  /// there are more than two states, as in Generations rules.
+  num_states: usize,
+  /// Whether the cell itself is counted.
+  middle: bool,
+  survival: RangeInclusive<usize>,
+  birth: RangeInclusive<usize>,
+  von_neumann: bool,
+}
+
+impl MultiRule for LargerThanLife {
+  fn num_states(&self) -> usize {
+    self.num_states
+  }
+
+  fn radius(&self) -> usize {
+    self.radius
+  }
+
+  fn step(&self, cells: &[u8], size: usize, next: &mut [u8]) {
+    let r = self.radius;
+    let inner = size - 2 * r;
+
+    // prefix sums of the alive cells in each row
+    let mut sums = vec![0u32; size * (size + 1)];
+    for y in 0..size {
+      for x in 0..size {
+        let alive = (cells[y * size + x] == 1) as u32;
+        sums[y * (size + 1) + x + 1] = sums[y * (size + 1) + x] + alive;
+      }
+    }
+
+    for y in 0..inner {
+      for x in 0..inner {
+        let mut count = 0;
+        for dy in 0..=2 * r {
+          let w = if self.von_neumann {
+            r - (dy as isize - r as isize).unsigned_abs()
+          } else {
+            r
+          };
+          let row = &sums[(y + dy) * (size + 1)..];
+          count += row[x + r + w + 1] - row[x + r - w];
+        }
+        let state = cells[(y + r) * size + x + r];
+        if !self.middle && state == 1 {
+          count -= 1;
+        }
+
+        let count = count as usize;
+        next[y * inner + x] = match state {
+          0 => self.birth.contains(&count) as u8,
+          1 if self.survival.contains(&count) => 1,
+          _ => ((state as usize + 1) % self.num_states) as u8,
+        };
+      }
+    }
+  }
+}
+
+impl Display for LargerThanLife {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "R{},C{},M{},S{}..{},B{}..{},N{}",
+      self.radius,
+      if self.num_states == 2 { 0 } else { self.num_states },
+      self.middle as u8,
+      self.survival.start(),
+      self.survival.end(),
+      self.birth.start(),
+      self.birth.end(),
+      if self.von_neumann { 'N' } else { 'M' },
+    )
+  }
+}
+
+impl FromStr for LargerThanLife {
+  type Err = String;
+
+  /// Parses `R5,C0,M1,S34..58,B34..45,NM`, or `5,34,45,34,58` for the radius
+  /// and the ranges of births and survivals.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let err = || format!("invalid Larger than Life rule {:?}", s);
+    let fields = s.split(',').map(|s| s.trim().to_ascii_uppercase()).collect::<Vec<_>>();
+
+    let mut radius = 0;
+    let mut num_states = 2;
+    let mut middle = false;
+    let (survival, birth);
+    let mut von_neumann = false;
+    if fields.iter().all(|f| f.parse::<usize>().is_ok()) {
+      let n = fields.iter().map(|f| f.parse().unwrap()).collect::<Vec<usize>>();
+      if n.len() != 5 {
+        return Err(err());
+      }
+      radius = n[0];
+      middle = true;
+      birth = n[1]..=n[2];
+      survival = n[3]..=n[4];
+    } else {
+      let range = |s: &str| {
+        let (start, end) = s.split_once("..")?;
+        Some(start.parse().ok()?..=end.parse().ok()?)
+      };
+      let mut birth_range = None;
+      let mut survival_range = None;
+      for field in &fields {
+        let (key, value) = field.split_at(field.len().min(1));
+        match key {
+          "R" => radius = value.parse().map_err(|_| err())?,
+          "C" => num_states = value.parse::<usize>().map_err(|_| err())?.max(2),
+          "M" if value == "0" || value == "1" => middle = value == "1",
+          "S" => survival_range = Some(range(value).ok_or_else(err)?),
+          "B" => birth_range = Some(range(value).ok_or_else(err)?),
+          "N" if value == "M" || value == "N" => von_neumann = value == "N",
+          _ => return Err(err()),
+        }
+      }
+      birth = birth_range.ok_or_else(err)?;
+      survival = survival_range.ok_or_else(err)?;
+    }
+
+    let rule = Self { radius, num_states, middle, survival, birth, von_neumann };
+    if rule.radius == 0 || rule.radius > MAX_RADIUS || rule.num_states > 256 {
+      return Err(err());
+    }
+    if rule.birth.contains(&0) {
+      return Err("B0 is not supported in Larger than Life rules".to_owned());
+    }
+    Ok(rule)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Returns whether `(dx, dy)` is in the neighborhood.
+  fn contains(rule: &LargerThanLife, dx: isize, dy: isize) -> bool {
+    if dx == 0 && dy == 0 {
+      return rule.middle;
+    }
+    let r = rule.radius as isize;
+    if rule.von_neumann {
+      dx.abs() + dy.abs() <= r
+    } else {
+      dx.abs() <= r && dy.abs() <= r
+    }
+  }
+
+  #[test]
+  fn test_parse() {
+    let bosco = "R5,C0,M1,S34..58,B34..45,NM";
+    assert_eq!(bosco.parse::<LargerThanLife>().unwrap().to_string(), bosco);
+    assert_eq!("5,34,45,34,58".parse::<LargerThanLife>().unwrap().to_string(), bosco);
+    assert_eq!(
+      "r7,c3,m0,s1..4,b2..3,nn".parse::<LargerThanLife>().unwrap().to_string(),
+      "R7,C3,M0,S1..4,B2..3,NN",
+    );
+    assert!("R5,C0,M1,S34..58,NM".parse::<LargerThanLife>().is_err());
+    assert!("R0,C0,M1,S1..2,B1..2,NM".parse::<LargerThanLife>().is_err());
+    assert!("R1,C0,M1,S1..2,B0..2,NM".parse::<LargerThanLife>().is_err());
+    assert!("R1,C0,M2,S1..2,B1..2,NM".parse::<LargerThanLife>().is_err());
+  }
+
+  #[test]
+  fn test_step() {
+    for rule in &["R3,C0,M1,S10..20,B8..12,NM", "R2,C4,M0,S2..5,B3..4,NN"] {
+      let rule = rule.parse::<LargerThanLife>().unwrap();
+      let r = rule.radius as isize;
+      let size = 20;
+      let mut seed = 1u32;
+      let cells = (0..size * size).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        ((seed >> 16) as usize % rule.num_states) as u8
+      }).collect::<Vec<_>>();
+      let inner = size - 2 * rule.radius;
+      let mut next = vec![0; inner * inner];
+      rule.step(&cells, size, &mut next);
+
+      for y in r..size as isize - r {
+        for x in r..size as isize - r {
+          let mut count = 0;
+          for dy in -r..=r {
+            for dx in -r..=r {
+              if contains(&rule, dx, dy) && cells[((y + dy) * size as isize + x + dx) as usize] == 1 {
+                count += 1;
+              }
+            }
+          }
+          let state = cells[(y * size as isize + x) as usize];
+          let expected = match state {
+            0 => rule.birth.contains(&count) as u8,
+            1 if rule.survival.contains(&count) => 1,
+            _ => ((state as usize + 1) % rule.num_states) as u8,
+          };
+          assert_eq!(next[((y - r) * inner as isize + x - r) as usize], expected, "{}", rule);
+        }
+      }
+    }
+  }
+}
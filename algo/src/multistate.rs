@@ -0,0 +1,1064 @@
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+use rustc_hash::FxHashMap;
+use std::fmt::Display;
+use crate::arena::{Arena, ArenaNode};
+use crate::node::{is_addressable, InternalNodeKey, NodeId, INVALID_NODE_ID, MAX_LEVEL};
+use crate::store::{GcConfig, MemoryLimitExceeded, Stats};
+use crate::universe::Boundary;
+
+/// A rule of a [`MultiUniverse`], whose cells have up to 256 states.
+pub trait MultiRule: Display + Send + Sync {
+  /// Number of states. State 0 is the background, and must stay 0 if all the
+  /// neighbors are 0.
+  fn num_states(&self) -> usize;
+
+  /// Distance of the farthest neighbors that affect a cell.
+  fn radius(&self) -> usize {
+    1
+  }
+
+  /// Advances one generation of the `size` x `size` square `cells`, and
+  /// writes the inner square of `size - 2 * radius` cells on both sides to
+  /// `next`. Both squares are in row-major order.
+  fn step(&self, cells: &[u8], size: usize, next: &mut [u8]);
+
+  /// Returns the permutation of the states that reverses time, if the rule is
+  /// reversible: advancing the permuted cells `n` generations and permuting
+  /// them back goes back `n` generations. The state 0 must stay 0.
+  fn time_reversal(&self) -> Option<Vec<u8>> {
+    None
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum MultiNodeKey {
+  /// States of a 2x2 square, in the order of NW, NE, SW and SE.
+  Leaf([u8; 4]),
+  Internal(InternalNodeKey),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct MultiNode {
+  key: MultiNodeKey,
+  /// `2 ^ level` cells on both sides.
+  level: u16,
+  /// Number of cells in non-zero states, saturated at `u64::MAX`.
+  population: u64,
+  result: NodeId,
+  mark: bool,
+}
+
+impl ArenaNode for MultiNode {
+  type Key = MultiNodeKey;
+
+  fn level(&self) -> u16 {
+    self.level
+  }
+
+  fn mark_mut(&mut self) -> &mut bool {
+    &mut self.mark
+  }
+
+  fn for_each_ref(&self, mut f: impl FnMut(NodeId)) {
+    if let MultiNodeKey::Internal(key) = self.key {
+      f(key.nw);
+      f(key.ne);
+      f(key.sw);
+      f(key.se);
+    }
+    if self.result != INVALID_NODE_ID {
+      f(self.result);
+    }
+  }
+}
+
+/// Hash-consed nodes of a multi-state universe.
+struct MultiStore {
+  arena: Arena<MultiNode>,
+  empty_nodes: Vec<NodeId>,
+  rule: Box<dyn MultiRule>,
+  /// Nodes of this level are advanced one generation by the rule itself.
+  /// The margin of a quarter of their size covers the radius of the rule.
+  base_level: u16,
+  last_k: Option<u16>,
+}
+
+impl MultiStore {
+  fn new(rule: Box<dyn MultiRule>, gc_config: GcConfig) -> Self {
+    let radius = rule.radius().max(1);
+    let base_level = 2 + (usize::BITS - (radius - 1).leading_zeros()) as u16;
+    let mut store = Self {
+      arena: Arena::new(gc_config),
+      empty_nodes: vec![],
+      rule,
+      base_level,
+      last_k: None,
+    };
+    let empty = store.find_node(MultiNodeKey::Leaf([0; 4]));
+    store.empty_nodes = vec![INVALID_NODE_ID, empty];
+    store.arena.clear_gc_roots();
+    store
+  }
+
+  fn node(&self, id: NodeId) -> &MultiNode {
+    self.arena.node(id)
+  }
+
+  fn level(&self, id: NodeId) -> u16 {
+    self.node(id).level
+  }
+
+  fn internal_key(&self, id: NodeId) -> InternalNodeKey {
+    match self.node(id).key {
+      MultiNodeKey::Internal(key) => key,
+      MultiNodeKey::Leaf(_) => unreachable!("leaf node"),
+    }
+  }
+
+  fn leaf_cells(&self, id: NodeId) -> [u8; 4] {
+    match self.node(id).key {
+      MultiNodeKey::Leaf(cells) => cells,
+      MultiNodeKey::Internal(_) => unreachable!("internal node"),
+    }
+  }
+
+  fn find_node(&mut self, key: MultiNodeKey) -> NodeId {
+    self.arena.find(key, |arena| new_multi_node(key, arena))
+  }
+
+  /// Like `find_node`, but collects garbage when the memory limit is reached,
+  /// so every node in use must be reachable from the roots of the arena. The
+  /// found node is rooted even if it already existed.
+  fn try_find_node(&mut self, key: MultiNodeKey) -> Result<NodeId, MemoryLimitExceeded> {
+    let Self { arena, empty_nodes, .. } = self;
+    arena.try_find(key, |arena| new_multi_node(key, arena), || empty_nodes.last().copied())
+  }
+
+  fn find_internal(
+    &mut self,
+    nw: NodeId,
+    ne: NodeId,
+    sw: NodeId,
+    se: NodeId,
+  ) -> NodeId {
+    self.find_node(MultiNodeKey::Internal(InternalNodeKey { nw, ne, sw, se }))
+  }
+
+  fn try_find_internal(
+    &mut self,
+    nw: NodeId,
+    ne: NodeId,
+    sw: NodeId,
+    se: NodeId,
+  ) -> Result<NodeId, MemoryLimitExceeded> {
+    self.try_find_node(MultiNodeKey::Internal(InternalNodeKey { nw, ne, sw, se }))
+  }
+
+  fn find_empty_node(&mut self, level: u16) -> NodeId {
+    let gc_s = self.arena.gc_save();
+    while self.empty_nodes.len() <= level as usize {
+      let prev = *self.empty_nodes.last().unwrap();
+      let node = self.find_internal(prev, prev, prev, prev);
+      self.empty_nodes.push(node);
+    }
+    self.arena.gc_restore(gc_s);
+    self.empty_nodes[level as usize]
+  }
+
+  /// Returns the node of the same level centered at the center of the four
+  /// nodes.
+  fn center(
+    &mut self,
+    nw: NodeId,
+    ne: NodeId,
+    sw: NodeId,
+    se: NodeId,
+  ) -> Result<NodeId, MemoryLimitExceeded> {
+    if self.level(nw) == 1 {
+      let cells = [
+        self.leaf_cells(nw)[3],
+        self.leaf_cells(ne)[2],
+        self.leaf_cells(sw)[1],
+        self.leaf_cells(se)[0],
+      ];
+      self.try_find_node(MultiNodeKey::Leaf(cells))
+    } else {
+      let nw = self.internal_key(nw).se;
+      let ne = self.internal_key(ne).sw;
+      let sw = self.internal_key(sw).ne;
+      let se = self.internal_key(se).nw;
+      self.try_find_internal(nw, ne, sw, se)
+    }
+  }
+
+  fn expand(&mut self, root: NodeId) -> NodeId {
+    let level = self.level(root);
+    let key = self.internal_key(root);
+    let empty = self.find_empty_node(level - 1);
+    let nw = self.find_internal(empty, empty, empty, key.nw);
+    let ne = self.find_internal(empty, empty, key.ne, empty);
+    let sw = self.find_internal(empty, key.sw, empty, empty);
+    let se = self.find_internal(key.se, empty, empty, empty);
+    self.find_internal(nw, ne, sw, se)
+  }
+
+  fn shrink(&mut self, mut root: NodeId) -> NodeId {
+    while self.level(root) > 2 {
+      let key = self.internal_key(root);
+      let empty = self.find_empty_node(self.level(root) - 2);
+      let nw = self.internal_key(key.nw);
+      let ne = self.internal_key(key.ne);
+      let sw = self.internal_key(key.sw);
+      let se = self.internal_key(key.se);
+      if [nw.nw, nw.ne, nw.sw, ne.nw, ne.ne, ne.se, sw.nw, sw.sw, sw.se, se.ne, se.sw, se.se]
+        .iter()
+        .any(|&id| id != empty)
+      {
+        break;
+      }
+      root = self.find_internal(nw.se, ne.sw, sw.ne, se.nw);
+    }
+    root
+  }
+
+  /// Removes the cells outside the node of `MAX_LEVEL` centered at the
+  /// center of `root`.
+  fn clamp_level(&mut self, mut root: NodeId) -> NodeId {
+    while self.level(root) > MAX_LEVEL {
+      let key = self.internal_key(root);
+      let nw = self.internal_key(key.nw).se;
+      let ne = self.internal_key(key.ne).sw;
+      let sw = self.internal_key(key.sw).ne;
+      let se = self.internal_key(key.se).nw;
+      root = self.find_internal(nw, ne, sw, se);
+    }
+    root
+  }
+
+  /// `(x, y)` are coordinates relative to the center of the node.
+  fn set_rec(&mut self, node: NodeId, x: i64, y: i64, state: u8) -> NodeId {
+    match self.node(node).key {
+      MultiNodeKey::Leaf(mut cells) => {
+        cells[((y + 1) * 2 + x + 1) as usize] = state;
+        self.find_node(MultiNodeKey::Leaf(cells))
+      }
+      MultiNodeKey::Internal(mut key) => {
+        let r = 1 << (self.level(node) - 2);
+        let (child, x, y) = match (x < 0, y < 0) {
+          (true, true) => (&mut key.nw, x + r, y + r),
+          (false, true) => (&mut key.ne, x - r, y + r),
+          (true, false) => (&mut key.sw, x + r, y - r),
+          (false, false) => (&mut key.se, x - r, y - r),
+        };
+        *child = self.set_rec(*child, x, y, state);
+        self.find_node(MultiNodeKey::Internal(key))
+      }
+    }
+  }
+
+  /// `(x, y)` are coordinates relative to the center of the node.
+  fn get_rec(&self, node: NodeId, x: i64, y: i64) -> u8 {
+    match self.node(node).key {
+      MultiNodeKey::Leaf(cells) => cells[((y + 1) * 2 + x + 1) as usize],
+      MultiNodeKey::Internal(key) => {
+        let r = 1 << (self.level(node) - 2);
+        match (x < 0, y < 0) {
+          (true, true) => self.get_rec(key.nw, x + r, y + r),
+          (false, true) => self.get_rec(key.ne, x - r, y + r),
+          (true, false) => self.get_rec(key.sw, x + r, y - r),
+          (false, false) => self.get_rec(key.se, x - r, y - r),
+        }
+      }
+    }
+  }
+
+  /// Calls `f` with the non-zero cells of the node inside `viewport`.
+  /// `(ox, oy)` is the center of the node.
+  fn write_states_rec<F>(
+    &self,
+    node: NodeId,
+    viewport: &Boundary,
+    ox: i64,
+    oy: i64,
+    f: &mut F,
+  )
+  where
+    F: FnMut(i64, i64, u8)
+  {
+    let MultiNode { key, level, population, .. } = *self.node(node);
+    let r = 1 << (level - 1);
+    if population == 0
+      || ox + r <= viewport.left
+      || ox - r >= viewport.right
+      || oy + r <= viewport.top
+      || oy - r >= viewport.bottom
+    {
+      return;
+    }
+
+    match key {
+      MultiNodeKey::Leaf(cells) => {
+        for (i, &state) in cells.iter().enumerate() {
+          let x = ox - 1 + (i & 1) as i64;
+          let y = oy - 1 + (i >> 1) as i64;
+          if state != 0
+            && x >= viewport.left && x < viewport.right
+            && y >= viewport.top && y < viewport.bottom
+          {
+            f(x, y, state);
+          }
+        }
+      }
+      MultiNodeKey::Internal(key) => {
+        let rr = r >> 1;
+        self.write_states_rec(key.nw, viewport, ox - rr, oy - rr, f);
+        self.write_states_rec(key.ne, viewport, ox + rr, oy - rr, f);
+        self.write_states_rec(key.sw, viewport, ox - rr, oy + rr, f);
+        self.write_states_rec(key.se, viewport, ox + rr, oy + rr, f);
+      }
+    }
+  }
+
+  /// Returns the boundary of the non-zero cells of the node, whose center is
+  /// `(ox, oy)`, or `None` if there are none.
+  fn boundary_rec(&self, node: NodeId, ox: i64, oy: i64) -> Option<Boundary> {
+    let MultiNode { key, level, population, .. } = *self.node(node);
+    if population == 0 {
+      return None;
+    }
+
+    match key {
+      MultiNodeKey::Leaf(cells) => {
+        let mut boundary = None::<Boundary>;
+        for (i, _) in cells.iter().enumerate().filter(|(_, &state)| state != 0) {
+          let x = ox - 1 + (i & 1) as i64;
+          let y = oy - 1 + (i >> 1) as i64;
+          let cell = Boundary { left: x, top: y, right: x + 1, bottom: y + 1 };
+          boundary = Some(boundary.map_or(cell, |b| union(b, cell)));
+        }
+        boundary
+      }
+      MultiNodeKey::Internal(key) => {
+        let rr = 1 << (level - 2);
+        [
+          self.boundary_rec(key.nw, ox - rr, oy - rr),
+          self.boundary_rec(key.ne, ox + rr, oy - rr),
+          self.boundary_rec(key.sw, ox - rr, oy + rr),
+          self.boundary_rec(key.se, ox + rr, oy + rr),
+        ].iter().flatten().copied().reduce(union)
+      }
+    }
+  }
+
+  fn population_rec(
+    &self,
+    node: NodeId,
+    cache: &mut FxHashMap<NodeId, BigUint>,
+  ) -> BigUint {
+    let MultiNode { key, population, .. } = *self.node(node);
+    if population != u64::MAX {
+      return population.into();
+    }
+    if let Some(population) = cache.get(&node) {
+      return population.clone();
+    }
+
+    let key = match key {
+      MultiNodeKey::Internal(key) => key,
+      MultiNodeKey::Leaf(_) => unreachable!("saturated leaf"),
+    };
+    let population = self.population_rec(key.nw, cache)
+      + self.population_rec(key.ne, cache)
+      + self.population_rec(key.sw, cache)
+      + self.population_rec(key.se, cache);
+    cache.insert(node, population.clone());
+    population
+  }
+
+  /// Copies the cells of the node to the square of `size` cells on both sides
+  /// at `(x, y)`.
+  fn write_square(&self, node: NodeId, cells: &mut [u8], size: usize, x: usize, y: usize) {
+    let MultiNode { key, level, population, .. } = *self.node(node);
+    if population == 0 {
+      return;
+    }
+    match key {
+      MultiNodeKey::Leaf(leaf) => {
+        cells[y * size + x] = leaf[0];
+        cells[y * size + x + 1] = leaf[1];
+        cells[(y + 1) * size + x] = leaf[2];
+        cells[(y + 1) * size + x + 1] = leaf[3];
+      }
+      MultiNodeKey::Internal(key) => {
+        let r = 1 << (level - 1);
+        self.write_square(key.nw, cells, size, x, y);
+        self.write_square(key.ne, cells, size, x + r, y);
+        self.write_square(key.sw, cells, size, x, y + r);
+        self.write_square(key.se, cells, size, x + r, y + r);
+      }
+    }
+  }
+
+  /// Returns the node of `level` whose cells are the square at `(x, y)` of
+  /// `cells`, which has `size` cells on both sides.
+  fn read_square(
+    &mut self,
+    cells: &[u8],
+    size: usize,
+    x: usize,
+    y: usize,
+    level: u16,
+  ) -> Result<NodeId, MemoryLimitExceeded> {
+    if level == 1 {
+      let leaf = [
+        cells[y * size + x],
+        cells[y * size + x + 1],
+        cells[(y + 1) * size + x],
+        cells[(y + 1) * size + x + 1],
+      ];
+      return self.try_find_node(MultiNodeKey::Leaf(leaf));
+    }
+    let r = 1 << (level - 1);
+    let nw = self.read_square(cells, size, x, y, level - 1)?;
+    let ne = self.read_square(cells, size, x + r, y, level - 1)?;
+    let sw = self.read_square(cells, size, x, y + r, level - 1)?;
+    let se = self.read_square(cells, size, x + r, y + r, level - 1)?;
+    self.try_find_internal(nw, ne, sw, se)
+  }
+
+  /// Replaces every state `s` with `states[s]`.
+  fn map_states_rec(
+    &mut self,
+    node: NodeId,
+    states: &[u8],
+    cache: &mut FxHashMap<NodeId, NodeId>,
+  ) -> NodeId {
+    if self.node(node).population == 0 {
+      return node;
+    }
+    if let Some(&result) = cache.get(&node) {
+      return result;
+    }
+
+    let key = match self.node(node).key {
+      MultiNodeKey::Leaf(cells) => MultiNodeKey::Leaf(cells.map(|s| states[s as usize])),
+      MultiNodeKey::Internal(key) => MultiNodeKey::Internal(InternalNodeKey {
+        nw: self.map_states_rec(key.nw, states, cache),
+        ne: self.map_states_rec(key.ne, states, cache),
+        sw: self.map_states_rec(key.sw, states, cache),
+        se: self.map_states_rec(key.se, states, cache),
+      }),
+    };
+    let result = self.find_node(key);
+    cache.insert(node, result);
+    result
+  }
+
+  fn map_states(&mut self, root: NodeId, states: &[u8]) -> NodeId {
+    let gc_s = self.arena.gc_save();
+    let root = self.map_states_rec(root, states, &mut FxHashMap::default());
+    self.arena.gc_restore(gc_s);
+    root
+  }
+
+  /// Sets the cell at `(x, y)` of the pattern under `root`, expanding it if
+  /// needed.
+  fn set_cell(&mut self, mut root: NodeId, x: i64, y: i64, state: u8) -> NodeId {
+    let gc_s = self.arena.gc_save();
+    let mut radius = 1 << (self.level(root) - 1);
+    while x < -radius || x >= radius || y < -radius || y >= radius {
+      root = self.expand(root);
+      radius <<= 1;
+    }
+    root = self.set_rec(root, x, y, state);
+    self.arena.gc_restore(gc_s);
+    root
+  }
+
+  fn clear_results(&mut self, k: u16) {
+    if let Some(last_k) = self.last_k {
+      let min_k = last_k.min(k);
+      let base_level = self.base_level;
+      for node in self.arena.nodes_mut() {
+        if min_k < node.level.saturating_sub(base_level) {
+          node.result = INVALID_NODE_ID;
+        }
+      }
+    }
+    self.last_k = Some(k);
+  }
+
+  /// Advances `2 ^ k` generations of the pattern under `root`.
+  ///
+  /// On error, the pattern is left unchanged.
+  fn simulate(&mut self, root: NodeId, k: u16) -> Result<NodeId, MemoryLimitExceeded> {
+    self.clear_results(k);
+    if self.arena.needs_gc() {
+      self.gc(root);
+    }
+
+    let gc_s = self.arena.gc_save();
+    self.arena.gc_root(root);
+    // preserve enough empty space for the pattern to grow into, rooting
+    // every level since `expand` may return existing nodes
+    let mut expanded = self.expand(root);
+    self.arena.gc_root(expanded);
+    expanded = self.expand(expanded);
+    self.arena.gc_root(expanded);
+    while self.level(expanded) < k + self.base_level + 1 {
+      expanded = self.expand(expanded);
+      self.arena.gc_root(expanded);
+    }
+    let result = self.step_rec(expanded, k)
+      .map(|result| self.shrink(result))
+      .map(|result| self.clamp_level(result));
+    self.arena.gc_restore(gc_s);
+    if result.is_err() {
+      self.gc(root);
+    }
+    result
+  }
+
+  /// Advances `2 ^ min(k, level - base_level)` generations, and returns the
+  /// center of the node, which is rooted.
+  fn step_rec(&mut self, node: NodeId, k: u16) -> Result<NodeId, MemoryLimitExceeded> {
+    let MultiNode { level, population, result, .. } = *self.node(node);
+    if result != INVALID_NODE_ID {
+      self.arena.memo_hits += 1;
+      self.arena.gc_root(result);
+      return Ok(result);
+    }
+    if population == 0 {
+      return Ok(self.find_empty_node(level - 1));
+    }
+
+    let gc_s = self.arena.gc_save();
+    let result = if level == self.base_level {
+      self.base_step(node)?
+    } else {
+      let key = self.internal_key(node);
+      let nw = self.internal_key(key.nw);
+      let ne = self.internal_key(key.ne);
+      let sw = self.internal_key(key.sw);
+      let se = self.internal_key(key.se);
+
+      let nn = self.try_find_internal(nw.ne, ne.nw, nw.se, ne.sw)?;
+      let ww = self.try_find_internal(nw.sw, nw.se, sw.nw, sw.ne)?;
+      let cc = self.try_find_internal(nw.se, ne.sw, sw.ne, se.nw)?;
+      let ee = self.try_find_internal(ne.sw, ne.se, se.nw, se.ne)?;
+      let ss = self.try_find_internal(sw.ne, se.nw, sw.se, se.sw)?;
+      let mut n = [INVALID_NODE_ID; 9];
+      for (n, node) in n.iter_mut()
+        .zip([key.nw, nn, key.ne, ww, cc, ee, key.sw, ss, key.se])
+      {
+        *n = self.step_rec(node, k)?;
+      }
+
+      let mut quadrants = [INVALID_NODE_ID; 4];
+      for (quadrant, [nw, ne, sw, se]) in quadrants.iter_mut().zip([
+        [n[0], n[1], n[3], n[4]],
+        [n[1], n[2], n[4], n[5]],
+        [n[3], n[4], n[6], n[7]],
+        [n[4], n[5], n[7], n[8]],
+      ]) {
+        *quadrant = if k >= level - self.base_level {
+          let node = self.try_find_internal(nw, ne, sw, se)?;
+          self.step_rec(node, k)?
+        } else {
+          self.center(nw, ne, sw, se)?
+        };
+      }
+      let [nw, ne, sw, se] = quadrants;
+      self.try_find_internal(nw, ne, sw, se)?
+    };
+    self.arena.gc_restore(gc_s);
+    self.arena.gc_root(result);
+
+    self.arena.node_mut(node).result = result;
+    Ok(result)
+  }
+
+  /// Advances one generation of a node of the base level by the rule.
+  fn base_step(&mut self, node: NodeId) -> Result<NodeId, MemoryLimitExceeded> {
+    let size = 1 << self.base_level;
+    let radius = self.rule.radius();
+    let mut cells = vec![0; size * size];
+    self.write_square(node, &mut cells, size, 0, 0);
+
+    let inner = size - 2 * radius;
+    let mut next = vec![0; inner * inner];
+    self.rule.step(&cells, size, &mut next);
+
+    // the center of the node is at a quarter of its size
+    let offset = size / 4 - radius;
+    self.read_square(&next, inner, offset, offset, self.base_level - 1)
+  }
+
+  /// Frees the nodes unreachable from `root`, the rooted nodes of the arena
+  /// and the empty nodes.
+  fn gc(&mut self, root: NodeId) {
+    let roots = self.empty_nodes.last().copied().into_iter().chain(Some(root));
+    self.arena.gc(roots);
+  }
+}
+
+/// Returns a new node of `key`, whose children are in `arena`.
+fn new_multi_node(key: MultiNodeKey, arena: &Arena<MultiNode>) -> MultiNode {
+  let (level, population) = match key {
+    MultiNodeKey::Leaf(cells) => {
+      (1, cells.iter().filter(|&&state| state != 0).count() as u64)
+    }
+    MultiNodeKey::Internal(key) => {
+      let population = [key.nw, key.ne, key.sw, key.se].iter()
+        .fold(0u64, |sum, &id| sum.saturating_add(arena.node(id).population));
+      (arena.node(key.nw).level + 1, population)
+    }
+  };
+  MultiNode {
+    key,
+    level,
+    population,
+    result: INVALID_NODE_ID,
+    mark: false,
+  }
+}
+
+fn union(a: Boundary, b: Boundary) -> Boundary {
+  Boundary {
+    left: a.left.min(b.left),
+    top: a.top.min(b.top),
+    right: a.right.max(b.right),
+    bottom: a.bottom.max(b.bottom),
+  }
+}
+
+/// A universe of cells with more than two states, such as the cells of
+/// Generations rules.
+pub struct MultiUniverse {
+  store: MultiStore,
+  root: NodeId,
+  generation: BigUint,
+}
+
+impl MultiUniverse {
+  pub fn new(rule: impl MultiRule + 'static) -> Self {
+    Self::with_boxed_rule(Box::new(rule))
+  }
+
+  pub fn with_boxed_rule(rule: Box<dyn MultiRule>) -> Self {
+    Self::with_config(rule, GcConfig::default())
+  }
+
+  pub fn with_config(rule: Box<dyn MultiRule>, gc_config: GcConfig) -> Self {
+    assert!(rule.num_states() <= 256, "too many states");
+    let mut store = MultiStore::new(rule, gc_config);
+    let root = store.find_empty_node(2);
+    Self {
+      store,
+      root,
+      generation: BigUint::zero(),
+    }
+  }
+
+  pub fn rule(&self) -> &dyn MultiRule {
+    &*self.store.rule
+  }
+
+  pub fn gc_config(&self) -> GcConfig {
+    self.store.arena.gc_config()
+  }
+
+  /// The new settings take effect from the next collection.
+  pub fn set_gc_config(&mut self, gc_config: GcConfig) {
+    self.store.arena.set_gc_config(gc_config);
+  }
+
+  pub fn stats(&self) -> Stats {
+    let heap_bytes = std::mem::size_of::<MultiStore>() +
+      self.store.empty_nodes.capacity() * std::mem::size_of::<NodeId>();
+    self.store.arena.stats(heap_bytes)
+  }
+
+  /// Number of generations the pattern has advanced.
+  pub fn generation(&self) -> &BigUint {
+    &self.generation
+  }
+
+  pub fn set_generation(&mut self, generation: BigUint) {
+    self.generation = generation;
+  }
+
+  /// `num_gen` is number of generations.
+  ///
+  /// If the memory limit is exceeded, the universe stays at the last
+  /// generation that could be reached.
+  ///
+  /// Cells that move `2 ^ 61` or more cells away from the origin are removed,
+  /// since cells are addressed by `i64`.
+  pub fn simulate(
+    &mut self,
+    mut num_gen: usize,
+  ) -> Result<(), MemoryLimitExceeded> {
+    while num_gen != 0 {
+      let k = num_gen.trailing_zeros() as u16;
+      num_gen &= num_gen - 1;
+      self.simulate_pow2(k)?;
+    }
+    Ok(())
+  }
+
+  /// Advances `num_gen` generations, or goes back `-num_gen` generations if it
+  /// is negative, which requires a reversible rule and is not allowed before
+  /// generation 0.
+  pub fn simulate_signed(&mut self, num_gen: i64) -> Result<(), String> {
+    if num_gen >= 0 {
+      return self.simulate(num_gen as usize).map_err(|err| err.to_string());
+    }
+
+    let reversal = self.store.rule.time_reversal()
+      .ok_or_else(|| format!("{} is not reversible", self.store.rule))?;
+    let num_gen = num_gen.unsigned_abs();
+    if self.generation < BigUint::from(num_gen) {
+      return Err("cannot go back before generation 0".to_owned());
+    }
+    let mut inverse = vec![0; reversal.len()];
+    for (s, &t) in reversal.iter().enumerate() {
+      inverse[t as usize] = s as u8;
+    }
+
+    let generation = self.generation.clone();
+    self.root = self.store.map_states(self.root, &reversal);
+    let result = self.simulate(num_gen as usize);
+    self.root = self.store.map_states(self.root, &inverse);
+    // the generations advanced so far went back in time
+    self.generation = &generation * 2u32 - &self.generation;
+    result.map_err(|err| err.to_string())
+  }
+
+  /// Advances `2 ^ k` generations.
+  fn simulate_pow2(&mut self, k: u16) -> Result<(), MemoryLimitExceeded> {
+    self.root = self.store.simulate(self.root, k)?;
+    self.generation += BigUint::one() << k as usize;
+    Ok(())
+  }
+
+  /// Sets the state of the cell at `(x, y)`. Cells `2 ^ 61` or more cells
+  /// away from the origin are ignored.
+  pub fn set(&mut self, x: i64, y: i64, state: u8) {
+    assert!((state as usize) < self.store.rule.num_states(), "invalid state {}", state);
+    if !is_addressable(x, y) {
+      return;
+    }
+    self.root = self.store.set_cell(self.root, x, y, state);
+  }
+
+  /// Returns the state of the cell at `(x, y)`.
+  pub fn get(&self, x: i64, y: i64) -> u8 {
+    // every cell is inside a root whose radius overflows
+    let level = self.store.level(self.root) as u32;
+    if let Some(radius) = 1i64.checked_shl(level - 1).filter(|&r| r > 0) {
+      if x < -radius || x >= radius || y < -radius || y >= radius {
+        return 0;
+      }
+    }
+    self.store.get_rec(self.root, x, y)
+  }
+
+  /// Returns the states of the cells inside `rect` in row-major order.
+  pub fn get_rect(&self, rect: &Boundary) -> Vec<u8> {
+    let w = (rect.right - rect.left).max(0);
+    let h = (rect.bottom - rect.top).max(0);
+    let mut buffer = vec![0; (w * h) as usize];
+    self.write_states(rect, |x, y, state| {
+      buffer[((y - rect.top) * w + x - rect.left) as usize] = state;
+    });
+    buffer
+  }
+
+  /// Returns the number of cells in non-zero states.
+  pub fn population(&self) -> BigUint {
+    self.store.population_rec(self.root, &mut FxHashMap::default())
+  }
+
+  /// Returns the boundary of the cells in non-zero states, which is empty
+  /// if there are none.
+  pub fn boundary(&self) -> Boundary {
+    self.store.boundary_rec(self.root, 0, 0)
+      .unwrap_or(Boundary { left: 0, top: 0, right: 0, bottom: 0 })
+  }
+
+  /// Calls `f` with the coordinates and the state of every cell in a non-zero
+  /// state inside `viewport`.
+  pub(crate) fn write_states<F>(&self, viewport: &Boundary, mut f: F)
+  where
+    F: FnMut(i64, i64, u8)
+  {
+    self.store.write_states_rec(self.root, viewport, 0, 0, &mut f);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::colorlife::ColorLife;
+  use crate::generations::Generations;
+  use crate::rule::Rule;
+  use crate::secondorder::SecondOrder;
+  use crate::ltl::LargerThanLife;
+
+  /// Advances `cells` of the naive simulation of a rule one generation,
+  /// surrounded by state 0.
+  pub(crate) fn naive_step(rule: &dyn MultiRule, cells: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let r = rule.radius();
+    let h = cells.len();
+    let w = cells[0].len();
+    let size = w.max(h) + 4 * r;
+    let mut square = vec![0; size * size];
+    for (y, row) in cells.iter().enumerate() {
+      for (x, &state) in row.iter().enumerate() {
+        square[(y + 2 * r) * size + x + 2 * r] = state;
+      }
+    }
+    let inner = size - 2 * r;
+    let mut next = vec![0; inner * inner];
+    rule.step(&square, size, &mut next);
+    (0..h).map(|y| next[(y + r) * inner + r..][..w].to_vec()).collect()
+  }
+
+  pub(crate) fn check_rule(rule: impl MultiRule + Clone + 'static, seed: u32) {
+    // wide enough for the pattern not to reach the edges
+    let r = 48 * rule.radius() as i64;
+    let mut uni = MultiUniverse::new(rule.clone());
+    let mut cells = vec![vec![0u8; 2 * r as usize]; 2 * r as usize];
+    let mut seed = seed;
+    for y in -6..6 {
+      for x in -6..6 {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let state = (seed >> 16) as usize % rule.num_states();
+        cells[(y + r) as usize][(x + r) as usize] = state as u8;
+        uni.set(x, y, state as u8);
+      }
+    }
+
+    let rect = Boundary { left: -r, top: -r, right: r, bottom: r };
+    for &num_gen in &[1, 2, 3, 8, 16] {
+      uni.simulate(num_gen).unwrap();
+      for _ in 0..num_gen {
+        cells = naive_step(&rule, &cells);
+      }
+      assert_eq!(uni.get_rect(&rect), cells.concat(), "{} at generation {}", rule, uni.generation());
+    }
+  }
+
+  #[test]
+  fn test_set_get() {
+    let mut uni = MultiUniverse::new(Generations::BRIANS_BRAIN);
+    uni.set(-3, 5, 1);
+    uni.set(100, -20, 2);
+    uni.set(0, 0, 2);
+    uni.set(0, 0, 0);
+    assert_eq!(uni.get(-3, 5), 1);
+    assert_eq!(uni.get(100, -20), 2);
+    assert_eq!(uni.get(0, 0), 0);
+    assert_eq!(uni.get(-1000, 1000), 0);
+    assert_eq!(uni.population(), BigUint::from(2u32));
+    assert_eq!(uni.boundary(), Boundary { left: -3, top: -20, right: 101, bottom: 6 });
+  }
+
+  #[test]
+  fn test_generations() {
+    check_rule("23/3/8".parse::<Generations>().unwrap(), 1);
+    check_rule(Generations::BRIANS_BRAIN, 2);
+  }
+
+  #[test]
+  fn test_second_order() {
+    let mut rule = Rule::new();
+    for num in [3, 6] {
+      rule.set_birth(num);
+    }
+    for num in [2, 3] {
+      rule.set_survival(num);
+    }
+    let rule = SecondOrder::new(rule).unwrap();
+    check_rule(rule, 5);
+
+    let mut uni = MultiUniverse::new(rule);
+    let mut seed = 6u32;
+    for y in -8..8 {
+      for x in -8..8 {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        uni.set(x, y, (seed >> 16 & 1) as u8);
+      }
+    }
+    let rect = Boundary { left: -8, top: -8, right: 8, bottom: 8 };
+    let cells = uni.get_rect(&rect);
+
+    uni.simulate_signed(100).unwrap();
+    assert_ne!(uni.get_rect(&rect), cells);
+    uni.simulate_signed(-30).unwrap();
+    uni.simulate_signed(-70).unwrap();
+    assert_eq!(uni.generation(), &BigUint::zero());
+    assert_eq!(uni.boundary(), rect);
+    assert_eq!(uni.get_rect(&rect), cells);
+
+    assert!(uni.simulate_signed(-1).is_err());
+    assert!(MultiUniverse::new(Generations::BRIANS_BRAIN).simulate_signed(-1).is_err());
+  }
+
+  #[test]
+  fn test_color_life() {
+    check_rule(ColorLife::IMMIGRATION, 3);
+    check_rule(ColorLife::QUADLIFE, 4);
+  }
+
+  #[test]
+  fn test_larger_than_life() {
+    for (i, rule) in ["R2,C0,M1,S6..12,B5..8,NM", "R5,C3,M0,S34..58,B34..45,NN"].iter().enumerate() {
+      check_rule(rule.parse::<LargerThanLife>().unwrap(), i as u32);
+    }
+  }
+
+  #[test]
+  fn test_large_step() {
+    // a glider of Life
+    let mut uni = MultiUniverse::new("23/3/2".parse::<Generations>().unwrap());
+    for &(x, y) in &[(1, 0), (2, 1), (0, 2), (1, 2), (2, 2)] {
+      uni.set(x, y, 1);
+    }
+
+    uni.simulate(1 << 62).unwrap();
+    let d = 1 << 60;
+    assert_eq!(uni.boundary(), Boundary { left: d, top: d, right: d + 3, bottom: d + 3 });
+    assert_eq!(uni.get(d + 1, d), 1);
+    assert_eq!(uni.population(), BigUint::from(5u32));
+
+    // the glider is removed once it leaves the cells addressed by `i64`
+    uni.simulate(1 << 62).unwrap();
+    assert_eq!(uni.population(), BigUint::zero());
+    assert!(uni.store.level(uni.root) <= MAX_LEVEL);
+    uni.set(i64::MIN, 0, 1);
+    assert_eq!(uni.population(), BigUint::zero());
+    assert_eq!(uni.get(i64::MIN, i64::MAX), 0);
+  }
+
+  #[test]
+  fn test_gc() {
+    let config = GcConfig { initial_threshold: 0, ..GcConfig::default() };
+    let mut uni = MultiUniverse::with_config(Box::new(Generations::BRIANS_BRAIN), config);
+    for x in -2..2 {
+      uni.set(x, 0, 1);
+      uni.set(x, 1, 2);
+    }
+    let mut expected = MultiUniverse::new(Generations::BRIANS_BRAIN);
+    for x in -2..2 {
+      expected.set(x, 0, 1);
+      expected.set(x, 1, 2);
+    }
+
+    for _ in 0..64 {
+      uni.simulate(1).unwrap();
+    }
+    expected.simulate(64).unwrap();
+    let rect = expected.boundary();
+    assert_eq!(uni.boundary(), rect);
+    assert_eq!(uni.get_rect(&rect), expected.get_rect(&rect));
+    assert!(uni.stats().gc_runs > 0);
+  }
+
+  #[test]
+  fn test_gc_during_step() {
+    // a field of blinkers, whose nodes are shared by the steps of 1, 2, 4
+    // and 8 generations
+    let rule = "23/3/2".parse::<Generations>().unwrap();
+    let config = GcConfig { max_nodes: Some(100), ..GcConfig::default() };
+    let mut uni = MultiUniverse::with_config(Box::new(rule), config);
+    let mut expected = MultiUniverse::new(rule);
+    for i in 0..12 {
+      let (x, y) = (i % 4 * 6, i / 4 * 6);
+      for dx in 0..3 {
+        uni.set(x + dx, y, 1);
+        expected.set(x + dx, y, 1);
+      }
+    }
+
+    for _ in 0..4 {
+      for &num_gen in &[1, 2, 4, 8] {
+        uni.simulate(num_gen).unwrap();
+        expected.simulate(num_gen).unwrap();
+        let rect = expected.boundary();
+        assert_eq!(uni.boundary(), rect);
+        assert_eq!(uni.get_rect(&rect), expected.get_rect(&rect));
+      }
+    }
+    assert!(uni.stats().gc_runs > 0);
+    assert!(uni.stats().num_nodes() <= 100);
+  }
+
+  #[test]
+  fn test_memory_limit_blinkers() {
+    let rule = "23/3/2".parse::<Generations>().unwrap();
+    let blinkers = |max_nodes| {
+      let config = GcConfig { max_nodes: Some(max_nodes), ..GcConfig::default() };
+      let mut uni = MultiUniverse::with_config(Box::new(rule), config);
+      for i in 0..12 {
+        let (x, y) = (i % 4 * 6, i / 4 * 6);
+        for dx in 0..3 {
+          uni.set(x + dx, y, 1);
+        }
+      }
+      uni
+    };
+
+    // the limit is reached in every step, but the live nodes fit
+    let mut expected = blinkers(usize::MAX);
+    expected.simulate(15).unwrap();
+    let rect = expected.boundary();
+    for max_nodes in (90..=270).step_by(20) {
+      let mut uni = blinkers(max_nodes);
+      for &num_gen in &[1, 2, 4, 8] {
+        uni.simulate(num_gen).unwrap();
+      }
+      assert_eq!(uni.get_rect(&rect), expected.get_rect(&rect), "{}", max_nodes);
+      assert!(uni.stats().num_nodes() <= max_nodes);
+    }
+
+    let mut uni = blinkers(40);
+    let gen0 = uni.get_rect(&rect);
+    assert_eq!(uni.simulate(8), Err(MemoryLimitExceeded));
+    assert_eq!(uni.generation(), &BigUint::zero());
+    assert_eq!(uni.get_rect(&rect), gen0);
+  }
+
+  #[test]
+  fn test_memory_limit() {
+    let config = GcConfig { max_nodes: Some(500), ..GcConfig::default() };
+    let mut uni = MultiUniverse::with_config(Box::new(Generations::BRIANS_BRAIN), config);
+    let mut expected = MultiUniverse::new(Generations::BRIANS_BRAIN);
+    let mut seed = 7u32;
+    for y in -8..8 {
+      for x in -8..8 {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let state = (seed >> 16) as u8 % 3;
+        uni.set(x, y, state);
+        expected.set(x, y, state);
+      }
+    }
+
+    // garbage is collected in the middle of steps
+    uni.simulate(256).unwrap();
+    expected.simulate(256).unwrap();
+    let rect = expected.boundary();
+    assert_eq!(uni.boundary(), rect);
+    assert_eq!(uni.get_rect(&rect), expected.get_rect(&rect));
+    assert!(uni.stats().gc_runs > 0);
+    assert!(uni.stats().num_nodes() <= 500);
+
+    uni.set_gc_config(GcConfig { max_nodes: Some(50), ..config });
+    assert_eq!(uni.simulate(256), Err(MemoryLimitExceeded));
+    assert_eq!(uni.generation(), &BigUint::from(256u32));
+    assert_eq!(uni.get_rect(&rect), expected.get_rect(&rect));
+  }
+
+}
@@ -1,34 +1,38 @@
-use std::cell::Cell;
-use std::hash::{Hash, Hasher};
-use indexmap::Equivalent;
+use std::sync::atomic::{AtomicU32, Ordering};
+use crate::arena::ArenaNode;
 
+/// Index of a node in the arena of its `Store`.
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub(crate) struct NodeId(pub(crate) u64);
+pub(crate) struct NodeId(pub(crate) u32);
 
-#[derive(Clone, Debug, Eq)]
+#[derive(Debug)]
 pub(crate) enum Node {
   Internal(InternalNode),
   Leaf(LeafNode),
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug)]
 pub(crate) struct LeafNode {
   pub(crate) key: LeafNodeKey,
   /// Results after one generation and two generations.
   pub(crate) results: [u16; 2],
-  pub(crate) mark: Cell<bool>,
+  pub(crate) population: u64,
+  pub(crate) mark: bool,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Debug)]
 pub(crate) struct InternalNode {
   pub(crate) key: InternalNodeKey,
-  pub(crate) result: Cell<NodeId>,
+  /// Id of the result, which can be set by the threads of a parallel step.
+  result: AtomicU32,
   /// `2 ^ level` cells on both sides of a root square.
   pub(crate) level: u16,
-  pub(crate) mark: Cell<bool>,
+  /// Number of alive cells, saturated at `u64::MAX`.
+  pub(crate) population: u64,
+  pub(crate) mark: bool,
 }
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub(crate) struct InternalNodeKey {
   pub(crate) nw: NodeId,
   pub(crate) ne: NodeId,
@@ -49,7 +53,7 @@ pub(crate) struct InternalNodeKey {
 ///
 /// I.e. bit 15 (the highest bit of `u16`) is the top left cell, bit 12 is the
 /// top right cell, etc.
-#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
 pub(crate) struct LeafNodeKey {
   pub(crate) nw: u16,
   pub(crate) ne: u16,
@@ -57,92 +61,48 @@ pub(crate) struct LeafNodeKey {
   pub(crate) se: u16,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub(crate) enum NodeKey {
   Internal(InternalNodeKey),
   Leaf(LeafNodeKey),
 }
 
-pub(crate) const INVALID_NODE_ID: NodeId = NodeId(0);
-
-impl Hash for Node {
-  fn hash<H: Hasher>(&self, state: &mut H) {
-    match self {
-      Node::Internal(InternalNode { key, .. }) => {
-        key.hash(state);
-        0.hash(state);
-      }
-      Node::Leaf(LeafNode { key, .. }) => {
-        key.hash(state);
-        1.hash(state);
-      }
-    }
-  }
-}
-
-impl Hash for NodeKey {
-  fn hash<H: Hasher>(&self, state: &mut H) {
-    match self {
-      NodeKey::Internal(key) => {
-        key.hash(state);
-        0.hash(state);
-      }
-      NodeKey::Leaf(key) => {
-        key.hash(state);
-        1.hash(state);
-      }
-    }
-  }
-}
+pub(crate) const INVALID_NODE_ID: NodeId = NodeId(u32::MAX);
 
-impl Equivalent<Box<Node>> for NodeKey {
-  fn equivalent(&self, key: &Box<Node>) -> bool {
-    match (self, key) {
-      (NodeKey::Internal(key1), box Node::Internal(InternalNode { key: key2, .. })) => {
-        key1 == key2
-      }
-      (NodeKey::Leaf(key1), box Node::Leaf(LeafNode { key: key2, .. })) => {
-        key1 == key2
-      }
-      _ => false,
-    }
-  }
-}
+/// Largest level of the root of a universe, centered at the origin. Cells are
+/// addressed by `i64`, which also holds the coordinates of the corners of the
+/// quadrants of such a root. Cells that leave it are removed by steps.
+pub(crate) const MAX_LEVEL: u16 = 62;
 
-impl PartialEq<Node> for Node {
-  fn eq(&self, other: &Node) -> bool {
-    match (self, other) {
-      (Node::Internal(InternalNode { key: key1, .. }),
-        Node::Internal(InternalNode { key: key2, .. }))
-      => {
-        key1 == key2
-      }
-      (Node::Leaf(LeafNode { key: key1, .. }),
-        Node::Leaf(LeafNode { key: key2, .. }))
-      => {
-        key1 == key2
-      }
-      _ => false,
-    }
-  }
+/// Whether the cell at `(x, y)` is inside a root of `MAX_LEVEL`.
+pub(crate) fn is_addressable(x: i64, y: i64) -> bool {
+  let radius = 1 << (MAX_LEVEL - 1);
+  (-radius..radius).contains(&x) && (-radius..radius).contains(&y)
 }
 
 impl Node {
   pub(crate) fn new_leaf(key: LeafNodeKey, results: [u16; 2]) -> Node {
+    let population = (key.nw.count_ones() + key.ne.count_ones() +
+      key.sw.count_ones() + key.se.count_ones()) as u64;
     Node::Leaf(LeafNode {
       key,
       results,
-      mark: Cell::new(false),
+      population,
+      mark: false,
     })
   }
 
-  pub(crate) fn new_internal(key: InternalNodeKey) -> Node {
-    let level = node_ref(key.nw).level() + 1;
+  pub(crate) fn new_internal(
+    key: InternalNodeKey,
+    level: u16,
+    population: u64,
+  ) -> Node {
     Node::Internal(InternalNode {
       key,
-      result: Cell::new(INVALID_NODE_ID),
+      result: AtomicU32::new(INVALID_NODE_ID.0),
       level,
-      mark: Cell::new(false),
+      population,
+      mark: false,
     })
   }
 
@@ -167,14 +127,60 @@ impl Node {
     }
   }
 
-  pub(crate) fn mark(&self) -> &Cell<bool> {
+  pub(crate) fn population(&self) -> u64 {
     match self {
-      Node::Leaf(node) => &node.mark,
-      Node::Internal(node) => &node.mark,
+      Node::Internal(node) => node.population,
+      Node::Leaf(node) => node.population,
+    }
+  }
+
+  pub(crate) fn mark_mut(&mut self) -> &mut bool {
+    match self {
+      Node::Leaf(node) => &mut node.mark,
+      Node::Internal(node) => &mut node.mark,
     }
   }
 }
 
+impl ArenaNode for Node {
+  type Key = NodeKey;
+
+  fn level(&self) -> u16 {
+    self.level()
+  }
+
+  fn mark_mut(&mut self) -> &mut bool {
+    self.mark_mut()
+  }
+
+  fn for_each_ref(&self, mut f: impl FnMut(NodeId)) {
+    if let Node::Internal(node) = self {
+      f(node.key.nw);
+      f(node.key.ne);
+      f(node.key.sw);
+      f(node.key.se);
+      let result = node.result();
+      if result != INVALID_NODE_ID {
+        f(result);
+      }
+    }
+  }
+}
+
+impl InternalNode {
+  pub(crate) fn result(&self) -> NodeId {
+    NodeId(self.result.load(Ordering::Acquire))
+  }
+
+  pub(crate) fn set_result(&self, result: NodeId) {
+    self.result.store(result.0, Ordering::Release);
+  }
+
+  pub(crate) fn clear_result(&mut self) {
+    *self.result.get_mut() = INVALID_NODE_ID.0;
+  }
+}
+
 impl NodeKey {
   pub(crate) fn new_leaf(nw: u16, ne: u16, sw: u16, se: u16) -> Self {
     Self::Leaf(LeafNodeKey { nw, ne, sw, se })
@@ -193,13 +199,3 @@ impl LeafNodeKey {
       self.sw >> 6 & 0x00cc | self.se >> 10 & 0x0033
   }
 }
-
-#[cfg(target_pointer_width = "32")]
-pub(crate) fn node_ref(NodeId(n): NodeId) -> &'static Node {
-  unsafe { std::mem::transmute(n as u32) }
-}
-
-#[cfg(target_pointer_width = "64")]
-pub(crate) fn node_ref(NodeId(n): NodeId) -> &'static Node {
-  unsafe { std::mem::transmute(n) }
-}
@@ -0,0 +1,325 @@
+use rustc_hash::{FxHashMap, FxHasher};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use crate::node::*;
+use crate::store::{compute_level4_result1, new_node, MemoryLimitExceeded, Store};
+
+/// Nodes of at least this level step their sub-squares in parallel.
+const MIN_PARALLEL_LEVEL: u16 = 8;
+const NUM_SHARDS: usize = 64;
+/// The `i`-th bucket holds `2 ^ (i + FIRST_BUCKET_BITS)` nodes.
+const FIRST_BUCKET_BITS: u32 = 10;
+const NUM_BUCKETS: usize = 32 - FIRST_BUCKET_BITS as usize + 1;
+
+/// A step of the nodes of a store by many threads.
+///
+/// The threads read the nodes of the store and memoize results in them. The
+/// store cannot be modified during the step, so new nodes are hash-consed in
+/// a separate arena, and are added to the store after the step. They take the
+/// free ids of the store first, in the order of `Store::find_node`.
+pub(crate) struct ParallelStep<'a> {
+  store: &'a Store,
+  free_ids: &'a [NodeId],
+  /// Id of the first new node that does not take a free id.
+  first_id: usize,
+  /// One plus the indices in the arena of the nodes that took free ids,
+  /// indexed by id.
+  redirects: Vec<AtomicU32>,
+  /// Append-only arena, split into buckets of doubling sizes so that nodes
+  /// never move.
+  buckets: Vec<OnceLock<Box<[OnceLock<Node>]>>>,
+  num_nodes: AtomicUsize,
+  max_nodes: usize,
+  /// Hash-consing tables of new nodes, sharded by the hash of keys.
+  shards: Vec<Mutex<FxHashMap<NodeKey, NodeId>>>,
+  find_hits: AtomicU64,
+  memo_hits: AtomicU64,
+}
+
+/// Nodes created by a parallel step in the order of their ids, and the
+/// counters of the step.
+pub(crate) struct NewNodes {
+  pub(crate) nodes: Vec<Node>,
+  pub(crate) find_hits: u64,
+  pub(crate) memo_hits: u64,
+}
+
+impl<'a> ParallelStep<'a> {
+  /// `max_nodes` is the maximum number of new nodes.
+  pub(crate) fn new(store: &'a Store, max_nodes: usize) -> Self {
+    let free_ids = store.free_ids();
+    let first_id = store.num_ids();
+    let max_ids = free_ids.len() + INVALID_NODE_ID.0 as usize - first_id;
+    Self {
+      store,
+      free_ids,
+      first_id,
+      redirects: (0..first_id).map(|_| AtomicU32::new(0)).collect(),
+      buckets: (0..NUM_BUCKETS).map(|_| OnceLock::new()).collect(),
+      num_nodes: AtomicUsize::new(0),
+      max_nodes: max_nodes.min(max_ids),
+      shards: (0..NUM_SHARDS).map(|_| Mutex::default()).collect(),
+      find_hits: AtomicU64::new(0),
+      memo_hits: AtomicU64::new(0),
+    }
+  }
+
+  pub(crate) fn finish(self) -> NewNodes {
+    let num_nodes = self.num_nodes.into_inner();
+    let nodes = self.buckets.into_iter()
+      .filter_map(OnceLock::into_inner)
+      .flat_map(|nodes| nodes.into_vec())
+      .take(num_nodes)
+      .map(|node| node.into_inner().unwrap())
+      .collect();
+    NewNodes {
+      nodes,
+      find_hits: self.find_hits.into_inner(),
+      memo_hits: self.memo_hits.into_inner(),
+    }
+  }
+
+  fn slot(&self, i: usize) -> &OnceLock<Node> {
+    let n = i as u64 + (1 << FIRST_BUCKET_BITS);
+    let bucket = 63 - n.leading_zeros() - FIRST_BUCKET_BITS;
+    let bucket_size = 1usize << (bucket + FIRST_BUCKET_BITS);
+    let nodes = self.buckets[bucket as usize].get_or_init(|| {
+      (0..bucket_size).map(|_| OnceLock::new()).collect()
+    });
+    &nodes[n as usize - bucket_size]
+  }
+
+  fn node(&self, id: NodeId) -> &Node {
+    let i = match (id.0 as usize).checked_sub(self.first_id) {
+      Some(i) => i + self.free_ids.len(),
+      None => match self.redirects[id.0 as usize].load(Ordering::Relaxed) {
+        0 => return self.store.node(id),
+        i => i as usize - 1,
+      },
+    };
+    self.slot(i).get().unwrap()
+  }
+
+  fn internal_key(&self, id: NodeId) -> InternalNodeKey {
+    self.node(id).unwrap_internal_ref().key
+  }
+
+  fn leaf(&self, id: NodeId) -> LeafNode {
+    *self.node(id).unwrap_leaf_ref()
+  }
+
+  fn find_node(&self, key: NodeKey) -> Result<NodeId, MemoryLimitExceeded> {
+    if let Some(id) = self.store.lookup(&key) {
+      self.find_hits.fetch_add(1, Ordering::Relaxed);
+      return Ok(id);
+    }
+
+    let mut hasher = FxHasher::default();
+    key.hash(&mut hasher);
+    // the low bits are left to the table of the shard
+    let shard = &self.shards[(hasher.finish() >> 40) as usize % NUM_SHARDS];
+
+    let mut table = shard.lock().unwrap();
+    if let Some(&id) = table.get(&key) {
+      self.find_hits.fetch_add(1, Ordering::Relaxed);
+      return Ok(id);
+    }
+
+    // a node is stored before the table is unlocked, so that the arena has
+    // no holes
+    if self.num_nodes.load(Ordering::Relaxed) >= self.max_nodes {
+      return Err(MemoryLimitExceeded);
+    }
+    let i = self.num_nodes.fetch_add(1, Ordering::Relaxed);
+    let node = new_node(key, |id| self.node(id), self.store.level2_results());
+    let _ = self.slot(i).set(node);
+    let id = match self.free_ids.len().checked_sub(i + 1) {
+      Some(j) => {
+        let id = self.free_ids[j];
+        self.redirects[id.0 as usize].store(i as u32 + 1, Ordering::Relaxed);
+        id
+      }
+      None => NodeId((self.first_id + i - self.free_ids.len()) as u32),
+    };
+    table.insert(key, id);
+    Ok(id)
+  }
+
+  /// Advance `2 ^ min(k, level - 2)` generations. Must be called inside a
+  /// rayon thread pool.
+  pub(crate) fn step_rec(
+    &self,
+    node: NodeId,
+    k: u16,
+  ) -> Result<NodeId, MemoryLimitExceeded> {
+    let node = self.node(node).unwrap_internal_ref();
+    let result = node.result();
+    if result != INVALID_NODE_ID {
+      self.memo_hits.fetch_add(1, Ordering::Relaxed);
+      return Ok(result);
+    }
+
+    let result = if node.level == 4 {
+      self.leaf_step(node.key, k)?
+    } else {
+      self.internal_step(node.key, node.level, k)?
+    };
+    node.set_result(result);
+    Ok(result)
+  }
+
+  fn internal_step(
+    &self,
+    key: InternalNodeKey,
+    level: u16,
+    k: u16,
+  ) -> Result<NodeId, MemoryLimitExceeded> {
+    let nw = self.internal_key(key.nw);
+    let ne = self.internal_key(key.ne);
+    let sw = self.internal_key(key.sw);
+    let se = self.internal_key(key.se);
+
+    let [n0, n1, n2, n3, n4, n5, n6, n7, n8] = self.step_all(level, [
+      key.nw,
+      self.find_node(NodeKey::new_internal(nw.ne, ne.nw, nw.se, ne.sw))?,
+      key.ne,
+      self.find_node(NodeKey::new_internal(nw.sw, nw.se, sw.nw, sw.ne))?,
+      self.find_node(NodeKey::new_internal(nw.se, ne.sw, sw.ne, se.nw))?,
+      self.find_node(NodeKey::new_internal(ne.sw, ne.se, se.nw, se.ne))?,
+      key.sw,
+      self.find_node(NodeKey::new_internal(sw.ne, se.nw, sw.se, se.sw))?,
+      key.se,
+    ], k)?;
+
+    let [nw, ne, sw, se] = if k >= level - 2 {
+      self.step_all(level, [
+        self.find_node(NodeKey::new_internal(n0, n1, n3, n4))?,
+        self.find_node(NodeKey::new_internal(n1, n2, n4, n5))?,
+        self.find_node(NodeKey::new_internal(n3, n4, n6, n7))?,
+        self.find_node(NodeKey::new_internal(n4, n5, n7, n8))?,
+      ], k)?
+    } else if level > 5 {
+      let n0 = self.internal_key(n0);
+      let n1 = self.internal_key(n1);
+      let n2 = self.internal_key(n2);
+      let n3 = self.internal_key(n3);
+      let n4 = self.internal_key(n4);
+      let n5 = self.internal_key(n5);
+      let n6 = self.internal_key(n6);
+      let n7 = self.internal_key(n7);
+      let n8 = self.internal_key(n8);
+      [
+        self.find_node(NodeKey::new_internal(n0.se, n1.sw, n3.ne, n4.nw))?,
+        self.find_node(NodeKey::new_internal(n1.se, n2.sw, n4.ne, n5.nw))?,
+        self.find_node(NodeKey::new_internal(n3.se, n4.sw, n6.ne, n7.nw))?,
+        self.find_node(NodeKey::new_internal(n4.se, n5.sw, n7.ne, n8.nw))?,
+      ]
+    } else {
+      let n0 = self.leaf(n0).key;
+      let n1 = self.leaf(n1).key;
+      let n2 = self.leaf(n2).key;
+      let n3 = self.leaf(n3).key;
+      let n4 = self.leaf(n4).key;
+      let n5 = self.leaf(n5).key;
+      let n6 = self.leaf(n6).key;
+      let n7 = self.leaf(n7).key;
+      let n8 = self.leaf(n8).key;
+      [
+        self.find_node(NodeKey::new_leaf(n0.se, n1.sw, n3.ne, n4.nw))?,
+        self.find_node(NodeKey::new_leaf(n1.se, n2.sw, n4.ne, n5.nw))?,
+        self.find_node(NodeKey::new_leaf(n3.se, n4.sw, n6.ne, n7.nw))?,
+        self.find_node(NodeKey::new_leaf(n4.se, n5.sw, n7.ne, n8.nw))?,
+      ]
+    };
+
+    self.find_node(NodeKey::new_internal(nw, ne, sw, se))
+  }
+
+  /// Steps independent sub-squares of a node of `level`, in parallel if the
+  /// level is high enough.
+  fn step_all<const N: usize>(
+    &self,
+    level: u16,
+    nodes: [NodeId; N],
+    k: u16,
+  ) -> Result<[NodeId; N], MemoryLimitExceeded> {
+    let mut results = [Ok(INVALID_NODE_ID); N];
+    if level >= MIN_PARALLEL_LEVEL {
+      rayon::scope(|scope| {
+        for (result, &node) in results.iter_mut().zip(&nodes) {
+          scope.spawn(move |_| *result = self.step_rec(node, k));
+        }
+      });
+    } else {
+      for (result, &node) in results.iter_mut().zip(&nodes) {
+        *result = self.step_rec(node, k);
+      }
+    }
+
+    let mut ids = [INVALID_NODE_ID; N];
+    for (id, result) in ids.iter_mut().zip(results) {
+      *id = result?;
+    }
+    Ok(ids)
+  }
+
+  fn leaf_step(
+    &self,
+    key: InternalNodeKey,
+    k: u16,
+  ) -> Result<NodeId, MemoryLimitExceeded> {
+    let nw = self.leaf(key.nw);
+    let ne = self.leaf(key.ne);
+    let sw = self.leaf(key.sw);
+    let se = self.leaf(key.se);
+
+    if k == 0 && self.store.last_odd() {
+      let result = compute_level4_result1(
+        &self.store.level2_results()[2], nw.key, ne.key, sw.key, se.key);
+      return self.find_node(NodeKey::Leaf(result));
+    }
+
+    let ix = (k > 0) as usize;
+    let n0 = nw.results[ix];
+    let n1 = self.leaf_result(
+      NodeKey::new_leaf(nw.key.ne, ne.key.nw, nw.key.se, ne.key.sw), ix)?;
+    let n2 = ne.results[ix];
+    let n3 = self.leaf_result(
+      NodeKey::new_leaf(nw.key.sw, nw.key.se, sw.key.nw, sw.key.ne), ix)?;
+    let n4 = self.leaf_result(
+      NodeKey::new_leaf(nw.key.se, ne.key.sw, sw.key.ne, se.key.nw), ix)?;
+    let n5 = self.leaf_result(
+      NodeKey::new_leaf(ne.key.sw, ne.key.se, se.key.nw, se.key.ne), ix)?;
+    let n6 = sw.results[ix];
+    let n7 = self.leaf_result(
+      NodeKey::new_leaf(sw.key.ne, se.key.nw, sw.key.se, se.key.sw), ix)?;
+    let n8 = se.results[ix];
+
+    let nw;
+    let ne;
+    let sw;
+    let se;
+    if k >= 2 {
+      nw = self.leaf_result(NodeKey::new_leaf(n0, n1, n3, n4), 1)?;
+      ne = self.leaf_result(NodeKey::new_leaf(n1, n2, n4, n5), 1)?;
+      sw = self.leaf_result(NodeKey::new_leaf(n3, n4, n6, n7), 1)?;
+      se = self.leaf_result(NodeKey::new_leaf(n4, n5, n7, n8), 1)?;
+    } else {
+      nw = LeafNodeKey { nw: n0, ne: n1, sw: n3, se: n4 }.center();
+      ne = LeafNodeKey { nw: n1, ne: n2, sw: n4, se: n5 }.center();
+      sw = LeafNodeKey { nw: n3, ne: n4, sw: n6, se: n7 }.center();
+      se = LeafNodeKey { nw: n4, ne: n5, sw: n7, se: n8 }.center();
+    }
+
+    self.find_node(NodeKey::new_leaf(nw, ne, sw, se))
+  }
+
+  fn leaf_result(
+    &self,
+    key: NodeKey,
+    ix: usize,
+  ) -> Result<u16, MemoryLimitExceeded> {
+    Ok(self.leaf(self.find_node(key)?).results[ix])
+  }
+}
@@ -1,52 +1,229 @@
+use std::convert::TryFrom;
+use std::path::Path;
+use crate::colorlife::ColorLife;
+use crate::generations::Generations;
+use crate::ltl::LargerThanLife;
+use crate::multistate::{MultiRule, MultiUniverse};
 use crate::rule::*;
+use crate::ruletable::RuleTable;
+use crate::secondorder::{self, SecondOrder};
 use crate::universe::*;
+use num_bigint::BigUint;
+use num_traits::Zero;
 
 /// Read a Life pattern from a RLE string.
 ///
 /// RLE format: <https://www.conwaylife.com/wiki/Run_Length_Encoded>.
+///
+/// The rule may have a bounded topology suffix, such as `B3/S23:T100,80`.
+/// Unless a position is given in a `#CXRLE` line, a pattern in a bounded grid
+/// is placed at the top left corner of the grid.
 pub fn read(
   src: impl AsRef<str>,
 ) -> Result<Universe, String> {
-  let mut src = src.as_ref();
+  let (header, src) = read_header(src.as_ref())?;
+  let rule = match &header.rule {
+    Some(rule) => rule.parse::<Rule>().map_err(|e| e.to_string())?,
+    None => GAME_OF_LIFE,
+  };
+
+  let mut uni = Universe::new(rule);
+  if let Some(generation) = header.generation {
+    uni.set_generation(generation);
+  }
+
+  let (left, top) = match (header.position, rule.topology().grid()) {
+    (Some(position), _) => position,
+    (None, Some(grid)) => (grid.left, grid.top),
+    (None, None) => (0, 0),
+  };
+  // the dead cells are written only if the background is alive
+  if uni.background() {
+    let (width, height) = header.size;
+    for y in top..top + height as i64 {
+      for x in left..left + width as i64 {
+        uni.set(x, y, false);
+      }
+    }
+  }
+  read_cells(src, left, top, |x, y, _| uni.set(x, y, true))?;
+  Ok(uni)
+}
+
+/// Read a pattern of a multi-state rule, such as the Generations rule
+/// `23/3/8` or the Larger than Life rule `R5,C0,M1,S34..58,B34..45,NM`, from
+/// a RLE string.
+///
+/// The states 1 to 255 are written as `A` to `X`, `pA` to `pX`, ..., `yA` to
+/// `yO`, and the state 0 as `.` or `b`.
+pub fn read_multi(
+  src: impl AsRef<str>,
+) -> Result<MultiUniverse, String> {
+  read_multi_impl(src.as_ref(), None)
+}
+
+/// Same as [`read_multi`], except that a rule other than a Generations or
+/// Larger than Life rule is loaded from the `.rule` file of its name in
+/// `rule_dir`.
+pub fn read_multi_with_rules(
+  src: impl AsRef<str>,
+  rule_dir: impl AsRef<Path>,
+) -> Result<MultiUniverse, String> {
+  read_multi_impl(src.as_ref(), Some(rule_dir.as_ref()))
+}
+
+fn read_multi_impl(
+  src: &str,
+  rule_dir: Option<&Path>,
+) -> Result<MultiUniverse, String> {
+  let (header, src) = read_header(src)?;
+  let rule = header.rule.ok_or_else(|| "missing rule in header line".to_owned())?;
+  if rule.contains(':') {
+    return Err("bounded topologies are not supported for multi-state rules".to_owned());
+  }
+  let rule = parse_multi_rule(&rule, rule_dir)?;
+
+  let num_states = rule.num_states();
+  let mut uni = MultiUniverse::with_boxed_rule(rule);
+  if let Some(generation) = header.generation {
+    uni.set_generation(generation);
+  }
+
+  let (left, top) = header.position.unwrap_or((0, 0));
+  let mut invalid_state = None;
+  read_cells(src, left, top, |x, y, state| {
+    if (state as usize) < num_states {
+      uni.set(x, y, state);
+    } else {
+      invalid_state = Some(state);
+    }
+  })?;
+  match invalid_state {
+    Some(state) => Err(format!("invalid state {}", state)),
+    None => Ok(uni),
+  }
+}
+
+/// Parses a Generations, Larger than Life, colored Life or second-order rule,
+/// or loads the rule from `rule_dir`.
+fn parse_multi_rule(
+  s: &str,
+  rule_dir: Option<&Path>,
+) -> Result<Box<dyn MultiRule>, String> {
+  if let Some(rule) = s.strip_suffix(secondorder::SUFFIX) {
+    return rule.parse::<Rule>().ok()
+      .and_then(SecondOrder::new)
+      .map(|rule| Box::new(rule) as Box<dyn MultiRule>)
+      .ok_or_else(|| format!("invalid second-order rule {:?}", s));
+  }
+  if s.contains('/') {
+    return Ok(Box::new(s.parse::<Generations>()?));
+  }
+  if s.contains(',') {
+    return Ok(Box::new(s.parse::<LargerThanLife>()?));
+  }
+  if let Ok(rule) = s.parse::<ColorLife>() {
+    return Ok(Box::new(rule));
+  }
+  match rule_dir {
+    Some(dir) => Ok(Box::new(RuleTable::load(dir, s)?)),
+    None => Err(format!("unknown rule {:?}", s)),
+  }
+}
+
+struct Header {
+  generation: Option<BigUint>,
+  position: Option<(i64, i64)>,
+  /// Width and height of the pattern.
+  size: (u32, u32),
+  /// The rule with its topology suffix if any.
+  rule: Option<String>,
+}
+
+/// Reads the comment lines and the header line, and returns the header and
+/// the rest of `src`.
+fn read_header(mut src: &str) -> Result<(Header, &str), String> {
   if src.is_empty() {
     return Err("empty".to_owned());
   }
 
-  let header = src.lines().next().unwrap()
-    .split(",")
-    .map(|s| s.trim());
+  let mut generation = None::<BigUint>;
+  let mut position = None::<(i64, i64)>;
+  while src.starts_with('#') {
+    let line = src.lines().next().unwrap();
+    if let Some(fields) = line.strip_prefix("#CXRLE") {
+      for w in fields.split_whitespace() {
+        if let Some(gen) = w.strip_prefix("Gen=") {
+          generation = Some(gen.parse()
+            .map_err(|_| "invalid Gen in #CXRLE line".to_owned())?);
+        } else if let Some(pos) = w.strip_prefix("Pos=") {
+          position = Some(parse_position(pos)
+            .ok_or_else(|| "invalid Pos in #CXRLE line".to_owned())?);
+        }
+      }
+    }
+    src = src[line.len()..].trim_start();
+  }
+
+  if src.is_empty() {
+    return Err("missing header line".to_owned());
+  }
+
+  // a comma without `=` after it belongs to the value, as in
+  // `rule = B3/S23:T100,80`
+  let mut fields = Vec::<String>::new();
+  for w in src.lines().next().unwrap().split(",") {
+    match fields.last_mut() {
+      Some(last) if !w.contains('=') => {
+        last.push(',');
+        last.push_str(w);
+      }
+      _ => fields.push(w.to_owned()),
+    }
+  }
 
   let mut width = None::<u32>;
   let mut height = None::<u32>;
-  let mut rule = None::<Rule>;
-  for w in header {
+  let mut rule = None::<String>;
+  for w in fields {
     let kv = w.split("=").map(|s| s.trim()).collect::<Vec<_>>();
     if kv.len() != 2 {
       return Err("invalid header line".to_owned());
     }
-    match &kv[0][..] {
+    match kv[0] {
       "x" => {
         width = Some(kv[1].parse().map_err(|_| "invalid x".to_owned())?);
       }
       "y" => {
         height = Some(kv[1].parse().map_err(|_| "invalid y".to_owned())?);
       }
-      "rule" => {
-        rule = Some(parse_rule(&kv[1]).ok_or_else(|| "invalid header line".to_owned())?);
-      }
+      "rule" => rule = Some(kv[1].to_owned()),
       _ => {}
     }
   }
 
-  let _width = width.ok_or_else(|| "missing x in header line".to_owned())?;
-  let _height = height.ok_or_else(|| "missing y in header line".to_owned())?;
-  let rule = rule.unwrap_or(GAME_OF_LIFE);
+  let width = width.ok_or_else(|| "missing x in header line".to_owned())?;
+  let height = height.ok_or_else(|| "missing y in header line".to_owned())?;
 
-  let mut uni = Universe::new(rule);
-  src = &src[src.find('\n').unwrap_or(src.len())..];
+  let header = Header {
+    generation,
+    position,
+    size: (width, height),
+    rule,
+  };
+  Ok((header, &src[src.find('\n').unwrap_or(src.len())..]))
+}
 
-  let mut x = 0;
-  let mut y = 0;
+/// Reads the runs of cells up to `!`, and calls `set` with every cell in a
+/// non-zero state. The first row starts at `(left, top)`.
+fn read_cells(
+  mut src: &str,
+  left: i64,
+  top: i64,
+  mut set: impl FnMut(i64, i64, u8),
+) -> Result<(), String> {
+  let mut x = left;
+  let mut y = top;
   loop {
     src = src.trim_start();
 
@@ -60,66 +237,53 @@ pub fn read(
     }
 
     let mut num = 1;
-    if b0 >= b'0' && b0 <= b'9' {
+    if b0.is_ascii_digit() {
       let num_len = src.find(|c: char| !c.is_ascii_digit()).unwrap_or(src.len());
       num = src[..num_len].parse().unwrap();
       src = &src[num_len..];
     }
 
-    match src.as_bytes()[0] {
-      b'b' => {
+    let bytes = src.as_bytes();
+    let mut len = 1;
+    match bytes[0] {
+      b'b' | b'.' => {
         x += num;
       }
       b'$' => {
-        x = 0;
+        x = left;
         y += num;
       }
       c => {
-        if c.is_ascii_alphabetic() {
-          for i in 0..num {
-            uni.set(x + i, y, true);
+        let state = match (c, bytes.get(1)) {
+          (b'A'..=b'X', _) => c - b'A' + 1,
+          (b'p'..=b'y', Some(&d @ b'A'..=b'X')) => {
+            len = 2;
+            let state = (c - b'p' + 1) as u32 * 24 + (d - b'A' + 1) as u32;
+            u8::try_from(state).map_err(|_| format!("invalid state {}", state))?
           }
-          x += num;
-        } else {
-          return Err(format!("invalid character {:?}", src.chars().next().unwrap()));
+          // any other letter is an alive cell
+          _ if c.is_ascii_alphabetic() => 1,
+          _ => return Err(format!("invalid character {:?}", src.chars().next().unwrap())),
+        };
+        for i in 0..num {
+          set(x + i, y, state);
         }
+        x += num;
       }
     }
 
-    src = &src[1..];
+    src = &src[len..];
   }
 
-  Ok(uni)
+  Ok(())
 }
 
-fn parse_rule(s: &str) -> Option<Rule> {
-  let r = s.split("/").collect::<Vec<_>>();
-  if r.len() != 2 {
-    return None;
-  }
-  if r[0].as_bytes()[0].to_ascii_lowercase() != b'b' ||
-    r[1].as_bytes()[0].to_ascii_lowercase() != b's'
-  {
-    return None;
-  }
-
-  let mut rule = Rule::new();
-  for c in r[0].chars().skip(1) {
-    let b = c.to_digit(10)?;
-    if b > 8 {
-      return None;
-    }
-    rule.set_birth(b as u8);
+fn parse_position(s: &str) -> Option<(i64, i64)> {
+  let mut xy = s.split(',').map(|s| s.parse().ok());
+  match (xy.next()?, xy.next()?, xy.next()) {
+    (Some(x), Some(y), None) => Some((x, y)),
+    _ => None,
   }
-  for c in r[1].chars().skip(1) {
-    let b = c.to_digit(10)?;
-    if b > 8 {
-      return None;
-    }
-    rule.set_survival(b as u8);
-  }
-
-  Some(rule)
 }
 
 /// Write a Life pattern to a RLE string.
@@ -130,8 +294,21 @@ pub fn write(
 ) -> String {
   let Boundary {left, top, right, bottom} = univ.boundary();
   let width = (right - left) as u32;
-  let mut output = format!("x = {}, y = {}, rule = {}\n",
-    width, bottom - top, univ.rule());
+  let topology = univ.topology();
+  let mut output = String::new();
+  if topology.is_bounded() || !univ.generation().is_zero() {
+    output.push_str("#CXRLE");
+    // the position matters only in a bounded grid
+    if topology.is_bounded() {
+      output.push_str(&format!(" Pos={},{}", left, top));
+    }
+    if !univ.generation().is_zero() {
+      output.push_str(&format!(" Gen={}", univ.generation()));
+    }
+    output.push('\n');
+  }
+  output.push_str(&format!("x = {}, y = {}, rule = {}\n",
+    width, bottom - top, univ.rule()));
   let data = crate::export::write_buffer(univ);
 
   let mut num_consec_next_rows = 0;
@@ -147,7 +324,7 @@ pub fn write(
           num_new_unit = x.leading_zeros().min(left_bits).min(row_left_bits);
           RleUnit::Dead
         } else {
-          num_new_unit = x.leading_ones();
+          num_new_unit = x.leading_ones().min(left_bits).min(row_left_bits);
           RleUnit::Alive
         };
         left_bits -= num_new_unit;
@@ -199,23 +376,70 @@ pub fn write(
   output
 }
 
+/// Write a pattern of a multi-state rule to a RLE string.
+pub fn write_multi(
+  univ: &MultiUniverse,
+) -> String {
+  let rect@Boundary {left, top, right, bottom} = univ.boundary();
+  let width = (right - left) as usize;
+  let mut output = String::new();
+  if !univ.generation().is_zero() {
+    output.push_str(&format!("#CXRLE Gen={}\n", univ.generation()));
+  }
+  output.push_str(&format!("x = {}, y = {}, rule = {}\n",
+    width, bottom - top, univ.rule()));
+
+  let cells = univ.get_rect(&rect);
+  let mut num_consec_next_rows = 0;
+  for row in cells.chunks(width.max(1)) {
+    let len = row.iter().rposition(|&state| state != 0).map_or(0, |i| i + 1);
+    if len > 0 && num_consec_next_rows > 0 {
+      RleUnit::NextRow.write(num_consec_next_rows, &mut output);
+      num_consec_next_rows = 0;
+    }
+
+    let mut x = 0;
+    while x < len {
+      let state = row[x];
+      let num = row[x..len].iter().take_while(|&&s| s == state).count();
+      RleUnit::State(state).write(num as u32, &mut output);
+      x += num;
+    }
+    num_consec_next_rows += 1;
+  }
+
+  output.push('!');
+  output.push('\n');
+  output
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum RleUnit {
   Dead,
   Alive,
   NextRow,
+  /// A cell of a multi-state rule.
+  State(u8),
 }
 
 impl RleUnit {
   fn write(&self, num: u32, s: &mut String) {
-    let c = match self {
-      Self::Dead => 'b',
-      Self::Alive => 'o',
-      Self::NextRow => '$',
+    let c = match *self {
+      Self::Dead => "b".to_owned(),
+      Self::Alive => "o".to_owned(),
+      Self::NextRow => "$".to_owned(),
+      Self::State(0) => ".".to_owned(),
+      Self::State(state) => {
+        let letter = (b'A' + (state - 1) % 24) as char;
+        match (state - 1) / 24 {
+          0 => letter.to_string(),
+          prefix => format!("{}{}", (b'p' + prefix - 1) as char, letter),
+        }
+      }
     };
 
     let buf = if num == 1 {
-      c.to_string()
+      c
     } else {
       format!("{}{}", num, c)
     };
@@ -231,6 +455,7 @@ impl RleUnit {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::topology::Topology;
 
   #[test]
   fn read_glider() {
@@ -239,7 +464,7 @@ x = 3, y = 3
 bo$2bo$3o!
 ".trim();
 
-    let uni = read(src.to_owned()).unwrap();
+    let uni = read(src).unwrap();
     assert_eq!(uni.debug_root(), vec![
       0b_0000_0000,
       0b_0000_0000,
@@ -251,4 +476,224 @@ bo$2bo$3o!
       0b_0000_0000,
     ]);
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn read_generation() {
+    let src = r"
+#N Glider
+#CXRLE Pos=0,0 Gen=123456789012345678901234567890
+x = 3, y = 3
+bo$2bo$3o!
+".trim();
+
+    let uni = read(src).unwrap();
+    assert_eq!(uni.generation().to_string(), "123456789012345678901234567890");
+  }
+
+  #[test]
+  fn write_generation() {
+    let src = "#CXRLE Gen=100000000000000000000\nx = 3, y = 3, rule = B3/S23\nbo$2bo$3o!\n";
+
+    let uni = read(src).unwrap();
+    assert_eq!(write(&uni), src);
+  }
+
+  #[test]
+  fn read_topology() {
+    let src = "x = 3, y = 3, rule = B3/S23:T10,8\nbo$2bo$3o!\n";
+    let uni = read(src).unwrap();
+    assert_eq!(uni.topology(), &Topology::Torus { width: 10, height: 8 });
+    assert_eq!(uni.boundary(), Boundary { left: -5, top: -4, right: -2, bottom: -1 });
+    assert!(uni.get(-4, -4));
+
+    let src = "#CXRLE Pos=1,2 Gen=5\nx = 3, y = 3, rule = B3/S23:P12,12\nbo$2bo$3o!\n";
+    let uni = read(src).unwrap();
+    assert_eq!(uni.boundary(), Boundary { left: 1, top: 2, right: 4, bottom: 5 });
+    assert_eq!(write(&uni), src);
+
+    assert!(read("x = 3, y = 3, rule = B3/S23:T0\nbo$2bo$3o!").is_err());
+    assert!(read("x = 3, y = 3, rule = B3/S23:\u{e9}\nbo$2bo$3o!").is_err());
+    assert!(read_multi("x = 3, y = 3, rule = 23/3/8:T10\nbo$2bo$3o!").is_err());
+  }
+
+  #[test]
+  fn read_isotropic_rule() {
+    for rule in &["B2-a/S12", "B3-ckq4z/S23", "B2ce3aiy/S2-k3"] {
+      let src = format!("x = 3, y = 3, rule = {}\nbo$2bo$3o!\n", rule);
+      let uni = read(&src).unwrap();
+      assert_eq!(&uni.rule().to_string(), rule);
+      assert_eq!(write(&uni), src);
+    }
+    assert_eq!("B3ceaiknjqry/S23".parse::<Rule>().unwrap().to_string(), "B3/S23");
+    assert!("B2z/S23".parse::<Rule>().is_err());
+    assert!("B2-/S23".parse::<Rule>().is_err());
+  }
+
+  #[test]
+  fn read_map_rule() {
+    let rule = Rule::from_fn(|n| n[1][0]);
+    let src = format!("x = 3, y = 3, rule = {}\nbo$2bo$3o!\n", rule.to_map());
+    let mut uni = read(&src).unwrap();
+    assert_eq!(write(&uni), src);
+
+    // the pattern moves to the east
+    uni.simulate(10).unwrap();
+    assert_eq!(uni.boundary(), Boundary { left: 10, top: 0, right: 13, bottom: 3 });
+  }
+
+  #[test]
+  fn read_b0_rule() {
+    // AntiLife, whose background stays alive
+    let src = "x = 3, y = 3, rule = B0123478/S01234678\nbo$2bo$3o!\n";
+    let mut uni = read(src).unwrap();
+    assert_eq!(uni.rule().to_string(), "B0123478/S01234678");
+    assert!(uni.background());
+    assert_eq!(uni.population(), 2u32.into());
+    // the last row is as alive as the background
+    assert_eq!(write(&uni), "x = 3, y = 2, rule = B0123478/S01234678\nbo$2bo!\n");
+
+    // naive simulation of the actual cells
+    const R: i64 = 16;
+    let mut cells = vec![vec![true; 2 * R as usize]; 2 * R as usize];
+    for &(x, y) in &[(0, 0), (2, 0), (0, 1), (1, 1)] {
+      cells[(y + R) as usize][(x + R) as usize] = false;
+    }
+    for _ in 0..9 {
+      let get = |x: i64, y: i64| {
+        !(-R..R).contains(&x) || !(-R..R).contains(&y)
+          || cells[(y + R) as usize][(x + R) as usize]
+      };
+      cells = (-R..R).map(|y| (-R..R).map(|x| {
+        let n = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)]
+          .iter()
+          .filter(|&&(dx, dy)| get(x + dx, y + dy))
+          .count();
+        if get(x, y) { n != 5 } else { n != 5 && n != 6 }
+      }).collect()).collect();
+    }
+    uni.simulate(9).unwrap();
+    for y in -R..R {
+      for x in -R..R {
+        assert_eq!(uni.get(x, y), cells[(y + R) as usize][(x + R) as usize],
+          "({}, {})", x, y);
+      }
+    }
+  }
+
+  #[test]
+  fn read_generations_rule() {
+    let src = "x = 5, y = 3, rule = 23/3/32\n.A$2.B2A$CD2.pA!\n";
+    let uni = read_multi(src).unwrap();
+    assert_eq!(uni.rule().to_string(), "23/3/32");
+    assert_eq!(uni.get(1, 0), 1);
+    assert_eq!(uni.get(2, 1), 2);
+    assert_eq!(uni.get(4, 1), 1);
+    assert_eq!(uni.get(1, 2), 4);
+    assert_eq!(uni.get(4, 2), 25);
+    assert!(read_multi("x = 1, y = 1, rule = 23/3/8\nI!").is_err());
+    assert!(read_multi("x = 1, y = 1, rule = B3/S23\nA!").is_err());
+
+    let src = "#CXRLE Gen=3\nx = 5, y = 4, rule = 23/3/8\n.A$2.B2A2$CD2.G!\n";
+    let uni = read_multi(src).unwrap();
+    assert_eq!(write_multi(&uni), src);
+
+    let src = "x = 2, y = 1, rule = /2/256\nAyO!\n";
+    let uni = read_multi(src).unwrap();
+    assert_eq!(uni.get(1, 0), 255);
+    assert_eq!(write_multi(&uni), src);
+
+    // letters other than `b` and `o` are alive cells in two-state rules
+    let uni = read("x = 3, y = 1\nA.x!").unwrap();
+    assert!(uni.get(0, 0) && !uni.get(1, 0) && uni.get(2, 0));
+  }
+
+  #[test]
+  fn read_rule_file() {
+    let dir = std::env::temp_dir().join(format!("hashlife-rules-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("Banks.rule"), r"
+@RULE Banks
+@TABLE
+n_states:2
+neighborhood:vonNeumann
+symmetries:rotate4
+0,1,1,0,0,1
+0,1,1,1,0,1
+0,1,1,1,1,1
+1,0,0,0,0,0
+1,1,0,0,0,0
+").unwrap();
+
+    let src = "x = 3, y = 1, rule = Banks\nA.A!\n";
+    let mut uni = read_multi_with_rules(src, &dir).unwrap();
+    assert_eq!(write_multi(&uni), src);
+    uni.simulate(1).unwrap();
+    assert_eq!(uni.get_rect(&Boundary { left: 0, top: 0, right: 3, bottom: 1 }), vec![0, 0, 0]);
+
+    assert!(read_multi(src).is_err());
+    assert!(read_multi_with_rules("x = 1, y = 1, rule = Missing\nA!", &dir).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn read_neighborhood_rule() {
+    for rule in &["B2/S34H", "B1/S1V"] {
+      let src = format!("x = 3, y = 3, rule = {}\nbo$2bo$3o!\n", rule);
+      let uni = read(&src).unwrap();
+      assert_eq!(&uni.rule().to_string(), rule);
+      assert_eq!(write(&uni), src);
+    }
+    assert_eq!("b2/s34h".parse::<Rule>().unwrap().to_string(), "B2/S34H");
+    assert!("B2/S37H".parse::<Rule>().is_err());
+    assert!("B1/S5V".parse::<Rule>().is_err());
+    assert!("B2a/S1V".parse::<Rule>().is_err());
+
+    // a single cell is replaced by its orthogonal neighbors
+    let mut uni = read("x = 1, y = 1, rule = B1/S1V\no!\n").unwrap();
+    uni.simulate(1).unwrap();
+    assert_eq!(uni.boundary(), Boundary { left: -1, top: -1, right: 2, bottom: 2 });
+    assert_eq!(uni.population(), 4u32.into());
+  }
+
+  #[test]
+  fn read_larger_than_life_rule() {
+    let src = "x = 3, y = 2, rule = R5,C0,M1,S34..58,B34..45,NM\n3A$A.A!\n";
+    let uni = read_multi(src).unwrap();
+    assert_eq!(write_multi(&uni), src);
+    assert!(read_multi("x = 1, y = 1, rule = R5,C0,M1\nA!").is_err());
+  }
+
+  #[test]
+  fn read_margolus_rule() {
+    let bbm = "M0,8,4,3,2,5,9,7,1,6,10,11,12,13,14,15";
+    let src = format!("x = 3, y = 3, rule = {}\nbo$2bo$3o!\n", bbm);
+    let uni = read(&src).unwrap();
+    assert_eq!(uni.rule().to_string(), bbm);
+    assert_eq!(write(&uni), src);
+
+    let critters = "MS,D15;14;13;3;11;5;6;1;7;9;10;2;12;4;8;0";
+    let rule = critters.parse::<Rule>().unwrap();
+    assert!(rule.is_strobing());
+    assert_eq!(rule.to_string(), "M15,14,13,3,11,5,6,1,7,9,10,2,12,4,8,0");
+    assert!("M0,8,4,3,2,5,9,7,1,6,10,11,12,13,14".parse::<Rule>().is_err());
+    assert!("M1,8,4,3,2,5,9,7,1,6,10,11,12,13,14,15".parse::<Rule>().is_err());
+  }
+
+  #[test]
+  fn read_color_rule() {
+    let src = "x = 4, y = 2, rule = QuadLife\nABCD$DCBA!\n";
+    let uni = read_multi(src).unwrap();
+    assert_eq!(uni.get(2, 0), 3);
+    assert_eq!(write_multi(&uni), src);
+    assert!(read_multi("x = 1, y = 1, rule = Immigration\nC!").is_err());
+  }
+
+  #[test]
+  fn read_second_order_rule() {
+    let src = "x = 3, y = 2, rule = B36/S23_SO\nA.B$2.C!\n";
+    let uni = read_multi(src).unwrap();
+    assert_eq!(uni.get(2, 1), 3);
+    assert_eq!(write_multi(&uni), src);
+    assert!(read_multi("x = 1, y = 1, rule = B0/S23_SO\nA!").is_err());
+  }
+}
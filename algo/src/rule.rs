@@ -1,30 +1,198 @@
+use crate::topology::Topology;
+use std::convert::TryFrom;
 use std::fmt::{self, Display};
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Copy, Default)]
+/// A two-state rule on the Moore neighborhood.
+///
+/// Any rule can be represented, although only isotropic ones can be written
+/// in the B/S notation. The others are written as `MAP` strings. Outer
+/// totalistic rules on the hexagonal and von Neumann neighborhoods, which are
+/// emulated on the Moore neighborhood, are written with the suffixes `H` and
+/// `V`.
+///
+/// A rule can also be a Margolus rule, which replaces every 2x2 block of cells
+/// at once instead.
+///
+/// The rule string may end with the topology of the grid, such as
+/// `B3/S23:T100,80`, which is kept with the rule but does not change how
+/// cells are advanced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub struct Rule {
-  birth: NeighborMask,
-  survival: NeighborMask,
+  /// Bit `i` is the next state of the 3x3 neighborhood `i`, whose cells are
+  /// arranged as follows
+  /// ```ignored
+  /// bit 8 7 6 5 4 3 2 1 0
+  ///     NW N NE W C E SW S SE
+  /// ```
+  table: [u64; 8],
+  /// The neighbors counted by `set_birth` and `set_survival`.
+  neighborhood: Neighborhood,
+  /// The next states of the 2x2 blocks of a Margolus rule, which replace
+  /// `table`. A block is the sum of its alive cells of NW = 1, NE = 2,
+  /// SW = 4 and SE = 8. Blocks are aligned at even coordinates on even
+  /// generations, and at odd coordinates on odd generations.
+  blocks: Option<[u8; 16]>,
+  topology: Topology,
+}
+
+/// The neighbors of a cell that a rule counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Neighborhood {
+  /// All the 8 neighbors.
+  #[default]
+  Moore,
+  /// The 6 neighbors except NE and SW, as in Golly. A hexagonal grid is
+  /// emulated by skewing it so that those cells are not adjacent.
+  Hexagonal,
+  /// The 4 orthogonal neighbors.
+  VonNeumann,
+}
+
+impl Neighborhood {
+  /// Bits of the neighbors in a neighborhood.
+  const fn mask(self) -> usize {
+    match self {
+      Self::Moore => NEIGHBORS,
+      Self::Hexagonal => 0b_110_101_011,
+      Self::VonNeumann => 0b_010_101_010,
+    }
+  }
+
+  pub fn num_neighbors(self) -> u8 {
+    self.mask().count_ones() as u8
+  }
+
+  /// Suffix of the rule string.
+  pub fn suffix(self) -> &'static str {
+    match self {
+      Self::Moore => "",
+      Self::Hexagonal => "H",
+      Self::VonNeumann => "V",
+    }
+  }
 }
 
 pub(crate) type NeighborMask = u16;
 
-pub const GAME_OF_LIFE: Rule = Rule {
-  birth: 0b000001000,
-  survival: 0b000001100,
-};
+/// Bit of the center cell in a neighborhood.
+const CENTER: usize = 1 << 4;
+/// Bits of the neighbors in a neighborhood.
+const NEIGHBORS: usize = 0x1ef;
 
-pub(crate) fn compute_level2_results(rule: Rule) -> [u8; 65536] {
-  let nexts = [rule.birth, rule.survival];
+pub const GAME_OF_LIFE: Rule = Rule::from_counts(0b000001000, 0b000001100);
 
+/// Letters of Hensel notation with their canonical neighborhoods, for each
+/// number of alive neighbors up to 4. The classes of `n > 4` neighbors are the
+/// complements of those of `8 - n` neighbors.
+const HENSEL_CLASSES: [&[(char, usize)]; 5] = [
+  &[],
+  &[('c', 0b_100_000_000), ('e', 0b_010_000_000)],
+  &[
+    ('c', 0b_101_000_000),
+    ('e', 0b_010_100_000),
+    ('a', 0b_110_000_000),
+    ('i', 0b_000_101_000),
+    ('k', 0b_100_001_000),
+    ('n', 0b_001_000_100),
+  ],
+  &[
+    ('c', 0b_101_000_100),
+    ('e', 0b_010_101_000),
+    ('a', 0b_110_100_000),
+    ('i', 0b_111_000_000),
+    ('k', 0b_010_001_100),
+    ('n', 0b_101_100_000),
+    ('j', 0b_011_100_000),
+    ('q', 0b_011_000_100),
+    ('r', 0b_100_101_000),
+    ('y', 0b_100_001_100),
+  ],
+  &[
+    ('c', 0b_101_000_101),
+    ('e', 0b_010_101_010),
+    ('a', 0b_111_100_000),
+    ('i', 0b_101_101_000),
+    ('k', 0b_110_001_100),
+    ('n', 0b_111_000_100),
+    ('j', 0b_010_101_100),
+    ('q', 0b_011_001_100),
+    ('r', 0b_110_101_000),
+    ('y', 0b_101_001_100),
+    ('t', 0b_100_101_100),
+    ('w', 0b_011_100_100),
+    ('z', 0b_001_101_100),
+  ],
+];
+
+const BASE64_DIGITS: &[u8; 64] =
+  b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Named rules, by their names in lowercase without spaces and punctuation.
+const ALIASES: &[(&str, &str)] = &[
+  ("life", "B3/S23"),
+  ("conwayslife", "B3/S23"),
+  ("highlife", "B36/S23"),
+  ("daynight", "B3678/S34678"),
+  ("seeds", "B2/S"),
+  ("lifewithoutdeath", "B3/S012345678"),
+  ("2x2", "B36/S125"),
+  ("maze", "B3/S12345"),
+  ("mazectric", "B3/S1234"),
+  ("replicator", "B1357/S1357"),
+  ("diamoeba", "B35678/S5678"),
+  ("morley", "B368/S245"),
+  ("anneal", "B4678/S35678"),
+  ("longlife", "B345/S5"),
+  ("34life", "B34/S34"),
+  ("amoeba", "B357/S1358"),
+  ("coral", "B3/S45678"),
+  ("drylife", "B37/S23"),
+];
+
+/// An error when parsing a rule string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseRuleError(String);
+
+impl Display for ParseRuleError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl std::error::Error for ParseRuleError {}
+
+/// `odd_blocks` is whether the blocks of a Margolus rule are aligned at odd
+/// coordinates of the 4x4 square.
+pub(crate) fn compute_level2_results(rule: Rule, odd_blocks: bool) -> [u8; 65536] {
   let mut result = [0u8; 65536];
-  for i in 0..65536usize {
-    let j = i as u16;
-    let nw = (nexts[i >> 10 & 1] >> (j & 0b_1110_1010_1110_0000).count_ones()) & 1;
-    let ne = (nexts[i >> 9 & 1] >> (j & 0b_0111_0101_0111_0000).count_ones()) & 1;
-    let sw = (nexts[i >> 6 & 1] >> (j & 0b_0000_1110_1010_1110).count_ones()) & 1;
-    let se = (nexts[i >> 5 & 1] >> (j & 0b_0000_0111_0101_0111).count_ones()) & 1;
-    let res = nw << 5 | ne << 4 | sw << 1 | se;
-    result[i] = res as u8;
+  if let Some(blocks) = rule.blocks {
+    for (i, result) in result.iter_mut().enumerate() {
+      // cell `(x, y)` is bit `15 - (4 * y + x)` of the 4x4 square
+      let cell = |x: usize, y: usize| i >> (15 - (4 * y + x)) & 1;
+      for (x, y, bit) in [(1, 1, 5), (2, 1, 4), (1, 2, 1), (2, 2, 0)] {
+        let x0 = x - (x + odd_blocks as usize) % 2;
+        let y0 = y - (y + odd_blocks as usize) % 2;
+        let block = cell(x0, y0) | cell(x0 + 1, y0) << 1
+          | cell(x0, y0 + 1) << 2 | cell(x0 + 1, y0 + 1) << 3;
+        let next = blocks[block] >> (2 * (y - y0) + x - x0) & 1;
+        *result |= next << bit;
+      }
+    }
+    return result;
+  }
+
+  for (i, result) in result.iter_mut().enumerate() {
+    // 3x3 neighborhoods of the 2x2 center of the 4x4 square
+    let nw = (i >> 13 & 7) << 6 | (i >> 9 & 7) << 3 | i >> 5 & 7;
+    let ne = (i >> 12 & 7) << 6 | (i >> 8 & 7) << 3 | i >> 4 & 7;
+    let sw = (i >> 9 & 7) << 6 | (i >> 5 & 7) << 3 | i >> 1 & 7;
+    let se = (i >> 8 & 7) << 6 | (i >> 4 & 7) << 3 | i & 7;
+    let res = (rule.next_state(nw) as u8) << 5
+      | (rule.next_state(ne) as u8) << 4
+      | (rule.next_state(sw) as u8) << 1
+      | rule.next_state(se) as u8;
+    *result = res;
   }
   result
 }
@@ -34,34 +202,666 @@ impl Rule {
     Self::default()
   }
 
-  pub fn set_birth(&mut self, num: u8) {
-    assert!(num < 9);
-    if num == 0 {
-      panic!("B0 is not allowed for HashLife");
+  /// Returns the rule in which nothing is born and nothing survives, whose
+  /// births and survivals count the cells of `neighborhood`.
+  pub fn with_neighborhood(neighborhood: Neighborhood) -> Self {
+    Self { neighborhood, ..Self::default() }
+  }
+
+  pub fn neighborhood(&self) -> Neighborhood {
+    self.neighborhood
+  }
+
+  pub fn topology(&self) -> Topology {
+    self.topology
+  }
+
+  pub fn set_topology(&mut self, topology: Topology) {
+    self.topology = topology;
+  }
+
+  /// Returns the Margolus rule that replaces every 2x2 block with
+  /// `blocks[block]`, where a block is the sum of its alive cells of
+  /// NW = 1, NE = 2, SW = 4 and SE = 8, as in MCell.
+  ///
+  /// Returns `None` unless every state is less than 16, and the empty block
+  /// becomes either empty or full.
+  pub fn margolus(blocks: [u8; 16]) -> Option<Self> {
+    if blocks.iter().any(|&b| b >= 16) || blocks[0] != 0 && blocks[0] != 15 {
+      return None;
+    }
+    Some(Self { blocks: Some(blocks), ..Self::default() })
+  }
+
+  /// Returns the next states of the blocks of a Margolus rule.
+  pub fn blocks(&self) -> Option<&[u8; 16]> {
+    self.blocks.as_ref()
+  }
+
+  /// Returns the tables of the next 2x2 centers of 4x4 squares of the stored
+  /// cells, of even generations, of odd generations at odd coordinates, and of
+  /// odd generations at even coordinates. The tables of odd generations
+  /// differ only for Margolus rules.
+  pub(crate) fn level2_results(&self) -> [[u8; 65536]; 3] {
+    let [even, odd] = self.emulation();
+    [
+      compute_level2_results(even, false),
+      compute_level2_results(odd, false),
+      compute_level2_results(odd, true),
+    ]
+  }
+
+  /// Returns the outer totalistic rule in which a cell with `n` alive
+  /// neighbors is born if bit `n` of `birth` is set, and survives if bit `n`
+  /// of `survival` is set.
+  const fn from_counts(birth: NeighborMask, survival: NeighborMask) -> Self {
+    let mut table = [0u64; 8];
+    let mut i = 0;
+    while i < 512 {
+      let num = (i & NEIGHBORS).count_ones();
+      let mask = if i & CENTER == 0 { birth } else { survival };
+      if mask >> num & 1 != 0 {
+        table[i >> 6] |= 1 << (i & 63);
+      }
+      i += 1;
+    }
+    Self {
+      table,
+      neighborhood: Neighborhood::Moore,
+      blocks: None,
+      topology: Topology::Unbounded,
     }
-    self.birth |= 1 << num;
+  }
+
+  /// Returns the rule in which the next state of a cell is
+  /// `f(neighborhood)`, where `neighborhood[y][x]` is the cell at `(x - 1,
+  /// y - 1)` relative to the cell.
+  ///
+  /// A rule with B0 is emulated as described in
+  /// [`is_strobing`](Self::is_strobing).
+  pub fn from_fn(f: impl Fn([[bool; 3]; 3]) -> bool) -> Self {
+    let mut rule = Self::new();
+    for i in 0..512 {
+      let mut neighborhood = [[false; 3]; 3];
+      for (y, row) in neighborhood.iter_mut().enumerate() {
+        for (x, cell) in row.iter_mut().enumerate() {
+          *cell = i >> (8 - (3 * y + x)) & 1 != 0;
+        }
+      }
+      if f(neighborhood) {
+        rule.set_next_state(i);
+      }
+    }
+    rule
+  }
+
+  /// Parses a `MAP` string, which is the base64 encoding of the next states
+  /// of all neighborhoods in the order of the bits of `Rule::table`.
+  ///
+  /// Format: <https://golly.sourceforge.net/Help/Algorithms/QuickLife.html>.
+  pub fn from_map(s: &str) -> Option<Self> {
+    let s = s.strip_prefix("MAP")?;
+    let s = s.strip_suffix("==").unwrap_or(s);
+    if s.len() != 86 {
+      return None;
+    }
+
+    let mut rule = Self::new();
+    for (i, c) in s.bytes().enumerate() {
+      let digit = BASE64_DIGITS.iter().position(|&d| d == c)?;
+      for j in 0..6 {
+        // the last digit has 2 bits of padding
+        let neighborhood = 6 * i + j;
+        if neighborhood < 512 && digit >> (5 - j) & 1 != 0 {
+          rule.set_next_state(neighborhood);
+        }
+      }
+    }
+    Some(rule)
+  }
+
+  /// Returns the `MAP` string of the rule, which is parsed by
+  /// [`from_map`](Self::from_map).
+  pub fn to_map(&self) -> String {
+    let mut s = "MAP".to_owned();
+    for i in 0..86 {
+      let mut digit = 0;
+      for j in 0..6 {
+        let neighborhood = 6 * i + j;
+        if neighborhood < 512 && self.next_state(neighborhood) {
+          digit |= 1 << (5 - j);
+        }
+      }
+      s.push(BASE64_DIGITS[digit] as char);
+    }
+    s
+  }
+
+  /// Whether the rule is invariant under rotations and reflections.
+  pub fn is_isotropic(&self) -> bool {
+    (0..512).all(|i| self.next_state(i) == self.next_state(canonical(i)))
+  }
+
+  /// Whether the next state depends only on the state of the cell and the
+  /// number of alive neighbors in the neighborhood of the rule.
+  fn is_totalistic(&self) -> bool {
+    let mask = self.neighborhood.mask();
+    let mut next_states = [None; 18];
+    (0..512).all(|i| {
+      let key = (i & mask).count_ones() as usize + if i & CENTER == 0 { 0 } else { 9 };
+      *next_states[key].get_or_insert(self.next_state(i)) == self.next_state(i)
+    })
+  }
+
+  /// Returns the next state of the center of the 3x3 `neighborhood`.
+  pub(crate) fn next_state(&self, neighborhood: usize) -> bool {
+    self.table[neighborhood >> 6] >> (neighborhood & 63) & 1 != 0
+  }
+
+  fn set_next_state(&mut self, neighborhood: usize) {
+    self.table[neighborhood >> 6] |= 1 << (neighborhood & 63);
+  }
+
+  pub fn set_birth(&mut self, num: u8) {
+    assert!(num <= self.neighborhood.num_neighbors());
+    self.set_class(0, num, None);
   }
 
   pub fn set_survival(&mut self, num: u8) {
-    assert!(num < 9);
-    self.survival |= 1 << num;
+    assert!(num <= self.neighborhood.num_neighbors());
+    self.set_class(CENTER, num, None);
+  }
+
+  /// Sets the births of the class `letter` of Hensel notation with `num`
+  /// alive neighbors, e.g. `set_birth_class(3, 'k')` for `B3k`.
+  pub fn set_birth_class(&mut self, num: u8, letter: char) {
+    assert!(hensel_letters(num).any(|c| c == letter), "invalid class {}{}", num, letter);
+    self.set_class(0, num, Some(letter));
+  }
+
+  /// Sets the survivals of the class `letter` of Hensel notation with `num`
+  /// alive neighbors, e.g. `set_survival_class(2, 'a')` for `S2a`.
+  pub fn set_survival_class(&mut self, num: u8, letter: char) {
+    assert!(hensel_letters(num).any(|c| c == letter), "invalid class {}{}", num, letter);
+    self.set_class(CENTER, num, Some(letter));
+  }
+
+  fn set_class(&mut self, center: usize, num: u8, letter: Option<char>) {
+    assert!(letter.is_none() || self.neighborhood == Neighborhood::Moore,
+      "Hensel notation on the {:?} neighborhood", self.neighborhood);
+    let mask = self.neighborhood.mask();
+    for neighbors in 0..512 {
+      if neighbors & CENTER == 0
+        && (neighbors & mask).count_ones() == num as u32
+        && (letter.is_none() || hensel_class(neighbors) == letter)
+      {
+        self.set_next_state(center | neighbors);
+      }
+    }
+  }
+
+  /// Whether the background of the rule flips on every generation, i.e. it
+  /// has B0 but not S8.
+  ///
+  /// Such rules are emulated like in Golly: on odd generations, all cells are
+  /// stored inverted, so that the background stays dead. B0 rules with S8
+  /// are stored inverted on every generation.
+  pub fn is_strobing(&self) -> bool {
+    match self.blocks {
+      Some(blocks) => blocks[0] == 15 && blocks[15] != 15,
+      None => self.next_state(0) && !self.next_state(CENTER | NEIGHBORS),
+    }
+  }
+
+  /// Whether a dead cell surrounded by dead cells is born.
+  fn has_b0(&self) -> bool {
+    match self.blocks {
+      Some(blocks) => blocks[0] != 0,
+      None => self.next_state(0),
+    }
+  }
+
+  /// Whether the cells are stored inverted on even or odd generations.
+  pub(crate) fn is_stored_inverted(&self, odd: bool) -> bool {
+    self.has_b0() && (odd || !self.is_strobing())
+  }
+
+  /// Whether even and odd generations are advanced differently.
+  pub(crate) fn is_alternating(&self) -> bool {
+    self.blocks.is_some() || self.is_strobing()
+  }
+
+  /// Returns the rules without B0 that advance even and odd generations of
+  /// the stored cells.
+  pub(crate) fn emulation(&self) -> [Rule; 2] {
+    // the cell and the neighbors are inverted
+    let inverted = |rule: Rule| {
+      let mut inverted = Rule {
+        table: [0; 8],
+        blocks: rule.blocks.map(|blocks| std::array::from_fn(|i| blocks[15 - i])),
+        ..rule
+      };
+      for i in 0..512 {
+        if rule.next_state(511 - i) {
+          inverted.set_next_state(i);
+        }
+      }
+      inverted
+    };
+    // the next state is inverted
+    let complement = |rule: Rule| Rule {
+      table: rule.table.map(|bits| !bits),
+      blocks: rule.blocks.map(|blocks| blocks.map(|b| b ^ 15)),
+      ..rule
+    };
+
+    if !self.has_b0() {
+      [*self, *self]
+    } else if self.is_strobing() {
+      [complement(*self), inverted(*self)]
+    } else {
+      let rule = complement(inverted(*self));
+      [rule, rule]
+    }
   }
+
+  fn fmt_without_topology(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if let Some(blocks) = self.blocks {
+      let blocks = blocks.iter().map(|b| b.to_string()).collect::<Vec<_>>();
+      return write!(f, "M{}", blocks.join(","));
+    }
+
+    if self.neighborhood != Neighborhood::Moore {
+      if !self.is_totalistic() {
+        return write!(f, "{}", self.to_map());
+      }
+      let mask = self.neighborhood.mask();
+      let num = self.neighborhood.num_neighbors();
+      let counts = |center: usize| (0..=num)
+        // the smallest neighborhood of `n` neighbors
+        .filter(|&n| self.next_state(center | (0..9).rev()
+          .filter(|&i| mask >> i & 1 != 0)
+          .take(n as usize)
+          .fold(0, |bits, i| bits | 1 << i)))
+        .map(|n| n.to_string())
+        .collect::<String>();
+      return write!(f, "B{}/S{}{}", counts(0), counts(CENTER), self.neighborhood.suffix());
+    }
+
+    if !self.is_isotropic() {
+      return write!(f, "{}", self.to_map());
+    }
+
+    write!(f, "B")?;
+    self.fmt_conditions(f, 0)?;
+    write!(f, "/S")?;
+    self.fmt_conditions(f, CENTER)
+  }
+
+  /// Writes the conditions of the births if `center` is 0, or the survivals
+  /// if it is `CENTER`.
+  fn fmt_conditions(&self, f: &mut fmt::Formatter, center: usize) -> fmt::Result {
+    for num in 0..=8u8 {
+      let neighborhood = |neighbors: usize| {
+        let neighbors = if num > 4 { !neighbors & NEIGHBORS } else { neighbors };
+        center | neighbors
+      };
+      let classes = HENSEL_CLASSES[num.min(8 - num) as usize];
+      if classes.is_empty() {
+        if self.next_state(neighborhood(0)) {
+          write!(f, "{}", num)?;
+        }
+        continue;
+      }
+
+      let (on, off): (Vec<_>, Vec<_>) = classes.iter()
+        .partition(|&&(_, neighbors)| self.next_state(neighborhood(neighbors)));
+      if off.is_empty() {
+        write!(f, "{}", num)?;
+      } else if on.len() <= off.len() {
+        if !on.is_empty() {
+          write!(f, "{}", num)?;
+          on.iter().try_for_each(|&&(c, _)| write!(f, "{}", c))?;
+        }
+      } else {
+        write!(f, "{}-", num)?;
+        off.iter().try_for_each(|&&(c, _)| write!(f, "{}", c))?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Returns the letters of Hensel notation for `num` alive neighbors.
+pub(crate) fn hensel_letters(num: u8) -> impl Iterator<Item = char> {
+  let classes = HENSEL_CLASSES.get(num.min(8u8.saturating_sub(num)) as usize).copied();
+  classes.unwrap_or(&[]).iter().map(|&(c, _)| c)
+}
+
+/// Returns the letter of Hensel notation of the neighbors of a neighborhood
+/// without the center.
+fn hensel_class(neighbors: usize) -> Option<char> {
+  let num = neighbors.count_ones() as usize;
+  let neighbors = if num > 4 { !neighbors & NEIGHBORS } else { neighbors };
+  let canonical = canonical(neighbors);
+  HENSEL_CLASSES[num.min(8 - num)].iter()
+    .find(|&&(_, neighbors)| self::canonical(neighbors) == canonical)
+    .map(|&(c, _)| c)
+}
+
+/// Returns the smallest of the rotations and reflections of a neighborhood.
+fn canonical(neighborhood: usize) -> usize {
+  // cell `(x, y)` is bit `8 - (3 * y + x)`
+  let permute = |neighborhood: usize, f: fn(usize, usize) -> (usize, usize)| {
+    let mut result = 0;
+    for y in 0..3 {
+      for x in 0..3 {
+        let (x1, y1) = f(x, y);
+        result |= (neighborhood >> (8 - (3 * y + x)) & 1) << (8 - (3 * y1 + x1));
+      }
+    }
+    result
+  };
+
+  // alternating two reflections yields all the symmetries of a square
+  let mut n = neighborhood;
+  let mut min = n;
+  for i in 0..8 {
+    n = if i % 2 == 0 {
+      permute(n, |x, y| (2 - x, y))
+    } else {
+      permute(n, |x, y| (y, x))
+    };
+    min = min.min(n);
+  }
+  min
 }
 
 impl Display for Rule {
+  /// Formats in the B/S notation, with letters of Hensel notation for
+  /// isotropic non-totalistic rules, e.g. `B3/S23` and `B2-a/S12`.
+  /// Anisotropic rules are formatted as `MAP` strings. A bounded topology is
+  /// appended as in `B3/S23:T100,80`.
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "B")?;
-    let mut b = self.birth;
-    while b != 0 {
-      write!(f, "{}", b.trailing_zeros())?;
-      b &= b - 1;
-    }
-    write!(f, "/S")?;
-    let mut s = self.survival;
-    while s != 0 {
-      write!(f, "{}", s.trailing_zeros())?;
-      s &= s - 1;
+    self.fmt_without_topology(f)?;
+    if self.topology.is_bounded() {
+      write!(f, ":{}", self.topology)?;
     }
     Ok(())
   }
-}
\ No newline at end of file
+}
+
+impl FromStr for Rule {
+  type Err = ParseRuleError;
+
+  /// Parses the B/S notation with letters of Hensel notation and optional
+  /// `H` or `V` suffixes, such as `B3/S23`, `s23/b3` and `B2/S34H`, the legacy
+  /// S/B notation such as `23/3`, `MAP` strings, Margolus rules such as
+  /// `MS,D0;8;4;3;2;5;9;7;1;6;10;11;12;13;14;15` and
+  /// `M0,8,4,3,2,5,9,7,1,6,10,11,12,13,14,15`, and named rules such as
+  /// `HighLife` and `Day & Night`.
+  ///
+  /// The rule may be followed by a topology, such as `B3/S23:T100,80`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Some((rule, topology)) = s.split_once(':') {
+      let mut rule = rule.parse::<Rule>()?;
+      rule.topology = topology.trim().parse().map_err(ParseRuleError)?;
+      return Ok(rule);
+    }
+
+    let s = s.trim();
+    let err = || ParseRuleError(format!("invalid rule {:?}", s));
+
+    let name = s.chars()
+      .filter(char::is_ascii_alphanumeric)
+      .map(|c| c.to_ascii_lowercase())
+      .collect::<String>();
+    if let Some((_, rule)) = ALIASES.iter().find(|&&(alias, _)| alias == name) {
+      return rule.parse();
+    }
+
+    if s.starts_with("MAP") {
+      return Self::from_map(s).ok_or_else(err);
+    }
+    let upper = s.to_ascii_uppercase();
+    if let Some(blocks) = upper.strip_prefix("MS,D").or_else(|| upper.strip_prefix('M')) {
+      return parse_margolus(blocks).ok_or_else(err);
+    }
+    parse_outer(s).ok_or_else(err)
+  }
+}
+
+/// Parses the 16 next states of the blocks of a Margolus rule, separated by
+/// commas or semicolons.
+fn parse_margolus(s: &str) -> Option<Rule> {
+  let blocks = s.split([',', ';'])
+    .map(|b| b.trim().parse().ok())
+    .collect::<Option<Vec<u8>>>()?;
+  Rule::margolus(<[u8; 16]>::try_from(blocks).ok()?)
+}
+
+/// Parses the births and survivals of a B/S or S/B rule.
+fn parse_outer(s: &str) -> Option<Rule> {
+  let (s, neighborhood) = match s.as_bytes().last()?.to_ascii_uppercase() {
+    b'H' => (&s[..s.len() - 1], Neighborhood::Hexagonal),
+    b'V' => (&s[..s.len() - 1], Neighborhood::VonNeumann),
+    _ => (s, Neighborhood::Moore),
+  };
+  let (first, second) = s.split_once('/')?;
+  let (birth, survival) = match (
+    first.strip_prefix(['B', 'b']),
+    second.strip_prefix(['S', 's']),
+    first.strip_prefix(['S', 's']),
+    second.strip_prefix(['B', 'b']),
+  ) {
+    (Some(birth), Some(survival), _, _) => (birth, survival),
+    (_, _, Some(survival), Some(birth)) => (birth, survival),
+    // the legacy S/B notation
+    _ => (second, first),
+  };
+
+  let mut rule = Rule::with_neighborhood(neighborhood);
+  parse_conditions(birth, neighborhood, |num, letter| match letter {
+    Some(letter) => rule.set_birth_class(num, letter),
+    None => rule.set_birth(num),
+  })?;
+  parse_conditions(survival, neighborhood, |num, letter| match letter {
+    Some(letter) => rule.set_survival_class(num, letter),
+    None => rule.set_survival(num),
+  })?;
+  Some(rule)
+}
+
+/// Parses neighbor counts with optional letters of Hensel notation, such as
+/// `2-a3` or `3ckq`, and calls `set` with every count and letter. Letters
+/// are allowed only on the Moore neighborhood.
+fn parse_conditions(
+  s: &str,
+  neighborhood: Neighborhood,
+  mut set: impl FnMut(u8, Option<char>),
+) -> Option<()> {
+  let mut chars = s.chars().peekable();
+  while let Some(c) = chars.next() {
+    let num = c.to_digit(10)?;
+    if num > neighborhood.num_neighbors() as u32 {
+      return None;
+    }
+    let num = num as u8;
+
+    let negated = chars.next_if_eq(&'-').is_some();
+    let mut letters = vec![];
+    while let Some(c) = chars.next_if(char::is_ascii_lowercase) {
+      if neighborhood != Neighborhood::Moore || !hensel_letters(num).any(|l| l == c) {
+        return None;
+      }
+      letters.push(c);
+    }
+
+    if negated {
+      if letters.is_empty() {
+        return None;
+      }
+      hensel_letters(num)
+        .filter(|l| !letters.contains(l))
+        .for_each(|l| set(num, Some(l)));
+    } else if letters.is_empty() {
+      set(num, None);
+    } else {
+      letters.into_iter().for_each(|l| set(num, Some(l)));
+    }
+  }
+  Some(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_hensel_classes() {
+    // the classes of each number of neighbors partition the neighborhoods
+    let sizes = [1, 8, 28, 56, 70, 56, 28, 8, 1];
+    for num in 0..=8u8 {
+      let mut counts = [0; 13];
+      for neighbors in (0..512).filter(|i| i & CENTER == 0) {
+        if neighbors.count_ones() == num as u32 {
+          match hensel_class(neighbors) {
+            Some(c) => {
+              let i = hensel_letters(num).position(|l| l == c).unwrap();
+              counts[i] += 1;
+            }
+            None => assert!(num == 0 || num == 8),
+          }
+        }
+      }
+      let total: usize = counts.iter().sum();
+      let num_letters = hensel_letters(num).count();
+      assert_eq!(total.max(1), sizes[num as usize]);
+      assert!(counts[..num_letters].iter().all(|&n| n > 0));
+    }
+  }
+
+  #[test]
+  fn test_display() {
+    assert_eq!(GAME_OF_LIFE.to_string(), "B3/S23");
+
+    let mut rule = Rule::new();
+    for c in "ceikn".chars() {
+      rule.set_birth_class(2, c);
+    }
+    rule.set_survival(1);
+    rule.set_survival(2);
+    assert_eq!(rule.to_string(), "B2-a/S12");
+
+    let mut rule = Rule::new();
+    rule.set_birth_class(3, 'k');
+    rule.set_birth_class(6, 'n');
+    rule.set_birth_class(6, 'i');
+    rule.set_survival(8);
+    assert_eq!(rule.to_string(), "B3k6in/S8");
+  }
+
+  #[test]
+  fn test_map() {
+    let life = "MAPARYXfhZofugWaH7oaIDogBZofuhogOiAaIDogIAAgAAWaH7oaIDogGiA6ICAAIAAaIDogIAAgACAAIAAAAAAAA";
+    assert_eq!(GAME_OF_LIFE.to_map(), life);
+    assert_eq!(Rule::from_map(life).unwrap().to_string(), "B3/S23");
+    assert_eq!(Rule::from_map(&format!("{}==", life)).unwrap().to_map(), life);
+    assert!(Rule::from_map(&life[..80]).is_none());
+    assert!(Rule::from_map(&life.replace('A', "!")).is_none());
+  }
+
+  #[test]
+  fn test_from_fn() {
+    let life = Rule::from_fn(|n| {
+      let num = n.iter().flatten().filter(|&&alive| alive).count();
+      num == 3 || num == 4 && n[1][1]
+    });
+    assert_eq!(life.to_string(), "B3/S23");
+
+    // every cell copies its western neighbor
+    let shift = Rule::from_fn(|n| n[1][0]);
+    assert!(!shift.is_isotropic());
+    let map = shift.to_string();
+    assert!(map.starts_with("MAP"));
+    assert_eq!(Rule::from_map(&map).unwrap().to_map(), map);
+  }
+
+  #[test]
+  fn test_neighborhoods() {
+    let mut hex = Rule::with_neighborhood(Neighborhood::Hexagonal);
+    hex.set_birth(2);
+    hex.set_survival(3);
+    hex.set_survival(4);
+    assert_eq!(hex.to_string(), "B2/S34H");
+    // NE and SW are ignored
+    assert!(hex.next_state(0b_100_000_001));
+    assert!(!hex.next_state(0b_101_000_100));
+    assert!(hex.next_state(0b_111_110_100));
+
+    let mut vn = Rule::with_neighborhood(Neighborhood::VonNeumann);
+    vn.set_birth(1);
+    vn.set_survival(1);
+    assert_eq!(vn.to_string(), "B1/S1V");
+    assert!(vn.next_state(0b_010_000_000));
+    assert!(!vn.next_state(0b_100_000_000));
+    assert!(vn.next_state(0b_101_110_101));
+
+    // a rule that counts the corners is no longer totalistic
+    let mut rule = vn;
+    rule.set_next_state(0b_100_000_000);
+    assert_eq!(rule.to_string(), rule.to_map());
+  }
+
+  #[test]
+  fn test_parse() {
+    let highlife = "B36/S23".parse::<Rule>().unwrap();
+    assert_eq!("b36/s23".parse::<Rule>().unwrap(), highlife);
+    assert_eq!("S23/B36".parse::<Rule>().unwrap(), highlife);
+    assert_eq!("23/36".parse::<Rule>().unwrap(), highlife);
+    assert_eq!("HighLife".parse::<Rule>().unwrap(), highlife);
+    assert_eq!("Life".parse::<Rule>().unwrap(), GAME_OF_LIFE);
+    assert_eq!("Day & Night".parse::<Rule>().unwrap().to_string(), "B3678/S34678");
+    assert_eq!("seeds".parse::<Rule>().unwrap().to_string(), "B2/S");
+    assert_eq!("/".parse::<Rule>().unwrap(), Rule::new());
+
+    for s in &["", "B", "H", "B3", "B3/S23/", "B3/23", "X3/S23", "B9/S23", "B3/S23:X10",
+      "B3/S23:\u{e9}5"] {
+      assert!(s.parse::<Rule>().is_err(), "{:?}", s);
+    }
+
+    let torus = "B3/S23:T100,80".parse::<Rule>().unwrap();
+    assert_eq!(torus.topology(), Topology::Torus { width: 100, height: 80 });
+    assert_eq!(torus.to_string(), "B3/S23:T100,80");
+    assert_ne!(torus, GAME_OF_LIFE);
+    let plane = "highlife:p10".parse::<Rule>().unwrap();
+    assert_eq!(plane.topology(), Topology::Plane { width: 10, height: 10 });
+    assert_eq!(plane.to_string(), "B36/S23:P10,10");
+    assert_eq!("B3/S23:".parse::<Rule>().unwrap(), GAME_OF_LIFE);
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let mut hex = Rule::with_neighborhood(Neighborhood::Hexagonal);
+    hex.set_birth(2);
+    hex.set_survival(3);
+    let mut b0 = Rule::new();
+    b0.set_birth(0);
+    b0.set_survival(8);
+    let rules = [
+      GAME_OF_LIFE,
+      Rule::new(),
+      b0,
+      hex,
+      "B2-a/S12".parse().unwrap(),
+      "B1/S1V".parse().unwrap(),
+      "MS,D0;8;4;3;2;5;9;7;1;6;10;11;12;13;14;15".parse().unwrap(),
+      Rule::from_fn(|n| n[0][0] && n[1][2]),
+      "B3/S23:K100*,80".parse().unwrap(),
+      "B1/S1V:C10,20".parse().unwrap(),
+    ];
+    for rule in &rules {
+      assert_eq!(&rule.to_string().parse::<Rule>().unwrap(), rule, "{}", rule);
+    }
+  }
+}
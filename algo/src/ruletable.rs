@@ -0,0 +1,698 @@
+use crate::multistate::MultiRule;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+
+/// A rule loaded from the `@TABLE` or `@TREE` section of a Golly `.rule`
+/// file.
+///
+/// Format: <https://golly.sourceforge.io/Help/formats.html#rule>.
+#[derive(Clone, Debug)]
+pub struct RuleTable {
+  name: String,
+  num_states: usize,
+  /// Offsets of the neighbors in the order of the lookup.
+  offsets: Vec<(usize, usize)>,
+  lookup: Lookup,
+}
+
+#[derive(Clone, Debug)]
+enum Lookup {
+  /// Bit `i` of `masks[(pos * num_states + state) * words + i / 64]` is set
+  /// if the `i`-th expanded transition matches `state` at `pos`, where the
+  /// position 0 is the cell itself.
+  Table {
+    words: usize,
+    masks: Vec<u64>,
+    outputs: Vec<u8>,
+  },
+  /// `nodes[i * num_states + state]` is the child of the `i`-th node for
+  /// `state`. The cell itself comes after the neighbors.
+  Tree {
+    nodes: Vec<u32>,
+    root: u32,
+  },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Neighborhood {
+  Moore,
+  VonNeumann,
+  Hexagonal,
+  OneDimensional,
+}
+
+impl Neighborhood {
+  /// Offsets of the neighbors from the cell at `(1, 1)` in the order of
+  /// Golly's rule tables, clockwise from north.
+  fn table_offsets(self) -> &'static [(usize, usize)] {
+    match self {
+      Self::Moore => &[(1, 0), (2, 0), (2, 1), (2, 2), (1, 2), (0, 2), (0, 1), (0, 0)],
+      Self::VonNeumann => &[(1, 0), (2, 1), (1, 2), (0, 1)],
+      Self::Hexagonal => &[(1, 0), (2, 1), (2, 2), (1, 2), (0, 1), (0, 0)],
+      Self::OneDimensional => &[(0, 1), (2, 1)],
+    }
+  }
+}
+
+/// A set of states.
+type StateSet = [u64; 4];
+
+fn state_set(states: impl IntoIterator<Item = u8>) -> StateSet {
+  let mut set = [0; 4];
+  for s in states {
+    set[s as usize >> 6] |= 1 << (s & 63);
+  }
+  set
+}
+
+fn contains(set: &StateSet, s: usize) -> bool {
+  set[s >> 6] & 1 << (s & 63) != 0
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Token {
+  State(u8),
+  Var(String),
+}
+
+impl RuleTable {
+  /// Loads the rule `name` from `name.rule` in `dir`.
+  pub fn load(dir: impl AsRef<Path>, name: &str) -> Result<Self, String> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+      return Err(format!("invalid rule name {:?}", name));
+    }
+    let path = dir.as_ref().join(format!("{}.rule", name));
+    let src = std::fs::read_to_string(&path)
+      .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    Self::parse(&src)
+  }
+
+  /// Parses the contents of a `.rule` file.
+  pub fn parse(src: &str) -> Result<Self, String> {
+    let mut name = None;
+    let mut sections = FxHashMap::<&str, Vec<&str>>::default();
+    let mut section = None;
+    for line in src.lines() {
+      let line = line.trim();
+      if let Some(header) = line.strip_prefix('@') {
+        let mut words = header.split_whitespace();
+        let s = words.next().unwrap_or("");
+        if s == "RULE" {
+          name = words.next().map(|s| s.to_owned());
+        }
+        section = Some(s);
+        sections.entry(s).or_default();
+      } else if let Some(s) = section {
+        let line = line.split('#').next().unwrap().trim();
+        if !line.is_empty() {
+          sections.get_mut(s).unwrap().push(line);
+        }
+      }
+    }
+
+    let name = name.ok_or_else(|| "missing @RULE".to_owned())?;
+    let rule = match (sections.get("TABLE"), sections.get("TREE")) {
+      (Some(lines), _) => Self::parse_table(name, lines)?,
+      (None, Some(lines)) => Self::parse_tree(name, lines)?,
+      (None, None) => return Err("missing @TABLE or @TREE".to_owned()),
+    };
+
+    let empty = vec![0; rule.offsets.len()];
+    if rule.next_state(0, &empty) != 0 {
+      return Err("state 0 surrounded by state 0 must stay 0".to_owned());
+    }
+    Ok(rule)
+  }
+
+  fn parse_table(name: String, lines: &[&str]) -> Result<Self, String> {
+    let mut num_states = None::<usize>;
+    let mut neighborhood = None;
+    let mut symmetries = "none";
+    let mut vars = FxHashMap::<&str, Vec<u8>>::default();
+    let mut transitions = vec![];
+    for &line in lines {
+      if let Some(n) = line.strip_prefix("n_states:") {
+        let n = n.trim().parse().map_err(|_| "invalid n_states".to_owned())?;
+        if !(2..=256).contains(&n) {
+          return Err("invalid n_states".to_owned());
+        }
+        num_states = Some(n);
+      } else if let Some(n) = line.strip_prefix("neighborhood:") {
+        neighborhood = Some(match &n.trim().to_ascii_lowercase()[..] {
+          "moore" => Neighborhood::Moore,
+          "vonneumann" => Neighborhood::VonNeumann,
+          "hexagonal" => Neighborhood::Hexagonal,
+          "onedimensional" => Neighborhood::OneDimensional,
+          n => return Err(format!("unsupported neighborhood {:?}", n)),
+        });
+      } else if let Some(s) = line.strip_prefix("symmetries:") {
+        symmetries = s.trim();
+      } else if let Some(var) = line.strip_prefix("var ") {
+        let (var, values) = var.split_once('=')
+          .ok_or_else(|| format!("invalid variable {:?}", line))?;
+        let values = values.trim().trim_start_matches('{').trim_end_matches('}');
+        let mut states = vec![];
+        for value in values.split(',').map(|s| s.trim()) {
+          match parse_token(value)? {
+            Token::State(s) => states.push(s),
+            Token::Var(v) => states.extend(vars.get(&v[..])
+              .ok_or_else(|| format!("unknown variable {:?}", v))?),
+          }
+        }
+        vars.insert(var.trim(), states);
+      } else {
+        let tokens = if line.contains([',', ' ', '\t']) {
+          line.split([',', ' ', '\t']).filter(|s| !s.is_empty()).map(parse_token).collect()
+        } else {
+          line.chars().map(|c| parse_token(&c.to_string())).collect::<Result<Vec<_>, _>>()
+        };
+        transitions.push(tokens?);
+      }
+    }
+
+    let num_states = num_states.ok_or_else(|| "missing n_states".to_owned())?;
+    let neighborhood = neighborhood.unwrap_or(Neighborhood::Moore);
+    let offsets = neighborhood.table_offsets();
+    let n = offsets.len();
+    let perms = symmetry_permutations(symmetries, n)?;
+
+    let mut expanded = vec![];
+    let mut seen = FxHashSet::default();
+    for tokens in &transitions {
+      if tokens.len() != n + 2 {
+        return Err(format!("transition of {} states instead of {}", tokens.len(), n + 2));
+      }
+      for (inputs, output) in bind_variables(tokens, &vars, num_states)? {
+        let mut push = |inputs: Vec<StateSet>| {
+          if seen.insert(inputs.clone()) {
+            expanded.push((inputs, output));
+          }
+        };
+        match &perms {
+          Some(perms) => {
+            for perm in perms {
+              let mut permuted = vec![inputs[0]];
+              permuted.extend(perm.iter().map(|&i| inputs[i + 1]));
+              push(permuted);
+            }
+          }
+          None => {
+            // `permute` matches every distinct ordering of the neighbors
+            let mut neighbors = inputs[1..].to_vec();
+            neighbors.sort_unstable();
+            loop {
+              let mut permuted = vec![inputs[0]];
+              permuted.extend_from_slice(&neighbors);
+              push(permuted);
+              if !next_permutation(&mut neighbors) {
+                break;
+              }
+            }
+          }
+        }
+      }
+    }
+
+    let words = expanded.len().div_ceil(64);
+    let mut masks = vec![0; (n + 1) * num_states * words];
+    for (i, (inputs, _)) in expanded.iter().enumerate() {
+      for (pos, set) in inputs.iter().enumerate() {
+        for s in (0..num_states).filter(|&s| contains(set, s)) {
+          masks[(pos * num_states + s) * words + i / 64] |= 1 << (i % 64);
+        }
+      }
+    }
+
+    Ok(Self {
+      name,
+      num_states,
+      offsets: offsets.to_vec(),
+      lookup: Lookup::Table {
+        words,
+        masks,
+        outputs: expanded.into_iter().map(|(_, output)| output).collect(),
+      },
+    })
+  }
+
+  fn parse_tree(name: String, lines: &[&str]) -> Result<Self, String> {
+    let mut num_states = None::<usize>;
+    let mut num_neighbors = None::<usize>;
+    let mut levels = vec![];
+    let mut nodes = vec![];
+    for &line in lines {
+      if let Some((key, value)) = line.split_once('=') {
+        let value = value.trim().parse::<usize>()
+          .map_err(|_| format!("invalid {}", key))?;
+        match key.trim() {
+          "num_states" if (2..=256).contains(&value) => num_states = Some(value),
+          "num_neighbors" if value == 4 || value == 8 => num_neighbors = Some(value),
+          "num_nodes" => {}
+          key => return Err(format!("invalid {}", key)),
+        }
+        continue;
+      }
+
+      let num_states = num_states.ok_or_else(|| "missing num_states".to_owned())?;
+      let values = line.split_whitespace()
+        .map(|s| s.parse::<u32>().map_err(|_| format!("invalid node {:?}", line)))
+        .collect::<Result<Vec<_>, _>>()?;
+      if values.len() != num_states + 1 || values[0] == 0 {
+        return Err(format!("invalid node {:?}", line));
+      }
+      let level = values[0];
+      for &child in &values[1..] {
+        let valid = if level == 1 {
+          (child as usize) < num_states
+        } else {
+          levels.get(child as usize) == Some(&(level - 1))
+        };
+        if !valid {
+          return Err(format!("invalid node {:?}", line));
+        }
+      }
+      levels.push(level);
+      nodes.extend_from_slice(&values[1..]);
+    }
+
+    let num_states = num_states.ok_or_else(|| "missing num_states".to_owned())?;
+    let num_neighbors = num_neighbors.ok_or_else(|| "missing num_neighbors".to_owned())?;
+    if levels.last() != Some(&(num_neighbors as u32 + 1)) {
+      return Err("invalid root node".to_owned());
+    }
+    let offsets = if num_neighbors == 4 {
+      vec![(1, 0), (0, 1), (2, 1), (1, 2)]
+    } else {
+      vec![(0, 0), (2, 0), (0, 2), (2, 2), (1, 0), (0, 1), (2, 1), (1, 2)]
+    };
+
+    Ok(Self {
+      name,
+      num_states,
+      offsets,
+      lookup: Lookup::Tree {
+        nodes,
+        root: levels.len() as u32 - 1,
+      },
+    })
+  }
+
+  /// Returns the name in the `@RULE` line.
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// `neighbors` are in the order of `offsets`.
+  fn next_state(&self, state: u8, neighbors: &[u8]) -> u8 {
+    let num_states = self.num_states;
+    match &self.lookup {
+      Lookup::Table { words, masks, outputs } => {
+        let mask = |pos: usize, s: u8, i: usize| masks[(pos * num_states + s as usize) * words + i];
+        for i in 0..*words {
+          let mut bits = mask(0, state, i);
+          for (pos, &s) in neighbors.iter().enumerate() {
+            bits &= mask(pos + 1, s, i);
+          }
+          if bits != 0 {
+            return outputs[i * 64 + bits.trailing_zeros() as usize];
+          }
+        }
+        state
+      }
+      Lookup::Tree { nodes, root } => {
+        let mut node = *root;
+        for &s in neighbors.iter().chain(&[state]) {
+          node = nodes[node as usize * num_states + s as usize];
+        }
+        node as u8
+      }
+    }
+  }
+}
+
+impl MultiRule for RuleTable {
+  fn num_states(&self) -> usize {
+    self.num_states
+  }
+
+  fn step(&self, cells: &[u8], size: usize, next: &mut [u8]) {
+    let inner = size - 2;
+    let mut neighbors = vec![0; self.offsets.len()];
+    for y in 0..inner {
+      for x in 0..inner {
+        for (n, &(dx, dy)) in neighbors.iter_mut().zip(&self.offsets) {
+          *n = cells[(y + dy) * size + x + dx];
+        }
+        next[y * inner + x] = self.next_state(cells[(y + 1) * size + x + 1], &neighbors);
+      }
+    }
+  }
+}
+
+impl Display for RuleTable {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.name)
+  }
+}
+
+fn parse_token(s: &str) -> Result<Token, String> {
+  if s.starts_with(|c: char| c.is_ascii_digit()) {
+    s.parse().map(Token::State).map_err(|_| format!("invalid state {:?}", s))
+  } else if !s.is_empty() {
+    Ok(Token::Var(s.to_owned()))
+  } else {
+    Err("empty state".to_owned())
+  }
+}
+
+/// Expands a transition for every combination of the values of the variables
+/// that appear more than once, which are bound to the same state.
+fn bind_variables(
+  tokens: &[Token],
+  vars: &FxHashMap<&str, Vec<u8>>,
+  num_states: usize,
+) -> Result<Vec<(Vec<StateSet>, u8)>, String> {
+  let (inputs, output) = tokens.split_at(tokens.len() - 1);
+  let mut bound = Vec::<&str>::new();
+  for (i, token) in inputs.iter().enumerate() {
+    if let Token::Var(v) = token {
+      if !bound.contains(&&v[..]) && inputs[i + 1..].contains(token) {
+        bound.push(v);
+      }
+    }
+  }
+  match &output[0] {
+    Token::Var(v) if !inputs.contains(&output[0]) => {
+      return Err(format!("unbound variable {:?} in output", v));
+    }
+    Token::Var(v) if !bound.contains(&&v[..]) => bound.push(v),
+    _ => {}
+  }
+
+  let values = |v: &str| vars.get(v).ok_or_else(|| format!("unknown variable {:?}", v));
+  for token in tokens {
+    let valid = match token {
+      Token::State(s) => (*s as usize) < num_states,
+      Token::Var(v) => values(v)?.iter().all(|&s| (s as usize) < num_states),
+    };
+    if !valid {
+      return Err("state out of range".to_owned());
+    }
+  }
+
+  let mut expanded = vec![];
+  let mut binding = vec![0; bound.len()];
+  loop {
+    let state = |token: &Token| -> Result<StateSet, String> {
+      Ok(match token {
+        Token::State(s) => state_set([*s]),
+        Token::Var(v) => match bound.iter().position(|b| b == v) {
+          Some(i) => state_set([values(v)?[binding[i]]]),
+          None => state_set(values(v)?.iter().copied()),
+        },
+      })
+    };
+    let inputs = inputs.iter().map(state).collect::<Result<Vec<_>, _>>()?;
+    let output = match &output[0] {
+      Token::State(s) => *s,
+      Token::Var(v) => {
+        let i = bound.iter().position(|b| b == v).unwrap();
+        values(v)?[binding[i]]
+      }
+    };
+    expanded.push((inputs, output));
+
+    // next combination
+    let mut i = 0;
+    loop {
+      if i == bound.len() {
+        return Ok(expanded);
+      }
+      binding[i] += 1;
+      if binding[i] < values(bound[i])?.len() {
+        break;
+      }
+      binding[i] = 0;
+      i += 1;
+    }
+  }
+}
+
+/// Returns the permutations of `n` neighbors for `symmetries`, where
+/// `perm[i]` is the neighbor moved to `i`, or `None` for `permute`.
+fn symmetry_permutations(
+  symmetries: &str,
+  n: usize,
+) -> Result<Option<Vec<Vec<usize>>>, String> {
+  let (num_rotations, reflect) = match symmetries {
+    "none" => (1, false),
+    "permute" => return Ok(None),
+    "reflect" | "reflect_horizontal" => (1, true),
+    s => match s.strip_prefix("rotate") {
+      Some(s) => {
+        let (num, reflect) = match s.strip_suffix("reflect") {
+          Some(num) => (num, true),
+          None => (s, false),
+        };
+        (num.parse::<usize>().unwrap_or(0), reflect)
+      }
+      None => (0, false),
+    },
+  };
+  if num_rotations == 0 || !n.is_multiple_of(num_rotations) {
+    return Err(format!("unsupported symmetries {:?}", symmetries));
+  }
+
+  let step = n / num_rotations;
+  let mut perms = vec![];
+  for r in 0..num_rotations {
+    let rotation = (0..n).map(|i| (i + r * step) % n).collect::<Vec<_>>();
+    if reflect {
+      perms.push(rotation.iter().map(|&i| (n - i) % n).collect());
+    }
+    perms.push(rotation);
+  }
+  Ok(Some(perms))
+}
+
+/// Rearranges `a` into the next permutation in lexicographic order, and
+/// returns `false` if `a` was the last one.
+fn next_permutation<T: Ord>(a: &mut [T]) -> bool {
+  let i = match (1..a.len()).rev().find(|&i| a[i - 1] < a[i]) {
+    Some(i) => i,
+    None => return false,
+  };
+  let j = (i..a.len()).rev().find(|&j| a[i - 1] < a[j]).unwrap();
+  a.swap(i - 1, j);
+  a[i..].reverse();
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::generations::Generations;
+
+  const WIREWORLD: &str = r"
+@RULE WireWorld
+
+@TABLE
+n_states:4
+neighborhood:Moore
+symmetries:permute
+var a={0,1,2,3}
+var b=a
+var c=a
+var d=a
+var e=a
+var f=a
+var g=a
+var h=a
+var i={0,2,3}
+var j=i
+var k=i
+var l=i
+var m=i
+var n=i
+var o=i
+# head -> tail -> wire
+1,a,b,c,d,e,f,g,h,2
+2,a,b,c,d,e,f,g,h,3
+# wire -> head with 1 or 2 heads
+3,1,i,j,k,l,m,n,o,1
+3,1,1,i,j,k,l,m,n,1
+
+@COLORS
+1 255 255 255
+";
+
+  /// Returns the result of `rule` at the centers of the 3x3 squares of
+  /// `cells`, which has `size` cells on both sides.
+  fn step(rule: &dyn MultiRule, cells: &[u8], size: usize) -> Vec<u8> {
+    let mut next = vec![0; (size - 2) * (size - 2)];
+    rule.step(cells, size, &mut next);
+    next
+  }
+
+  fn random_cells(size: usize, num_states: usize, mut seed: u32) -> Vec<u8> {
+    (0..size * size).map(|_| {
+      seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+      ((seed >> 16) as usize % num_states) as u8
+    }).collect()
+  }
+
+  #[test]
+  fn test_wireworld() {
+    let rule = RuleTable::parse(WIREWORLD).unwrap();
+    assert_eq!(rule.to_string(), "WireWorld");
+    assert_eq!(rule.num_states(), 4);
+    let cells = random_cells(16, 4, 1);
+    let next = step(&rule, &cells, 16);
+    for y in 0..14 {
+      for x in 0..14 {
+        let heads = (0..9)
+          .filter(|&i| i != 4 && cells[(y + i / 3) * 16 + x + i % 3] == 1)
+          .count();
+        let expected = match cells[(y + 1) * 16 + x + 1] {
+          0 => 0,
+          1 => 2,
+          2 => 3,
+          _ if heads == 1 || heads == 2 => 1,
+          _ => 3,
+        };
+        assert_eq!(next[y * 14 + x], expected, "({}, {})", x, y);
+      }
+    }
+  }
+
+  #[test]
+  fn test_symmetries() {
+    // Life, with every symmetry that a totalistic rule has
+    let life = "23/3/2".parse::<Generations>().unwrap();
+    let cells = random_cells(32, 2, 2);
+    for symmetries in &["permute", "rotate8reflect", "rotate4reflect", "none"] {
+      let mut src = format!(
+        "@RULE Life\n@TABLE\nn_states:2\nneighborhood:Moore\nsymmetries:{}\n",
+        symmetries,
+      );
+      // every neighborhood is listed, so the symmetries only add duplicates
+      for n in 0..256u32 {
+        let bits = (0..8).map(|i| (n >> i & 1).to_string()).collect::<Vec<_>>().join(",");
+        match n.count_ones() {
+          3 => src.push_str(&format!("0,{},1\n", bits)),
+          2 => {}
+          _ => src.push_str(&format!("1,{},0\n", bits)),
+        }
+      }
+      let rule = RuleTable::parse(&src).unwrap();
+      assert_eq!(step(&rule, &cells, 32), step(&life, &cells, 32), "{}", symmetries);
+    }
+  }
+
+  #[test]
+  fn test_rotations_and_reflections() {
+    // a dead cell is born if its only alive neighbors are N and NE
+    let births = |symmetries: &str| {
+      let src = format!(
+        "@RULE Pair\n@TABLE\nn_states:2\nneighborhood:Moore\nsymmetries:{}\n{}",
+        symmetries,
+        "0,1,1,0,0,0,0,0,0,1\n",
+      );
+      let rule = RuleTable::parse(&src).unwrap();
+      (0..256usize).filter(|&n| {
+        let mut cells = [0; 9];
+        for (i, &(x, y)) in Neighborhood::Moore.table_offsets().iter().enumerate() {
+          cells[y * 3 + x] = (n >> i & 1) as u8;
+        }
+        step(&rule, &cells, 3) == [1]
+      }).collect::<Vec<_>>()
+    };
+
+    // the bits of the alive neighbors from N to NW, clockwise
+    assert_eq!(births("none"), [0b0000_0011]);
+    // mirrored left to right
+    assert_eq!(births("reflect"), [0b0000_0011, 0b1000_0001]);
+    // rotated by quarter turns
+    assert_eq!(births("rotate4"), [0b0000_0011, 0b0000_1100, 0b0011_0000, 0b1100_0000]);
+    // rotated by eighth turns and mirrored
+    assert_eq!(births("rotate8reflect"), [
+      0b0000_0011, 0b0000_0110, 0b0000_1100, 0b0001_1000,
+      0b0011_0000, 0b0110_0000, 0b1000_0001, 0b1100_0000,
+    ]);
+  }
+
+  #[test]
+  fn test_bound_variables() {
+    // a cell copies its north neighbor if its east and west neighbors agree
+    let src = r"
+@RULE Copy
+@TABLE
+n_states:3
+neighborhood:vonNeumann
+symmetries:none
+var a={0,1,2}
+var b=a
+var c=a
+0,a,b,c,b,a
+";
+    let rule = RuleTable::parse(src).unwrap();
+    let cells = random_cells(10, 3, 3);
+    let next = step(&rule, &cells, 10);
+    for y in 0..8 {
+      for x in 0..8 {
+        let c = cells[(y + 1) * 10 + x + 1];
+        let n = cells[y * 10 + x + 1];
+        let expected = if c == 0 && cells[(y + 1) * 10 + x] == cells[(y + 1) * 10 + x + 2] {
+          n
+        } else {
+          c
+        };
+        assert_eq!(next[y * 8 + x], expected);
+      }
+    }
+
+    assert!(RuleTable::parse("@RULE R\n@TABLE\nn_states:2\nvar a={0,1}\n0,0,0,0,0,0,0,0,0,a").is_err());
+  }
+
+  #[test]
+  fn test_tree() {
+    // every cell copies its north neighbor
+    let src = r"
+@RULE South
+@TREE
+num_states=2
+num_neighbors=4
+num_nodes=9
+1 0 0
+1 1 1
+2 0 0
+2 1 1
+3 2 2
+3 3 3
+4 4 4
+4 5 5
+5 6 7
+";
+    let rule = RuleTable::parse(src).unwrap();
+    let cells = random_cells(10, 2, 4);
+    let next = step(&rule, &cells, 10);
+    for y in 0..8 {
+      assert_eq!(&next[y * 8..][..8], &cells[y * 10 + 1..][..8]);
+    }
+
+    assert!(RuleTable::parse("@RULE R\n@TREE\nnum_states=2\nnum_neighbors=4\n1 0 1\n2 1 0").is_err());
+    // state 0 would become 1
+    assert!(RuleTable::parse(&src.replace("1 0 0\n", "1 1 1\n")).is_err());
+  }
+
+  #[test]
+  fn test_next_permutation() {
+    let mut a = [1, 1, 2];
+    let mut perms = vec![a];
+    while next_permutation(&mut a) {
+      perms.push(a);
+    }
+    assert_eq!(perms, vec![[1, 1, 2], [1, 2, 1], [2, 1, 1]]);
+  }
+}
@@ -0,0 +1,100 @@
+use crate::multistate::MultiRule;
+use crate::rule::Rule;
+use std::fmt::{self, Display, Formatter};
+
+/// Suffix of the rule strings of second-order rules, such as `B36/S23_SO`.
+pub const SUFFIX: &str = "_SO";
+
+/// The second-order variant of a two-state rule, in which the next state of a
+/// cell is the next state in the rule XOR its previous state.
+///
+/// The state of a cell is its current state in bit 0 and its previous state
+/// in bit 1. Such rules are reversible.
+#[derive(Clone, Copy, Debug)]
+pub struct SecondOrder {
+  rule: Rule,
+}
+
+impl SecondOrder {
+  /// Returns `None` if the rule has B0, is a Margolus rule or has a bounded
+  /// topology.
+  pub fn new(rule: Rule) -> Option<Self> {
+    if rule.blocks().is_some() || rule.next_state(0) || rule.topology().is_bounded() {
+      return None;
+    }
+    Some(Self { rule })
+  }
+
+  pub fn rule(&self) -> &Rule {
+    &self.rule
+  }
+}
+
+impl MultiRule for SecondOrder {
+  fn num_states(&self) -> usize {
+    4
+  }
+
+  fn step(&self, cells: &[u8], size: usize, next: &mut [u8]) {
+    let inner = size - 2;
+    for y in 0..inner {
+      for x in 0..inner {
+        let neighborhood = (0..9).fold(0, |bits, i| {
+          bits << 1 | (cells[(y + i / 3) * size + x + i % 3] & 1) as usize
+        });
+        let state = cells[(y + 1) * size + x + 1];
+        let current = self.rule.next_state(neighborhood) as u8 ^ state >> 1;
+        next[y * inner + x] = current | (state & 1) << 1;
+      }
+    }
+  }
+
+  fn time_reversal(&self) -> Option<Vec<u8>> {
+    // swapping the current and the previous states
+    Some(vec![0, 2, 1, 3])
+  }
+}
+
+impl Display for SecondOrder {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{}{}", self.rule, SUFFIX)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::rule::GAME_OF_LIFE;
+
+  #[test]
+  fn test_step() {
+    let rule = SecondOrder::new(GAME_OF_LIFE).unwrap();
+    assert_eq!(rule.to_string(), "B3/S23_SO");
+    let size = 16;
+    let mut seed = 1u32;
+    let cells = (0..size * size).map(|_| {
+      seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+      (seed >> 16 & 3) as u8
+    }).collect::<Vec<_>>();
+    let inner = size - 2;
+    let mut next = vec![0; inner * inner];
+    rule.step(&cells, size, &mut next);
+
+    for y in 1..size - 1 {
+      for x in 1..size - 1 {
+        let num = (0..9)
+          .filter(|&i| i != 4 && cells[(y + i / 3 - 1) * size + x + i % 3 - 1] & 1 != 0)
+          .count();
+        let state = cells[y * size + x];
+        let alive = state & 1 != 0;
+        let life = num == 3 || num == 2 && alive;
+        let expected = (life ^ (state & 2 != 0)) as u8 | (alive as u8) << 1;
+        assert_eq!(next[(y - 1) * inner + x - 1], expected);
+      }
+    }
+
+    let mut b0 = Rule::new();
+    b0.set_birth(0);
+    assert!(SecondOrder::new(b0).is_none());
+  }
+}
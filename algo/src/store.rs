@@ -0,0 +1,1382 @@
+use num_bigint::BigUint;
+use num_traits::Zero;
+use rustc_hash::FxHashMap;
+use rayon::ThreadPool;
+use std::fmt;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::arena::Arena;
+use crate::node::*;
+use crate::parallel::{NewNodes, ParallelStep};
+use crate::rule::*;
+use crate::topology::Topology;
+use crate::universe::Boundary;
+
+/// Hash-consed nodes and their memoized results.
+///
+/// A store can be shared by several universes with the same rule, so that
+/// they reuse the results computed by each other. Universes sharing a store
+/// can be used from different threads, but they do not simulate at the same
+/// time. A parallel step reuses and memoizes results in the store.
+#[derive(Clone)]
+pub struct NodeStore(Arc<RwLock<Store>>);
+
+pub(crate) struct Store {
+  arena: Arena<Node>,
+  empty_nodes: Vec<NodeId>,
+  /// result is a 2x2 square, whose cells are arranged as follows
+  /// ```ignored
+  /// bit 7 ...  5  4  3  2  1  0
+  ///     -  -  NW NE  -  - SW SE
+  /// ```
+  ///
+  /// The tables of even generations, odd generations at odd coordinates and
+  /// odd generations at even coordinates, as returned by
+  /// `Rule::level2_results`.
+  level2_results: [[u8; 65536]; 3],
+  rule: Rule,
+  last_k: Option<u16>,
+  /// Whether the last step started at an odd generation of an alternating rule.
+  last_odd: bool,
+  /// Indexed by the ids of attached universes.
+  attached: Vec<Option<AttachedRoots>>,
+}
+
+/// Nodes that an attached universe keeps alive.
+pub(crate) struct AttachedRoots {
+  pub(crate) root: NodeId,
+  /// Roots of saved states, such as undo history and recorded timeline.
+  pub(crate) snapshots: Vec<NodeId>,
+}
+
+/// Garbage collection settings and memory budget of a [`NodeStore`] or a
+/// [`MultiUniverse`](crate::multistate::MultiUniverse).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GcConfig {
+  /// Number of nodes that triggers the first collection between steps.
+  pub initial_threshold: usize,
+  /// After a collection, the next one is triggered when the number of nodes
+  /// reaches this percentage of the surviving nodes.
+  pub threshold_increment: usize,
+  /// Maximum number of nodes. Garbage is also collected in the middle of a
+  /// step when it is reached, and the simulation fails with
+  /// [`MemoryLimitExceeded`] if more than 3/4 of the nodes survive.
+  pub max_nodes: Option<usize>,
+}
+
+/// Approximate heap bytes used by a node, including its hash table entry.
+pub const BYTES_PER_NODE: usize = std::mem::size_of::<Node>() +
+  std::mem::size_of::<(NodeKey, NodeId)>() + 1;
+
+impl Default for GcConfig {
+  fn default() -> Self {
+    Self {
+      initial_threshold: 30000,
+      threshold_increment: 160,
+      max_nodes: None,
+    }
+  }
+}
+
+impl GcConfig {
+  /// Limits the nodes to approximately `bytes` of heap memory.
+  pub fn with_max_bytes(self, bytes: usize) -> Self {
+    Self { max_nodes: Some(bytes / BYTES_PER_NODE), ..self }
+  }
+}
+
+/// A snapshot of the counters of a [`NodeStore`] or a
+/// [`MultiUniverse`](crate::multistate::MultiUniverse).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+  /// Number of live nodes of each level. Leaves are level 3, or level 1 in a
+  /// multi-state universe.
+  pub nodes_per_level: Vec<usize>,
+  /// Lookups in the hash-consing table that found an existing node.
+  pub find_hits: u64,
+  /// Lookups in the hash-consing table that created a new node.
+  pub find_misses: u64,
+  /// Steps whose results were already memoized.
+  pub memo_hits: u64,
+  pub gc_runs: u64,
+  /// Total number of nodes freed by garbage collection.
+  pub gc_freed: u64,
+  /// Number of nodes that triggers the next collection between steps.
+  pub gc_threshold: usize,
+  /// Approximate heap bytes used by the store.
+  pub heap_bytes: usize,
+}
+
+impl Stats {
+  pub fn num_nodes(&self) -> usize {
+    self.nodes_per_level.iter().sum()
+  }
+}
+
+/// The live nodes of a simulation do not fit in the budget given by
+/// [`GcConfig::max_nodes`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryLimitExceeded;
+
+impl fmt::Display for MemoryLimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "memory limit exceeded")
+  }
+}
+
+impl std::error::Error for MemoryLimitExceeded {}
+
+impl NodeStore {
+  /// The topology of the rule is dropped, because universes of a store may
+  /// have different grids.
+  pub fn new(rule: Rule) -> Self {
+    Self::with_config(rule, GcConfig::default())
+  }
+
+  pub fn with_config(rule: Rule, gc_config: GcConfig) -> Self {
+    Self(Arc::new(RwLock::new(Store::new(rule, gc_config))))
+  }
+
+  pub fn gc_config(&self) -> GcConfig {
+    self.borrow().arena.gc_config()
+  }
+
+  /// The new settings take effect from the next collection.
+  pub fn set_gc_config(&self, gc_config: GcConfig) {
+    self.borrow_mut().arena.set_gc_config(gc_config);
+  }
+
+  pub fn rule(&self) -> Rule {
+    self.borrow().rule
+  }
+
+  pub fn stats(&self) -> Stats {
+    self.borrow().stats()
+  }
+
+  pub(crate) fn borrow(&self) -> RwLockReadGuard<'_, Store> {
+    self.0.read().unwrap()
+  }
+
+  pub(crate) fn borrow_mut(&self) -> RwLockWriteGuard<'_, Store> {
+    self.0.write().unwrap()
+  }
+}
+
+impl Store {
+  fn new(mut rule: Rule, gc_config: GcConfig) -> Self {
+    rule.set_topology(Topology::Unbounded);
+    let level2_results = rule.level2_results();
+    let mut store = Self {
+      arena: Arena::new(gc_config),
+      empty_nodes: vec![INVALID_NODE_ID; 4],
+      level2_results,
+      rule,
+      last_k: None,
+      last_odd: false,
+      attached: vec![],
+    };
+
+    let empty = store.find_node(NodeKey::new_leaf(0, 0, 0, 0));
+    store.empty_nodes[3] = empty;
+    store.arena.clear_gc_roots();
+    store
+  }
+
+  pub(crate) fn attach(&mut self) -> usize {
+    let roots = AttachedRoots {
+      root: self.empty_nodes[3],
+      snapshots: vec![],
+    };
+    match self.attached.iter().position(Option::is_none) {
+      Some(id) => {
+        self.attached[id] = Some(roots);
+        id
+      }
+      None => {
+        self.attached.push(Some(roots));
+        self.attached.len() - 1
+      }
+    }
+  }
+
+  pub(crate) fn detach(&mut self, id: usize) {
+    self.attached[id] = None;
+  }
+
+  pub(crate) fn attached_roots(&mut self, id: usize) -> &mut AttachedRoots {
+    self.attached[id].as_mut().unwrap()
+  }
+
+  pub(crate) fn root(&self, id: usize) -> NodeId {
+    self.attached[id].as_ref().unwrap().root
+  }
+
+  pub(crate) fn set_root(&mut self, id: usize, root: NodeId) {
+    self.attached_roots(id).root = root;
+  }
+
+  pub(crate) fn node(&self, id: NodeId) -> &Node {
+    self.arena.node(id)
+  }
+  pub(crate) fn internal_key(&self, id: NodeId) -> InternalNodeKey {
+    self.node(id).unwrap_internal_ref().key
+  }
+  pub(crate) fn leaf_key(&self, id: NodeId) -> LeafNodeKey {
+    self.node(id).unwrap_leaf_ref().key
+  }
+  pub(crate) fn level(&self, node: NodeId) -> u16 {
+    self.node(node).level()
+  }
+  /// Number of live nodes.
+  pub(crate) fn num_nodes(&self) -> usize {
+    self.arena.num_nodes()
+  }
+  fn stats(&self) -> Stats {
+    let heap_bytes = std::mem::size_of::<Self>() +
+      self.empty_nodes.capacity() * std::mem::size_of::<NodeId>() +
+      self.attached.iter().flatten()
+        .map(|roots| roots.snapshots.capacity() * std::mem::size_of::<NodeId>())
+        .sum::<usize>();
+    self.arena.stats(heap_bytes)
+  }
+  /// Returns the node of `key` if it exists.
+  pub(crate) fn lookup(&self, key: &NodeKey) -> Option<NodeId> {
+    self.arena.lookup(key)
+  }
+  /// Number of ids in use, including the ids of freed nodes.
+  pub(crate) fn num_ids(&self) -> usize {
+    self.arena.num_ids()
+  }
+  /// Ids of freed nodes, which are reused from the last one.
+  pub(crate) fn free_ids(&self) -> &[NodeId] {
+    self.arena.free_ids()
+  }
+  /// Whether the current step starts at an odd generation of an alternating
+  /// rule.
+  pub(crate) fn last_odd(&self) -> bool {
+    self.last_odd
+  }
+  pub(crate) fn level2_results(&self) -> &[[u8; 65536]; 3] {
+    &self.level2_results
+  }
+  pub(crate) fn find_node(&mut self, key: NodeKey) -> NodeId {
+    let level2_results = &self.level2_results;
+    self.arena.find(key, |arena| {
+      new_node(key, |id| arena.node(id), level2_results)
+    })
+  }
+  /// Like `find_node`, but collects garbage when the memory limit is reached,
+  /// so every node in use must be reachable from `gc_roots`. The found node
+  /// is rooted even if it already existed.
+  fn try_find_node(
+    &mut self,
+    key: NodeKey,
+  ) -> Result<NodeId, MemoryLimitExceeded> {
+    let Self { arena, level2_results, empty_nodes, attached, .. } = self;
+    arena.try_find(
+      key,
+      |arena| new_node(key, |id| arena.node(id), level2_results),
+      || gc_roots(empty_nodes, attached),
+    )
+  }
+  /// Advances `2 ^ k` generations of the pattern under `root`, which must be
+  /// the root of an attached universe. `odd` is whether the current
+  /// generation is odd, which is allowed only for single generation steps.
+  /// The step uses the threads of `pool` if given.
+  ///
+  /// On error, the pattern is left unchanged.
+  pub(crate) fn simulate(
+    &mut self,
+    mut root: NodeId,
+    k: u16,
+    odd: bool,
+    pool: Option<&ThreadPool>,
+  ) -> Result<NodeId, MemoryLimitExceeded> {
+    let odd = odd && self.rule.is_alternating();
+    assert!(k == 0 || !odd, "multi-generation step from an odd generation");
+    self.clear_results(k, odd);
+
+    let gc_s = self.gc_save();
+    self.gc_root(root);
+    if self.arena.needs_gc() {
+      self.gc();
+    }
+    root = self.expand_for_step(root, k);
+
+    // results of `k` are memoized from now on, even if stepping fails
+    self.last_k = Some(k);
+    self.last_odd = odd;
+
+    self.gc_root(root);
+    let result = match pool {
+      Some(pool) => self.par_step(root, k, pool),
+      None => self.step_rec(root, k),
+    };
+    self.gc_restore(gc_s);
+    root = match result {
+      Ok(root) => root,
+      Err(err) => {
+        self.gc();
+        return Err(err);
+      }
+    };
+
+    let gc_s = self.gc_save();
+    root = self.shrink(root);
+    root = self.clamp_level(root);
+    self.gc_restore(gc_s);
+    Ok(root)
+  }
+  /// Steps `root`, which must be rooted, with the threads of `pool`. When the
+  /// memory limit is reached, garbage is collected and the step is retried,
+  /// keeping the results memoized so far.
+  fn par_step(
+    &mut self,
+    root: NodeId,
+    k: u16,
+    pool: &ThreadPool,
+  ) -> Result<NodeId, MemoryLimitExceeded> {
+    let mut survivors = None;
+    loop {
+      let max_nodes = match self.arena.gc_config().max_nodes {
+        Some(max_nodes) => max_nodes.saturating_sub(self.num_nodes()),
+        None => usize::MAX,
+      };
+      let step = ParallelStep::new(self, max_nodes);
+      let result = pool.install(|| step.step_rec(root, k));
+      let new_nodes = step.finish();
+      self.add_nodes(new_nodes);
+      if let Ok(result) = result {
+        self.gc_root(result);
+        return Ok(result);
+      }
+
+      self.gc();
+      let num_nodes = self.num_nodes();
+      let max_nodes = self.arena.gc_config().max_nodes.unwrap_or(usize::MAX);
+      // give up if the nodes are nearly all alive, or the last attempt did
+      // not memoize anything
+      if num_nodes > max_nodes / 4 * 3 || survivors == Some(num_nodes) {
+        return Err(MemoryLimitExceeded);
+      }
+      survivors = Some(num_nodes);
+    }
+  }
+  /// Adds the nodes created by a parallel step, which get the ids they were
+  /// given by the step.
+  fn add_nodes(&mut self, new_nodes: NewNodes) {
+    self.arena.find_hits += new_nodes.find_hits;
+    self.arena.find_misses += new_nodes.nodes.len() as u64;
+    self.arena.memo_hits += new_nodes.memo_hits;
+    for node in new_nodes.nodes {
+      let key = match &node {
+        Node::Internal(node) => NodeKey::Internal(node.key),
+        Node::Leaf(node) => NodeKey::Leaf(node.key),
+      };
+      self.arena.insert(key, node);
+    }
+  }
+  /// Expands `root` so that stepping it advances `2 ^ k` generations.
+  fn expand_for_step(&mut self, mut root: NodeId, k: u16) -> NodeId {
+    // preserve enough empty space
+    root = self.expand(root);
+    root = self.expand(root);
+
+    // we need to advance `2 ^ min(k, level - 3)` generations, instead of
+    // `2 ^ min(k, level - 2)` generations, because the latter can cause the
+    // leakage of information of the RESULT macro-cell.
+    while self.level(root) < 4.max(k + 3) {
+      root = self.expand(root);
+    }
+    root
+  }
+  /// Sets the cell at `(x, y)` of the pattern under `root`, expanding it if
+  /// needed.
+  pub(crate) fn set_cell(
+    &mut self,
+    mut root: NodeId,
+    x: i64,
+    y: i64,
+    alive: bool,
+  ) -> NodeId {
+    let gc_s = self.gc_save();
+    let mut radius = 1 << (self.level(root) - 1);
+    while x < -radius || x >= radius ||
+      y < -radius || y >= radius
+    {
+      root = self.expand(root);
+      radius <<= 1;
+    }
+
+    root = self.set_rec(root, x, y, alive);
+    self.gc_restore(gc_s);
+    root
+  }
+  /// Clears the cells of the pattern under `root` outside `rect`.
+  pub(crate) fn crop(&mut self, mut root: NodeId, rect: &Boundary) -> NodeId {
+    let gc_s = self.gc_save();
+    root = self.crop_rec(root, rect, 0, 0);
+    root = self.shrink(root);
+    self.gc_restore(gc_s);
+    root
+  }
+  /// `(ox, oy)` is the center of the node.
+  fn crop_rec(
+    &mut self,
+    node: NodeId,
+    rect: &Boundary,
+    ox: i64,
+    oy: i64,
+  ) -> NodeId {
+    let level = self.level(node);
+    let r = 1 << (level - 1);
+    let left = ox - r;
+    let top = oy - r;
+    let right = ox + r;
+    let bottom = oy + r;
+    if self.node(node).population() == 0
+      || left >= rect.left
+      && right <= rect.right
+      && top >= rect.top
+      && bottom <= rect.bottom
+    {
+      return node;
+    }
+    if right <= rect.left
+      || left >= rect.right
+      || top >= rect.bottom
+      || bottom <= rect.top
+    {
+      return self.find_empty_node(level);
+    }
+
+    match *self.node(node) {
+      Node::Leaf(LeafNode { mut key, .. }) => {
+        let quadrants = [
+          (&mut key.nw, left, top),
+          (&mut key.ne, left + 4, top),
+          (&mut key.sw, left, top + 4),
+          (&mut key.se, left + 4, top + 4),
+        ];
+        for (bits, x0, y0) in quadrants {
+          for i in 0..16 {
+            let x = x0 + 3 - (i & 3);
+            let y = y0 + 3 - (i >> 2);
+            if x < rect.left || x >= rect.right || y < rect.top || y >= rect.bottom {
+              *bits &= !(1 << i);
+            }
+          }
+        }
+        self.find_node(NodeKey::Leaf(key))
+      }
+      Node::Internal(InternalNode { key, .. }) => {
+        let gc_s = self.gc_save();
+        let rr = r >> 1;
+        let nw = self.crop_rec(key.nw, rect, ox - rr, oy - rr);
+        let ne = self.crop_rec(key.ne, rect, ox + rr, oy - rr);
+        let sw = self.crop_rec(key.sw, rect, ox - rr, oy + rr);
+        let se = self.crop_rec(key.se, rect, ox + rr, oy + rr);
+        let node = self.find_node(NodeKey::new_internal(nw, ne, sw, se));
+        self.gc_restore(gc_s);
+        self.gc_root(node);
+        node
+      }
+    }
+  }
+  /// `(x, y)` are coordinate relative to center of the node.
+  pub(crate) fn set_rec(
+    &mut self,
+    node: NodeId,
+    x: i64,
+    y: i64,
+    alive: bool
+  ) -> NodeId {
+    match *self.node(node) {
+      Node::Leaf(LeafNode { key, .. }) => {
+        debug_assert!((-4..4).contains(&x) && (-4..4).contains(&y));
+        let mut new_key = key;
+        let bits = if x < 0 {
+          if y < 0 {
+            &mut new_key.nw
+          } else {
+            &mut new_key.sw
+          }
+        } else {
+          if y < 0 {
+            &mut new_key.ne
+          } else {
+            &mut new_key.se
+          }
+        };
+        let mask = 1u16 << ((3 - (x & 3)) + 4 * (3 - (y & 3)));
+        if alive {
+          *bits |= mask;
+        } else {
+          *bits &= !mask;
+        }
+
+        self.find_node(NodeKey::Leaf(new_key))
+      }
+      Node::Internal(InternalNode { key, level, .. }) => {
+        let gc_s = self.gc_save();
+        let r = 1i64 << (level - 2);
+        let mut new_key = key;
+        if y < 0 {
+          if x < 0 {
+            new_key.nw = self.set_rec(key.nw, x + r, y + r, alive);
+          } else {
+            new_key.ne = self.set_rec(key.ne, x - r, y + r, alive);
+          }
+        } else {
+          if x < 0 {
+            new_key.sw = self.set_rec(key.sw, x + r, y - r, alive);
+          } else {
+            new_key.se = self.set_rec(key.se, x - r, y - r, alive);
+          }
+        }
+
+        let new_node = self.find_node(NodeKey::Internal(new_key));
+        self.gc_restore(gc_s);
+        self.gc_root(new_node);
+        new_node
+      }
+    }
+  }
+  /// `(x, y)` are coordinate relative to center of the node.
+  pub(crate) fn get_rec(&self, node: NodeId, x: i64, y: i64) -> bool {
+    let level = self.level(node);
+    if self.empty_nodes.len() > level as usize &&
+      node == self.empty_nodes[level as usize]
+    {
+      return false;
+    }
+
+    match self.node(node) {
+      Node::Leaf(LeafNode { key, .. }) => {
+        debug_assert!((-4..4).contains(&x) && (-4..4).contains(&y));
+        let bits = if x < 0 {
+          if y < 0 {
+            key.nw
+          } else {
+            key.sw
+          }
+        } else {
+          if y < 0 {
+            key.ne
+          } else {
+            key.se
+          }
+        };
+        let mask = 1u16 << ((3 - (x & 3)) + 4 * (3 - (y & 3)));
+        bits & mask != 0
+      }
+      Node::Internal(InternalNode { key, level, .. }) => {
+        let r = 1i64 << (level - 2);
+        if y < 0 {
+          if x < 0 {
+            self.get_rec(key.nw, x + r, y + r)
+          } else {
+            self.get_rec(key.ne, x - r, y + r)
+          }
+        } else {
+          if x < 0 {
+            self.get_rec(key.sw, x + r, y - r)
+          } else {
+            self.get_rec(key.se, x - r, y - r)
+          }
+        }
+      }
+    }
+  }
+  /// Only the nodes whose population is saturated are memoized.
+  pub(crate) fn node_population(
+    &self,
+    node: NodeId,
+    memo: &mut FxHashMap<NodeId, BigUint>,
+  ) -> BigUint {
+    let population = self.node(node).population();
+    if population != u64::MAX {
+      return population.into();
+    }
+
+    if let Some(population) = memo.get(&node) {
+      return population.clone();
+    }
+
+    let key = self.internal_key(node);
+    let population = self.node_population(key.nw, memo) +
+      self.node_population(key.ne, memo) +
+      self.node_population(key.sw, memo) +
+      self.node_population(key.se, memo);
+    memo.insert(node, population.clone());
+    population
+  }
+  pub(crate) fn population_in_rec(
+    &self,
+    node: NodeId,
+    rect: &Boundary,
+    ox: i64,
+    oy: i64,
+    memo: &mut FxHashMap<NodeId, BigUint>,
+  ) -> BigUint {
+    let level = self.level(node);
+    if self.empty_nodes.len() > level as usize &&
+      node == self.empty_nodes[level as usize]
+    {
+      return BigUint::zero();
+    }
+
+    let r = 1 << (level - 1);
+    let left = ox - r;
+    let top = oy - r;
+    let right = ox + r;
+    let bottom = oy + r;
+    if right <= rect.left
+      || left >= rect.right
+      || top >= rect.bottom
+      || bottom <= rect.top
+    {
+      return BigUint::zero();
+    }
+
+    if left >= rect.left
+      && right <= rect.right
+      && top >= rect.top
+      && bottom <= rect.bottom
+    {
+      return self.node_population(node, memo);
+    }
+
+    match self.node(node) {
+      Node::Leaf(LeafNode { key, .. }) => {
+        let mut population = 0u32;
+        for dy in 0..8 {
+          if top + dy < rect.top || top + dy >= rect.bottom {
+            continue;
+          }
+          let (west, east) = if dy < 4 {
+            (key.nw, key.ne)
+          } else {
+            (key.sw, key.se)
+          };
+          for dx in 0..8 {
+            if left + dx < rect.left || left + dx >= rect.right {
+              continue;
+            }
+            let bits = if dx < 4 { west } else { east };
+            let mask = 1u16 << ((3 - (dx & 3)) + 4 * (3 - (dy & 3)));
+            population += (bits & mask != 0) as u32;
+          }
+        }
+        population.into()
+      }
+      Node::Internal(InternalNode { key, .. }) => {
+        let rr = r >> 1;
+        self.population_in_rec(key.nw, rect, ox - rr, oy - rr, memo) +
+          self.population_in_rec(key.ne, rect, ox + rr, oy - rr, memo) +
+          self.population_in_rec(key.sw, rect, ox - rr, oy + rr, memo) +
+          self.population_in_rec(key.se, rect, ox + rr, oy + rr, memo)
+      }
+    }
+  }
+  pub(crate) fn expand(&mut self, root: NodeId) -> NodeId {
+    let gc_s = self.gc_save();
+    let nw;
+    let ne;
+    let sw;
+    let se;
+    match *self.node(root) {
+      Node::Leaf(LeafNode { key, .. }) => {
+        nw = self.find_node(NodeKey::Leaf(LeafNodeKey {
+          se: key.nw,
+          ..Default::default()
+        }));
+        ne = self.find_node(NodeKey::Leaf(LeafNodeKey {
+          sw: key.ne,
+          ..Default::default()
+        }));
+        sw = self.find_node(NodeKey::Leaf(LeafNodeKey {
+          ne: key.sw,
+          ..Default::default()
+        }));
+        se = self.find_node(NodeKey::Leaf(LeafNodeKey {
+          nw: key.se,
+          ..Default::default()
+        }));
+      }
+      Node::Internal(InternalNode { key, level, .. }) => {
+        let empty = self.find_empty_node(level - 1);
+        nw = self.find_node(NodeKey::Internal(InternalNodeKey {
+          nw: empty,
+          ne: empty,
+          sw: empty,
+          se: key.nw,
+        }));
+        ne = self.find_node(NodeKey::Internal(InternalNodeKey {
+          nw: empty,
+          ne: empty,
+          sw: key.ne,
+          se: empty,
+        }));
+        sw = self.find_node(NodeKey::Internal(InternalNodeKey {
+          nw: empty,
+          ne: key.sw,
+          sw: empty,
+          se: empty,
+        }));
+        se = self.find_node(NodeKey::Internal(InternalNodeKey {
+          nw: key.se,
+          ne: empty,
+          sw: empty,
+          se: empty,
+        }));
+      }
+    }
+    let root = self.find_node(NodeKey::new_internal(nw, ne, sw, se));
+    self.gc_restore(gc_s);
+    root
+  }
+  pub(crate) fn shrink(&mut self, mut root: NodeId) -> NodeId {
+    let mut level = self.level(root);
+    if self.empty_nodes.len() <= (level - 2) as usize {
+      return root;
+    }
+
+    let gc_s = self.gc_save();
+    while level > 4 {
+      let key = self.internal_key(root);
+      let nw = self.internal_key(key.nw);
+      let ne = self.internal_key(key.ne);
+      let sw = self.internal_key(key.sw);
+      let se = self.internal_key(key.se);
+      let empty = self.empty_nodes[(level - 2) as usize];
+
+      if nw.nw == empty && nw.ne == empty && nw.sw == empty &&
+        ne.nw == empty && ne.ne == empty && ne.se == empty &&
+        sw.nw == empty && sw.sw == empty && sw.se == empty &&
+        se.ne == empty && se.sw == empty && se.se == empty
+      {
+        root = self.find_node(NodeKey::new_internal(
+          nw.se, ne.sw, sw.ne, se.nw));
+        level -= 1;
+        self.gc_restore(gc_s);
+      } else {
+        break;
+      }
+    }
+    root
+  }
+  /// Removes the cells outside the node of `MAX_LEVEL` centered at the
+  /// center of `root`.
+  fn clamp_level(&mut self, mut root: NodeId) -> NodeId {
+    while self.level(root) > MAX_LEVEL {
+      let key = self.internal_key(root);
+      root = self.find_node(NodeKey::new_internal(
+        self.internal_key(key.nw).se,
+        self.internal_key(key.ne).sw,
+        self.internal_key(key.sw).ne,
+        self.internal_key(key.se).nw,
+      ));
+    }
+    root
+  }
+  /// Copies the pattern under `node` of `from`, which may have a different
+  /// rule. `copies` maps the nodes of `from` to their copies, so that copying
+  /// several patterns shares their common nodes.
+  pub(crate) fn copy_from(
+    &mut self,
+    from: &Store,
+    node: NodeId,
+    copies: &mut FxHashMap<NodeId, NodeId>,
+  ) -> NodeId {
+    if let Some(&copy) = copies.get(&node) {
+      return copy;
+    }
+
+    let key = match from.node(node) {
+      Node::Leaf(LeafNode { key, .. }) => NodeKey::Leaf(*key),
+      Node::Internal(InternalNode { key, .. }) => {
+        let key = *key;
+        NodeKey::new_internal(
+          self.copy_from(from, key.nw, copies),
+          self.copy_from(from, key.ne, copies),
+          self.copy_from(from, key.sw, copies),
+          self.copy_from(from, key.se, copies),
+        )
+      }
+    };
+    let gc_s = self.gc_save();
+    let copy = self.find_node(key);
+    self.gc_restore(gc_s);
+    copies.insert(node, copy);
+    copy
+  }
+
+  pub(crate) fn clear_results(&mut self, k: u16, odd: bool) {
+    if let Some(last_k) = self.last_k {
+      // every result depends on the parity of the generation
+      let min_k = if odd != self.last_odd { 0 } else { last_k.min(k) };
+
+      for node in self.arena.nodes_mut() {
+        if let Node::Internal(node) = node {
+          if min_k < node.level - 2 {
+            node.clear_result();
+          }
+        }
+      }
+    }
+  }
+  fn find_empty_node(&mut self, level: u16) -> NodeId {
+    let len = self.empty_nodes.len() ;
+    if len < level as usize + 1 {
+      let gc_s = self.gc_save();
+      for i in len..=level as usize {
+        let prev = self.empty_nodes[i - 1];
+        let node = self.find_node(NodeKey::new_internal(prev, prev, prev, prev));
+        self.node(node).unwrap_internal_ref().set_result(prev);
+        self.empty_nodes.push(node);
+      }
+      self.gc_restore(gc_s);
+    }
+    self.empty_nodes[level as usize]
+  }
+  // Advance `2 ^ min(k, level - 2)` generations.
+  pub(crate) fn step_rec(
+    &mut self,
+    node: NodeId,
+    k: u16,
+  ) -> Result<NodeId, MemoryLimitExceeded> {
+    let InternalNode { key, level, .. } = *self.node(node).unwrap_internal_ref();
+    let result = self.node(node).unwrap_internal_ref().result();
+    if result != INVALID_NODE_ID {
+      self.arena.memo_hits += 1;
+      return Ok(result);
+    }
+
+    if level == 4 {
+      return self.leaf_step(node, k);
+    }
+
+    let nw = self.internal_key(key.nw);
+    let ne = self.internal_key(key.ne);
+    let sw = self.internal_key(key.sw);
+    let se = self.internal_key(key.se);
+
+    let gc_s = self.gc_save();
+
+    let n0 = self.step_rec(key.nw, k)?;
+    let nn = self.try_find_node(
+      NodeKey::new_internal(nw.ne, ne.nw, nw.se, ne.sw))?;
+    let n1 = self.step_rec(nn, k)?;
+    let n2 = self.step_rec(key.ne, k)?;
+    let ww = self.try_find_node(
+      NodeKey::new_internal(nw.sw, nw.se, sw.nw, sw.ne))?;
+    let n3 = self.step_rec(ww, k)?;
+    let cc = self.try_find_node(
+      NodeKey::new_internal(nw.se, ne.sw, sw.ne, se.nw))?;
+    let n4 = self.step_rec(cc, k)?;
+    let ee = self.try_find_node(
+      NodeKey::new_internal(ne.sw, ne.se, se.nw, se.ne))?;
+    let n5 = self.step_rec(ee, k)?;
+    let n6 = self.step_rec(key.sw, k)?;
+    let ss = self.try_find_node(
+      NodeKey::new_internal(sw.ne, se.nw, sw.se, se.sw))?;
+    let n7 = self.step_rec(ss, k)?;
+    let n8 = self.step_rec(key.se, k)?;
+
+    let nw;
+    let ne;
+    let sw;
+    let se;
+    if k >= level - 2 {
+      let r0 = self.try_find_node(NodeKey::new_internal(n0, n1, n3, n4))?;
+      let r1 = self.try_find_node(NodeKey::new_internal(n1, n2, n4, n5))?;
+      let r2 = self.try_find_node(NodeKey::new_internal(n3, n4, n6, n7))?;
+      let r3 = self.try_find_node(NodeKey::new_internal(n4, n5, n7, n8))?;
+      nw = self.step_rec(r0, k)?;
+      ne = self.step_rec(r1, k)?;
+      sw = self.step_rec(r2, k)?;
+      se = self.step_rec(r3, k)?;
+    } else if level > 5 {
+      let n0 = self.internal_key(n0);
+      let n1 = self.internal_key(n1);
+      let n2 = self.internal_key(n2);
+      let n3 = self.internal_key(n3);
+      let n4 = self.internal_key(n4);
+      let n5 = self.internal_key(n5);
+      let n6 = self.internal_key(n6);
+      let n7 = self.internal_key(n7);
+      let n8 = self.internal_key(n8);
+      nw = self.try_find_node(
+        NodeKey::new_internal(n0.se, n1.sw, n3.ne, n4.nw))?;
+      ne = self.try_find_node(
+        NodeKey::new_internal(n1.se, n2.sw, n4.ne, n5.nw))?;
+      sw = self.try_find_node(
+        NodeKey::new_internal(n3.se, n4.sw, n6.ne, n7.nw))?;
+      se = self.try_find_node(
+        NodeKey::new_internal(n4.se, n5.sw, n7.ne, n8.nw))?;
+    } else {
+      let n0 = self.leaf_key(n0);
+      let n1 = self.leaf_key(n1);
+      let n2 = self.leaf_key(n2);
+      let n3 = self.leaf_key(n3);
+      let n4 = self.leaf_key(n4);
+      let n5 = self.leaf_key(n5);
+      let n6 = self.leaf_key(n6);
+      let n7 = self.leaf_key(n7);
+      let n8 = self.leaf_key(n8);
+      nw = self.try_find_node(NodeKey::new_leaf(n0.se, n1.sw, n3.ne, n4.nw))?;
+      ne = self.try_find_node(NodeKey::new_leaf(n1.se, n2.sw, n4.ne, n5.nw))?;
+      sw = self.try_find_node(NodeKey::new_leaf(n3.se, n4.sw, n6.ne, n7.nw))?;
+      se = self.try_find_node(NodeKey::new_leaf(n4.se, n5.sw, n7.ne, n8.nw))?;
+    }
+
+    self.gc_restore(gc_s);
+    self.gc_root(nw);
+    self.gc_root(ne);
+    self.gc_root(sw);
+    self.gc_root(se);
+
+    let result = self.try_find_node(NodeKey::new_internal(nw, ne, sw, se))?;
+    self.gc_restore(gc_s);
+    self.gc_root(result);
+    self.node(node).unwrap_internal_ref().set_result(result);
+    Ok(result)
+  }
+  fn leaf_step(
+    &mut self,
+    node: NodeId,
+    k: u16,
+  ) -> Result<NodeId, MemoryLimitExceeded> {
+    let key = self.internal_key(node);
+    let nw = *self.node(key.nw).unwrap_leaf_ref();
+    let ne = *self.node(key.ne).unwrap_leaf_ref();
+    let sw = *self.node(key.sw).unwrap_leaf_ref();
+    let se = *self.node(key.se).unwrap_leaf_ref();
+
+    if k == 0 && self.last_odd {
+      let result = compute_level4_result1(
+        &self.level2_results[2], nw.key, ne.key, sw.key, se.key);
+      let result = self.try_find_node(NodeKey::Leaf(result))?;
+      self.node(node).unwrap_internal_ref().set_result(result);
+      return Ok(result);
+    }
+
+    let gc_s = self.gc_save();
+    let quad_result_ix = (k > 0) as usize;
+    let n0 = nw.results[quad_result_ix];
+    let n1 = self.leaf_result(
+      NodeKey::new_leaf(nw.key.ne, ne.key.nw, nw.key.se, ne.key.sw),
+      quad_result_ix)?;
+    let n2 = ne.results[quad_result_ix];
+    let n3 = self.leaf_result(
+      NodeKey::new_leaf(nw.key.sw, nw.key.se, sw.key.nw, sw.key.ne),
+      quad_result_ix)?;
+    let n4 = self.leaf_result(
+      NodeKey::new_leaf(nw.key.se, ne.key.sw, sw.key.ne, se.key.nw),
+      quad_result_ix)?;
+    let n5 = self.leaf_result(
+      NodeKey::new_leaf(ne.key.sw, ne.key.se, se.key.nw, se.key.ne),
+      quad_result_ix)?;
+    let n6 = sw.results[quad_result_ix];
+    let n7 = self.leaf_result(
+      NodeKey::new_leaf(sw.key.ne, se.key.nw, sw.key.se, se.key.sw),
+      quad_result_ix)?;
+    let n8 = se.results[quad_result_ix];
+
+    let nw;
+    let ne;
+    let sw;
+    let se;
+    if k >= 2 {
+      nw = self.leaf_result(NodeKey::new_leaf(n0, n1, n3, n4), 1)?;
+      ne = self.leaf_result(NodeKey::new_leaf(n1, n2, n4, n5), 1)?;
+      sw = self.leaf_result(NodeKey::new_leaf(n3, n4, n6, n7), 1)?;
+      se = self.leaf_result(NodeKey::new_leaf(n4, n5, n7, n8), 1)?;
+    } else {
+      nw = LeafNodeKey { nw: n0, ne: n1, sw: n3, se: n4 }.center();
+      ne = LeafNodeKey { nw: n1, ne: n2, sw: n4, se: n5 }.center();
+      sw = LeafNodeKey { nw: n3, ne: n4, sw: n6, se: n7 }.center();
+      se = LeafNodeKey { nw: n4, ne: n5, sw: n7, se: n8 }.center();
+    }
+    self.gc_restore(gc_s);
+
+    let result = self.try_find_node(NodeKey::new_leaf(nw, ne, sw, se))?;
+    self.node(node).unwrap_internal_ref().set_result(result);
+    Ok(result)
+  }
+  fn leaf_result(
+    &mut self,
+    key: NodeKey,
+    ix: usize,
+  ) -> Result<u16, MemoryLimitExceeded> {
+    let node = self.try_find_node(key)?;
+    Ok(self.node(node).unwrap_leaf_ref().results[ix])
+  }
+  fn gc_root(&mut self, node: NodeId) {
+    self.arena.gc_root(node);
+  }
+  fn gc_save(&mut self) -> usize {
+    self.arena.gc_save()
+  }
+  fn gc_restore(&mut self, s: usize) {
+    self.arena.gc_restore(s);
+  }
+  pub(crate) fn gc(&mut self) {
+    self.arena.gc(gc_roots(&self.empty_nodes, &self.attached));
+  }
+  /// Returns (left, top, right, bottom), where right and bottom are exclusive.
+  pub(crate) fn boundary_rec(&self, node: NodeId, ox: i64, oy: i64) -> Boundary {
+    let level = self.level(node);
+    if self.empty_nodes.len() > level as usize &&
+      node == self.empty_nodes[level as usize]
+    {
+      EMPTY_BOUNDARY
+    } else {
+      match self.node(node) {
+        Node::Leaf(LeafNode { key, .. }) => {
+          let w = key.nw | key.sw;
+          let w = (w >> 8 | w >> 4 | w | w << 4 ) & 0xf0;
+          let e = key.ne | key.se;
+          let e = (e >> 12 | e >> 8 | e >> 4 | e) & 0xf;
+          let row = w | e;
+          let (left, right) = BYTE_RANGE[row as usize];
+
+          let n = key.nw | key.ne;
+          let n = n | n >> 1 | n >> 2 | n >> 3;
+          let s = key.sw | key.se;
+          let s = s | s >> 1 | s >> 2 | s >> 3;
+          let col = n >> 5 & 0x80 | n >> 2 & 0x40 | n << 1 & 0x20 | n << 4 & 0x10 |
+            s >> 9 & 0x8 | s >> 6 & 0x4 | s >> 3 & 0x2 | s & 0x1;
+          let (top, bottom) = BYTE_RANGE[col as usize];
+
+          Boundary {
+            left: left + ox,
+            top: top + oy,
+            right: right + ox,
+            bottom: bottom + oy,
+          }
+        }
+        Node::Internal(InternalNode { key, .. }) => {
+          let r = 1 << (level - 2);
+          let nw_bound = self.boundary_rec(key.nw, ox - r, oy - r);
+          let ne_bound = self.boundary_rec(key.ne, ox + r, oy - r);
+          let sw_bound = self.boundary_rec(key.sw, ox - r, oy + r);
+          let se_bound = self.boundary_rec(key.se, ox + r, oy + r);
+          let left = nw_bound.left.min(ne_bound.left)
+            .min(sw_bound.left)
+            .min(se_bound.left);
+          let right = nw_bound.right.max(ne_bound.right)
+            .max(sw_bound.right)
+            .max(se_bound.right);
+          let top = nw_bound.top.min(ne_bound.top)
+            .min(sw_bound.top)
+            .min(se_bound.top);
+          let bottom = nw_bound.bottom.max(ne_bound.bottom)
+            .max(sw_bound.bottom)
+            .max(se_bound.bottom);
+          Boundary { left, top, right, bottom }
+        }
+      }
+    }
+  }
+  pub(crate) fn write_cells_rec<F>(
+    &self,
+    node: NodeId,
+    viewport: &Boundary,
+    ox: i64,
+    oy: i64,
+    f: &mut F,
+  )
+  where
+    F: FnMut(u16, u16, u16, u16, i64, i64)
+  {
+    let level = self.level(node);
+    if self.empty_nodes.len() > level as usize &&
+      node == self.empty_nodes[level as usize]
+    {
+      return;
+    }
+
+    let r = 1 << (level - 1);
+    let left = ox - r;
+    let top = oy - r;
+    let right = ox + r;
+    let bottom = oy + r;
+    if right <= viewport.left
+      || left >= viewport.right
+      || top >= viewport.bottom
+      || bottom <= viewport.top
+    {
+      return;
+    }
+
+    match self.node(node) {
+      Node::Leaf(node) => {
+        f(node.key.nw, node.key.ne, node.key.sw, node.key.se, left, top);
+      }
+      Node::Internal(node) => {
+        let rr = r >> 1;
+        self.write_cells_rec(node.key.nw, viewport, ox - rr, oy - rr, f);
+        self.write_cells_rec(node.key.ne, viewport, ox + rr, oy - rr, f);
+        self.write_cells_rec(node.key.sw, viewport, ox - rr, oy + rr, f);
+        self.write_cells_rec(node.key.se, viewport, ox + rr, oy + rr, f);
+      }
+    }
+  }
+  #[cfg(test)]
+  pub(crate) fn debug(&self, node: NodeId) -> Vec<u128> {
+    use itertools::Itertools;
+
+    match self.node(node) {
+      Node::Leaf(LeafNode { key, .. }) => {
+        vec![
+          (key.nw >> 8 & 0xf0 | key.ne >> 12 & 0xf) as u128,
+          (key.nw >> 4 & 0xf0 | key.ne >>  8 & 0xf) as u128,
+          (key.nw      & 0xf0 | key.ne >>  4 & 0xf) as u128,
+          (key.nw << 4 & 0xf0 | key.ne       & 0xf) as u128,
+          (key.sw >> 8 & 0xf0 | key.se >> 12 & 0xf) as u128,
+          (key.sw >> 4 & 0xf0 | key.se >>  8 & 0xf) as u128,
+          (key.sw      & 0xf0 | key.se >>  4 & 0xf) as u128,
+          (key.sw << 4 & 0xf0 | key.se       & 0xf) as u128,
+        ]
+      }
+      Node::Internal(InternalNode { key, level, .. }) => {
+        let r = 1 << (level - 1);
+        let nw = self.debug(key.nw);
+        let ne = self.debug(key.ne);
+        let sw = self.debug(key.sw);
+        let se = self.debug(key.se);
+        nw.into_iter().zip(ne)
+          .chain(sw.into_iter().zip(se))
+          .map(|(x, y)| x << r | y)
+          .collect_vec()
+      }
+    }
+  }
+}
+
+/// Nodes that survive every collection: the largest empty node, which keeps
+/// the smaller ones alive, and the nodes of the attached universes.
+fn gc_roots<'a>(
+  empty_nodes: &'a [NodeId],
+  attached: &'a [Option<AttachedRoots>],
+) -> impl Iterator<Item = NodeId> + 'a {
+  empty_nodes.last().copied().into_iter()
+    .chain(attached.iter().flatten().flat_map(|roots| {
+      std::iter::once(roots.root).chain(roots.snapshots.iter().copied())
+    }))
+}
+
+/// Returns a new node of `key`, whose children are returned by `node`.
+pub(crate) fn new_node<'a>(
+  key: NodeKey,
+  node: impl Fn(NodeId) -> &'a Node,
+  level2_results: &[[u8; 65536]; 3],
+) -> Node {
+  match key {
+    NodeKey::Internal(key) => {
+      let level = node(key.nw).level() + 1;
+      let population = node(key.nw).population()
+        .saturating_add(node(key.ne).population())
+        .saturating_add(node(key.sw).population())
+        .saturating_add(node(key.se).population());
+      Node::new_internal(key, level, population)
+    }
+    NodeKey::Leaf(key) => {
+      let results = compute_level3_results(level2_results, key);
+      Node::new_leaf(key, results)
+    }
+  }
+}
+
+/// `level2_results` are the tables of `Rule::level2_results`. The second
+/// generation is computed from 4x4 squares at odd coordinates.
+pub(crate) fn compute_level3_results(
+  level2_results: &[[u8; 65536]; 3],
+  key: LeafNodeKey,
+) -> [u16; 2] {
+  let [r0, r1, r2, r3] = level3_step(&level2_results[0], key);
+
+  let result1_nw = r0 << 5 & 0xcc00;
+  let result1_ne = r1 << 3 & 0x3300;
+  let result1_sw = r2 >> 3 & 0x00cc;
+  let result1_se = r3 >> 5 & 0x0033;
+  let result1 = result1_nw | result1_ne | result1_sw | result1_se;
+
+  let result2_nw = level2_results[1][r0 as usize];
+  let result2_ne = level2_results[1][r1 as usize];
+  let result2_sw = level2_results[1][r2 as usize];
+  let result2_se = level2_results[1][r3 as usize];
+  let result2 = level2_square_from_quadrant(
+    result2_nw, result2_ne, result2_sw, result2_se);
+
+  [result1, result2]
+}
+
+/// Returns the center of a level 4 node after one generation, without
+/// memoizing leaves.
+pub(crate) fn compute_level4_result1(
+  level2_results: &[u8; 65536],
+  nw: LeafNodeKey,
+  ne: LeafNodeKey,
+  sw: LeafNodeKey,
+  se: LeafNodeKey,
+) -> LeafNodeKey {
+  let result1 = |nw, ne, sw, se| {
+    let [r0, r1, r2, r3] =
+      level3_step(level2_results, LeafNodeKey { nw, ne, sw, se });
+    r0 << 5 & 0xcc00 | r1 << 3 & 0x3300 | r2 >> 3 & 0x00cc | r3 >> 5 & 0x0033
+  };
+  let n0 = result1(nw.nw, nw.ne, nw.sw, nw.se);
+  let n1 = result1(nw.ne, ne.nw, nw.se, ne.sw);
+  let n2 = result1(ne.nw, ne.ne, ne.sw, ne.se);
+  let n3 = result1(nw.sw, nw.se, sw.nw, sw.ne);
+  let n4 = result1(nw.se, ne.sw, sw.ne, se.nw);
+  let n5 = result1(ne.sw, ne.se, se.nw, se.ne);
+  let n6 = result1(sw.nw, sw.ne, sw.sw, sw.se);
+  let n7 = result1(sw.ne, se.nw, sw.se, se.sw);
+  let n8 = result1(se.nw, se.ne, se.sw, se.se);
+
+  LeafNodeKey {
+    nw: LeafNodeKey { nw: n0, ne: n1, sw: n3, se: n4 }.center(),
+    ne: LeafNodeKey { nw: n1, ne: n2, sw: n4, se: n5 }.center(),
+    sw: LeafNodeKey { nw: n3, ne: n4, sw: n6, se: n7 }.center(),
+    se: LeafNodeKey { nw: n4, ne: n5, sw: n7, se: n8 }.center(),
+  }
+}
+
+/// Returns the 6x6 center of a leaf after one generation, as four
+/// overlapping 4x4 squares.
+fn level3_step(level2_results: &[u8; 65536], key: LeafNodeKey) -> [u16; 4] {
+  let n0 = level2_results[key.nw as usize];
+  let nn = key.nw << 2 & 0xcccc | key.ne >> 2 & 0x3333;
+  let n1 = level2_results[nn as usize];
+  let n2 = level2_results[key.ne as usize];
+  let ww = key.nw << 8 | key.sw >> 8;
+  let n3 = level2_results[ww as usize];
+  let n4 = level2_results[key.center() as usize];
+  let ee = key.ne << 8 | key.se >> 8;
+  let n5 = level2_results[ee as usize];
+  let n6 = level2_results[key.sw as usize];
+  let ss = key.sw << 2 & 0xcccc | key.se >> 2 & 0x3333;
+  let n7 = level2_results[ss as usize];
+  let n8 = level2_results[key.se as usize];
+
+  [
+    level2_square_from_quadrant(n0, n1, n3, n4),
+    level2_square_from_quadrant(n1, n2, n4, n5),
+    level2_square_from_quadrant(n3, n4, n6, n7),
+    level2_square_from_quadrant(n4, n5, n7, n8),
+  ]
+}
+
+fn level2_square_from_quadrant(
+  nw: u8, ne: u8, sw: u8, se: u8
+) -> u16 {
+  (nw as u16) << 10 | (ne as u16) << 8 | (sw as u16) << 2 | (se as u16)
+}
+
+const EMPTY_BOUNDARY: Boundary = Boundary {
+  left: i64::MAX,
+  top: i64::MAX,
+  right: i64::MIN,
+  bottom: i64::MIN,
+};
+
+const BYTE_RANGE: [(i64, i64); 256] = compute_byte_range();
+
+const fn compute_byte_range() -> [(i64, i64); 256] {
+  let mut result = [(0i64, 0i64); 256];
+  let mut i = 1;
+  result[0] = (i64::MAX, i64::MIN);
+  while i < 256 {
+    let low = (i as u8).leading_zeros() as i64 - 4;
+    let high = 4 - (i as u8).trailing_zeros() as i64;
+    result[i as usize] = (low, high);
+    i += 1;
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_level2_result() {
+    let store = Store::new(GAME_OF_LIFE, GcConfig::default());
+    assert_eq!(store.level2_results[0][0b_0000_0110_1100_0100], 0b0011_0000);
+    assert_eq!(store.level2_results[0][0b_1100_0100_0000_0000], 0b0010_0000);
+  }
+
+  #[test]
+  fn test_level3_result1() {
+    let mut store = Store::new(GAME_OF_LIFE, GcConfig::default());
+    let node = store.find_node(NodeKey::new_leaf(
+      0b_0000_0000_0000_0001,
+      0b_0000_0000_0000_1000,
+      0b_0011_0001_0000_0000,
+      0b_0000_0000_0000_0000,
+    ));
+    let node = store.node(node).unwrap_leaf_ref();
+    assert_eq!(node.results[0], 0b_0000_1110_1000_1100);
+
+    let node = store.find_node(NodeKey::new_leaf(
+      0b_0000_0000_0001_0011,
+      0b_0000_0000_0000_0000,
+      0b_0100_0011_0000_0000,
+      0b_1000_0000_0000_0000,
+    ));
+    let node = store.node(node).unwrap_leaf_ref();
+    assert_eq!(node.results[0], 0b_1100_1110_0010_1100);
+  }
+
+  #[test]
+  fn test_level3_result2() {
+    let mut store = Store::new(GAME_OF_LIFE, GcConfig::default());
+    let node = store.find_node(NodeKey::new_leaf(
+      0b_0000_0000_0000_0001,
+      0b_0000_0000_0000_1000,
+      0b_0011_0001_0000_0000,
+      0b_0000_0000_0000_0000,
+    ));
+    let node = store.node(node).unwrap_leaf_ref();
+    assert_eq!(node.results[1], 0b_0100_1100_0010_1100);
+
+    let node = store.find_node(NodeKey::new_leaf(
+      0b_0000_0000_0000_0000,
+      0b_0000_0000_0000_0000,
+      0b_0000_0000_0001_0000,
+      0b_0100_1100_0010_1100,
+    ));
+    let node = store.node(node).unwrap_leaf_ref();
+    assert_eq!(node.results[1], 0b_0000_0000_0010_0100);
+  }
+
+  #[test]
+  fn test_saturated_population() {
+    let mut store = Store::new(GAME_OF_LIFE, GcConfig::default());
+    let mut node = store.find_node(NodeKey::new_leaf(0xffff, 0xffff, 0xffff, 0xffff));
+    for _ in 0..40 {
+      node = store.find_node(NodeKey::new_internal(node, node, node, node));
+    }
+    assert_eq!(store.node(node).population(), u64::MAX);
+    assert_eq!(store.node_population(node, &mut FxHashMap::default()),
+      BigUint::from(1u32) << 86);
+  }
+
+  #[test]
+  fn test_reuse_freed_ids() {
+    let mut store = Store::new(GAME_OF_LIFE, GcConfig::default());
+    let num_nodes = store.num_nodes();
+    let node = store.find_node(NodeKey::new_leaf(1, 2, 3, 4));
+    store.arena.clear_gc_roots();
+    store.gc();
+    assert_eq!(store.num_nodes(), num_nodes);
+
+    let reused = store.find_node(NodeKey::new_leaf(5, 6, 7, 8));
+    assert_eq!(reused, node);
+    assert_eq!(store.leaf_key(reused), LeafNodeKey { nw: 5, ne: 6, sw: 7, se: 8 });
+  }
+}
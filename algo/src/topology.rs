@@ -0,0 +1,372 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use crate::node::NodeId;
+use crate::store::Store;
+use crate::universe::Boundary;
+
+/// Shape of the grid of a universe.
+///
+/// Bounded grids are centered at the origin like in Golly, i.e. the top left
+/// cell of a `width` x `height` grid is `(-(width / 2), -(height / 2))`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Topology {
+  #[default]
+  Unbounded,
+  /// Cells outside the grid are always dead.
+  Plane { width: u32, height: u32 },
+  /// Opposite edges are joined.
+  Torus { width: u32, height: u32 },
+  /// Opposite edges are joined, and one pair of them with a twist: the top
+  /// and bottom edges if `twisted_top_bottom` (written `K100*,80`), otherwise
+  /// the left and right edges (`K100,80*`).
+  KleinBottle { width: u32, height: u32, twisted_top_bottom: bool },
+  /// Both pairs of opposite edges are joined with a twist.
+  CrossSurface { width: u32, height: u32 },
+}
+
+impl Topology {
+  pub fn is_bounded(&self) -> bool {
+    *self != Topology::Unbounded
+  }
+
+  fn size(&self) -> Option<(i64, i64)> {
+    match *self {
+      Topology::Unbounded => None,
+      Topology::Plane { width, height }
+      | Topology::Torus { width, height }
+      | Topology::KleinBottle { width, height, .. }
+      | Topology::CrossSurface { width, height } => {
+        Some((width as i64, height as i64))
+      }
+    }
+  }
+
+  /// Returns the cells of a bounded grid.
+  pub fn grid(&self) -> Option<Boundary> {
+    let (width, height) = self.size()?;
+    let left = -(width / 2);
+    let top = -(height / 2);
+    Some(Boundary { left, top, right: left + width, bottom: top + height })
+  }
+
+  /// The largest `k` such that `2 ^ k` generations can be simulated at once.
+  ///
+  /// Before a step, the grid is surrounded by copies of its edges, which are
+  /// exact for `min(width, height)` generations on a torus or a Klein bottle,
+  /// because both are tiled by copies of the grid. A cross-surface is not,
+  /// so it is simulated one generation at a time, and so is a plane.
+  pub(crate) fn max_step_exponent(&self) -> u16 {
+    match *self {
+      Topology::Unbounded => u16::MAX,
+      Topology::Torus { width, height }
+      | Topology::KleinBottle { width, height, .. } => {
+        31 - width.min(height).leading_zeros() as u16
+      }
+      Topology::Plane { .. } | Topology::CrossSurface { .. } => 0,
+    }
+  }
+
+  /// Maps the cell `(x, y)` of the grid to its copy in the neighboring tile
+  /// `(tx, ty)`, where `tx` and `ty` are -1, 0 or 1.
+  fn image(&self, grid: &Boundary, x: i64, y: i64, tx: i64, ty: i64) -> (i64, i64) {
+    let (flip_x, flip_y) = match *self {
+      Topology::KleinBottle { twisted_top_bottom: true, .. } => (ty != 0, false),
+      Topology::KleinBottle { twisted_top_bottom: false, .. } => (false, tx != 0),
+      Topology::CrossSurface { .. } => (ty != 0, tx != 0),
+      _ => (false, false),
+    };
+    let x = if flip_x { grid.left + grid.right - 1 - x } else { x };
+    let y = if flip_y { grid.top + grid.bottom - 1 - y } else { y };
+    (x + tx * (grid.right - grid.left), y + ty * (grid.bottom - grid.top))
+  }
+
+  /// Surrounds the grid of the pattern under `root` with `border` cells wide
+  /// copies of its edges.
+  pub(crate) fn pad(&self, store: &mut Store, mut root: NodeId, border: i64) -> NodeId {
+    let grid = match self.grid() {
+      Some(grid) => grid,
+      None => return root,
+    };
+    if let Topology::Plane { .. } = self {
+      return root;
+    }
+
+    let strips = [
+      Boundary {
+        left: grid.left,
+        top: grid.top,
+        right: grid.right,
+        bottom: grid.top + border,
+      },
+      Boundary {
+        left: grid.left,
+        top: grid.bottom - border,
+        right: grid.right,
+        bottom: grid.bottom,
+      },
+      Boundary {
+        left: grid.left,
+        top: grid.top,
+        right: grid.left + border,
+        bottom: grid.bottom,
+      },
+      Boundary {
+        left: grid.right - border,
+        top: grid.top,
+        right: grid.right,
+        bottom: grid.bottom,
+      },
+    ];
+    let mut cells = vec![];
+    for strip in &strips {
+      store.write_cells_rec(root, strip, 0, 0, &mut |nw, ne, sw, se, x0, y0| {
+        for (bits, qx, qy) in [(nw, x0, y0), (ne, x0 + 4, y0), (sw, x0, y0 + 4), (se, x0 + 4, y0 + 4)] {
+          let mut bits = bits;
+          while bits != 0 {
+            let i = bits.trailing_zeros() as i64;
+            bits &= bits - 1;
+            let x = qx + 3 - (i & 3);
+            let y = qy + 3 - (i >> 2);
+            if x >= strip.left && x < strip.right && y >= strip.top && y < strip.bottom {
+              cells.push((x, y));
+            }
+          }
+        }
+      });
+    }
+
+    let padded = Boundary {
+      left: grid.left - border,
+      top: grid.top - border,
+      right: grid.right + border,
+      bottom: grid.bottom + border,
+    };
+    for (x, y) in cells {
+      for ty in -1..=1 {
+        for tx in -1..=1 {
+          if tx == 0 && ty == 0 {
+            continue;
+          }
+          let (x, y) = self.image(&grid, x, y, tx, ty);
+          if x >= padded.left && x < padded.right && y >= padded.top && y < padded.bottom {
+            root = store.set_cell(root, x, y, true);
+          }
+        }
+      }
+    }
+    root
+  }
+}
+
+impl Display for Topology {
+  /// Formats in the Golly notation, e.g. `T100,80`. Unbounded topology is an
+  /// empty string.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Topology::Unbounded => Ok(()),
+      Topology::Plane { width, height } => write!(f, "P{},{}", width, height),
+      Topology::Torus { width, height } => write!(f, "T{},{}", width, height),
+      Topology::KleinBottle { width, height, twisted_top_bottom: true } => {
+        write!(f, "K{}*,{}", width, height)
+      }
+      Topology::KleinBottle { width, height, twisted_top_bottom: false } => {
+        write!(f, "K{},{}*", width, height)
+      }
+      Topology::CrossSurface { width, height } => {
+        write!(f, "C{},{}", width, height)
+      }
+    }
+  }
+}
+
+impl FromStr for Topology {
+  type Err = String;
+
+  /// Parses the Golly notation, such as `T100,80`, `P100,80`, `K100*,80` and
+  /// `C100,80`. A single size, such as `T100`, is a square grid.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s.is_empty() {
+      return Ok(Topology::Unbounded);
+    }
+
+    let mut chars = s.chars();
+    let kind = chars.next().unwrap().to_ascii_uppercase();
+    let mut sizes = chars.as_str().splitn(2, ',');
+    let (width, width_twisted) = parse_size(sizes.next().unwrap())?;
+    let (height, height_twisted) = match sizes.next() {
+      Some(height) => parse_size(height)?,
+      None => (width, false),
+    };
+    if kind != 'K' && (width_twisted || height_twisted) {
+      return Err(format!("unexpected twist in topology {:?}", s));
+    }
+
+    match kind {
+      'P' => Ok(Topology::Plane { width, height }),
+      'T' => Ok(Topology::Torus { width, height }),
+      'K' if width_twisted != height_twisted => Ok(Topology::KleinBottle {
+        width,
+        height,
+        twisted_top_bottom: width_twisted,
+      }),
+      'K' => Err(format!("Klein bottle needs exactly one twist {:?}", s)),
+      'C' => Ok(Topology::CrossSurface { width, height }),
+      _ => Err(format!("unsupported topology {:?}", s)),
+    }
+  }
+}
+
+/// Parses a size with an optional twist suffix `*`.
+fn parse_size(s: &str) -> Result<(u32, bool), String> {
+  let (size, twisted) = match s.strip_suffix('*') {
+    Some(size) => (size, true),
+    None => (s, false),
+  };
+  match size.parse() {
+    Ok(0) => Err("unbounded dimensions are not supported".to_owned()),
+    Ok(size) => Ok((size, twisted)),
+    Err(_) => Err(format!("invalid grid size {:?}", s)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::rule::GAME_OF_LIFE;
+  use crate::universe::Universe;
+
+  #[test]
+  fn test_parse() {
+    let cases = [
+      ("T100,80", Topology::Torus { width: 100, height: 80 }),
+      ("P7", Topology::Plane { width: 7, height: 7 }),
+      ("K10*,8", Topology::KleinBottle {
+        width: 10,
+        height: 8,
+        twisted_top_bottom: true,
+      }),
+      ("K10,8*", Topology::KleinBottle {
+        width: 10,
+        height: 8,
+        twisted_top_bottom: false,
+      }),
+      ("C5,6", Topology::CrossSurface { width: 5, height: 6 }),
+    ];
+    for &(s, topology) in &cases {
+      assert_eq!(s.parse::<Topology>(), Ok(topology));
+      assert_eq!(topology.to_string(), s.replace("P7", "P7,7"));
+    }
+    assert_eq!("t3,4".parse(), Ok(Topology::Torus { width: 3, height: 4 }));
+    assert!("T0,80".parse::<Topology>().is_err());
+    assert!("K10,8".parse::<Topology>().is_err());
+    assert!("T10*,8".parse::<Topology>().is_err());
+    assert!("S10".parse::<Topology>().is_err());
+    assert!("\u{e9}5".parse::<Topology>().is_err());
+    assert!("T\u{e9}".parse::<Topology>().is_err());
+  }
+
+  /// Steps `cells` one generation by brute force.
+  fn naive_step(topology: Topology, cells: &[Vec<bool>]) -> Vec<Vec<bool>> {
+    let grid = topology.grid().unwrap();
+    let h = cells.len() as i64;
+    let w = cells[0].len() as i64;
+    let get = |x: i64, y: i64| -> bool {
+      let tx = x.div_euclid(w);
+      let ty = y.div_euclid(h);
+      if tx == 0 && ty == 0 {
+        return cells[y as usize][x as usize];
+      }
+      if let Topology::Plane { .. } = topology {
+        return false;
+      }
+      // the inverse of an image is the image in the opposite tile
+      let (x, y) = topology.image(&grid,
+        x - tx * w + grid.left, y - ty * h + grid.top, -tx, -ty);
+      let (x, y) = (x + tx * w - grid.left, y + ty * h - grid.top);
+      cells[y as usize][x as usize]
+    };
+
+    (0..h).map(|y| (0..w).map(|x| {
+      let mut n = 0;
+      for dy in -1..=1 {
+        for dx in -1..=1 {
+          if (dx != 0 || dy != 0) && get(x + dx, y + dy) {
+            n += 1;
+          }
+        }
+      }
+      n == 3 || n == 2 && cells[y as usize][x as usize]
+    }).collect()).collect()
+  }
+
+  fn check_topology(topology: Topology) {
+    let grid = topology.grid().unwrap();
+    let w = (grid.right - grid.left) as usize;
+    let h = (grid.bottom - grid.top) as usize;
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    uni.set_topology(topology);
+    let mut cells = vec![vec![false; w]; h];
+    let mut seed = 12345u32;
+    for (y, row) in cells.iter_mut().enumerate() {
+      for (x, cell) in row.iter_mut().enumerate() {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        *cell = seed >> 16 & 3 == 0;
+        uni.set(grid.left + x as i64, grid.top + y as i64, *cell);
+      }
+    }
+
+    // cells outside the grid are ignored
+    uni.set(grid.right, grid.bottom, true);
+
+    for &num_gen in &[1, 2, 5, 16, 40] {
+      uni.simulate(num_gen).unwrap();
+      for _ in 0..num_gen {
+        cells = naive_step(topology, &cells);
+      }
+      for (y, row) in cells.iter().enumerate() {
+        for (x, &cell) in row.iter().enumerate() {
+          assert_eq!(uni.get(grid.left + x as i64, grid.top + y as i64),
+            cell, "{} ({}, {})", topology, x, y);
+        }
+      }
+      let boundary = uni.boundary();
+      assert!(boundary.left >= grid.left && boundary.right <= grid.right);
+      assert!(boundary.top >= grid.top && boundary.bottom <= grid.bottom);
+    }
+  }
+
+  #[test]
+  fn test_bounded_grids() {
+    check_topology(Topology::Plane { width: 13, height: 10 });
+    check_topology(Topology::Torus { width: 13, height: 10 });
+    check_topology(Topology::Torus { width: 32, height: 40 });
+    check_topology(Topology::KleinBottle {
+      width: 13,
+      height: 10,
+      twisted_top_bottom: true,
+    });
+    check_topology(Topology::KleinBottle {
+      width: 9,
+      height: 16,
+      twisted_top_bottom: false,
+    });
+    check_topology(Topology::CrossSurface { width: 11, height: 12 });
+  }
+
+  #[test]
+  fn test_glider_on_torus() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    uni.set_topology(Topology::Torus { width: 8, height: 8 });
+    for &(x, y) in &[(1, 0), (2, 1), (0, 2), (1, 2), (2, 2)] {
+      uni.set(x, y, true);
+    }
+    let grid = uni.topology().grid().unwrap();
+    let gen0 = uni.get_rect(&grid);
+
+    uni.simulate(16).unwrap();
+    assert_ne!(uni.get_rect(&grid), gen0);
+    uni.set_step(2, 4);
+    uni.step().unwrap();
+    assert_eq!(uni.get_rect(&grid), gen0);
+  }
+}
@@ -1,749 +1,684 @@
-use indexmap::IndexSet;
-use rustc_hash::FxHasher;
-use std::hash::BuildHasherDefault;
+use num_bigint::BigUint;
+use num_traits::{One, ToPrimitive, Zero};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rustc_hash::FxHashMap;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
 use crate::node::*;
 use crate::rule::*;
+use crate::store::{MemoryLimitExceeded, NodeStore, Stats};
+use crate::topology::Topology;
 
 pub struct Universe {
-  set: IndexSet<Box<Node>, BuildHasherDefault<FxHasher>>,
-  root: NodeId,
-  empty_nodes: Vec<NodeId>,
-  /// result is a 2x2 square, whose cells are arranged as follows
-  /// ```ignored
-  /// bit 7 ...  5  4  3  2  1  0
-  ///     -  -  NW NE  -  - SW SE
-  /// ```
-  level2_results: [u8; 65536],
-  rule: Rule,
-  last_k: Option<u16>,
-  gc_roots: Vec<NodeId>,
-  gc_threshold: usize,
+  store: NodeStore,
+  /// Id of this universe in the store.
+  id: usize,
+  topology: Topology,
+  generation: BigUint,
+  step_base: u32,
+  step_exponent: u32,
+  hyperspeed: bool,
+  undo_history: VecDeque<Snapshot>,
+  redo_history: Vec<Snapshot>,
+  history_limit: usize,
+  timeline: BTreeMap<BigUint, NodeId>,
+  timeline_interval: Option<u64>,
+  thread_pool: Option<ThreadPool>,
 }
 
-const INITIAL_GC_THRESHOLD: usize = 30000;
-/// percentage
-const GC_THRESHOLD_INCREMENT: usize = 160;
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+/// Largest `k` of a step of `2 ^ k` generations, whose root has the largest
+/// level `k + 3` that fits in `u16`.
+const MAX_STEP_POW2: u32 = u16::MAX as u32 - 3;
+
+/// A saved state of the universe. Nodes are immutable, so the root is enough
+/// to restore the pattern.
+struct Snapshot {
+  root: NodeId,
+  generation: BigUint,
+}
 
 impl Universe {
+  /// Creates an empty universe whose grid is the topology of `rule`.
   pub fn new(rule: Rule) -> Self {
-    let level2_results = compute_level2_results(rule);
-    let mut uni = Self {
-      set: IndexSet::default(),
-      root: INVALID_NODE_ID,
-      empty_nodes: vec![INVALID_NODE_ID; 4],
-      level2_results,
-      rule,
-      last_k: None,
-      gc_roots: vec![],
-      gc_threshold: INITIAL_GC_THRESHOLD,
-    };
-
-    let root = uni.find_node(NodeKey::new_leaf(0, 0, 0, 0));
-    uni.root = root;
-    uni.empty_nodes[3] = root;
-    uni.gc_roots.clear();
+    let mut uni = Self::with_store(&NodeStore::new(rule));
+    uni.set_topology(rule.topology());
     uni
   }
 
+  /// Creates an empty universe whose nodes are stored in `store`.
+  pub fn with_store(store: &NodeStore) -> Self {
+    let id = store.borrow_mut().attach();
+    Self {
+      store: store.clone(),
+      id,
+      topology: Topology::Unbounded,
+      generation: BigUint::zero(),
+      step_base: 2,
+      step_exponent: 0,
+      hyperspeed: false,
+      undo_history: VecDeque::new(),
+      redo_history: vec![],
+      history_limit: DEFAULT_HISTORY_LIMIT,
+      timeline: BTreeMap::new(),
+      timeline_interval: None,
+      thread_pool: None,
+    }
+  }
+
+  pub fn store(&self) -> &NodeStore {
+    &self.store
+  }
+
+  /// Returns the statistics of the store, which are shared by all universes
+  /// of the store.
+  pub fn stats(&self) -> Stats {
+    self.store.stats()
+  }
+
+  pub(crate) fn root(&self) -> NodeId {
+    self.store.borrow().root(self.id)
+  }
+
   pub(crate) fn level(&self) -> u16 {
-    node_ref(self.root).level()
+    let store = self.store.borrow();
+    store.level(store.root(self.id))
   }
 
-  pub fn rule(&self) -> &Rule {
-    &self.rule
+  /// Returns the rule with the topology of this universe. The rest of the
+  /// rule is shared by all universes of the store.
+  pub fn rule(&self) -> Rule {
+    let mut rule = self.store.rule();
+    rule.set_topology(self.topology);
+    rule
   }
 
-  /// `num_gen` is number of generations.
-  pub fn simulate(&mut self, mut num_gen: usize) {
-    if num_gen == 0 {
-      return;
+  /// Changes the rule, keeping the pattern and the saved states. The grid
+  /// changes to the topology of the rule, as by
+  /// [`set_topology`](Self::set_topology).
+  ///
+  /// Unless the rule is already the rule of the store, the universe moves to
+  /// a new store of the rule with the same garbage collection settings, so
+  /// the other universes of the old store keep their rule and results.
+  ///
+  /// Fails if the background of the current generation, which is alive in
+  /// some rules with B0, would be different in the new rule.
+  pub fn set_rule(&mut self, rule: Rule) -> Result<(), String> {
+    let odd = !(&self.generation % 2u32).is_zero();
+    if rule.is_stored_inverted(odd) != self.background() {
+      return Err(format!("the background of {} cannot change to that of {}",
+        self.rule(), rule));
     }
+    let mut unbounded = rule;
+    unbounded.set_topology(Topology::Unbounded);
+    if unbounded != self.store.rule() {
+      self.move_to_store(NodeStore::with_config(rule, self.store.gc_config()));
+    }
+    self.set_topology(rule.topology());
+    self.truncate_timeline();
+    Ok(())
+  }
 
-    loop {
-      let k = num_gen.trailing_zeros() as u16;
-      self.clear_results(k);
-
-      if self.set.len() >= self.gc_threshold {
-        self.gc();
+  /// Copies the pattern and the saved states to `store`, and detaches from
+  /// the current store.
+  fn move_to_store(&mut self, store: NodeStore) {
+    let id = {
+      let old = self.store.borrow();
+      let mut new = store.borrow_mut();
+      let mut copies = FxHashMap::default();
+      let id = new.attach();
+      let root = new.copy_from(&old, old.root(self.id), &mut copies);
+      new.set_root(id, root);
+      for snapshot in self.undo_history.iter_mut().chain(&mut self.redo_history) {
+        snapshot.root = new.copy_from(&old, snapshot.root, &mut copies);
       }
+      for root in self.timeline.values_mut() {
+        *root = new.copy_from(&old, *root, &mut copies);
+      }
+      id
+    };
+    self.store.borrow_mut().detach(self.id);
+    self.store = store;
+    self.id = id;
+    self.publish_snapshots();
+  }
 
-      // preserve enough empty space
-      self.expand();
-      self.expand();
+  /// Whether the cells outside the pattern are alive, which happens on some
+  /// generations of rules with B0. The cells are stored inverted then.
+  pub fn background(&self) -> bool {
+    let odd = !(&self.generation % 2u32).is_zero();
+    self.rule().is_stored_inverted(odd)
+  }
 
-      // we need to advance `2 ^ min(k, level - 3)` generations, instead of
-      // `2 ^ min(k, level - 2)` generations, because the latter can cause the
-      // leakage of information of the RESULT macro-cell.
-      while node_ref(self.root).level() < 4.max(k + 3) {
-        self.expand();
-      }
+  pub fn topology(&self) -> &Topology {
+    &self.topology
+  }
 
-      num_gen &= num_gen - 1;
-      self.root = self.step(self.root, k);
+  /// Sets the shape of the grid. Cells outside a bounded grid are cleared.
+  pub fn set_topology(&mut self, topology: Topology) {
+    self.topology = topology;
+    if let Some(grid) = topology.grid() {
+      let mut store = self.store.borrow_mut();
+      let root = store.root(self.id);
+      let root = store.crop(root, &grid);
+      store.set_root(self.id, root);
+      drop(store);
+      self.truncate_timeline();
+    }
+  }
 
-      self.shrink();
+  /// Number of generations the pattern has advanced.
+  pub fn generation(&self) -> &BigUint {
+    &self.generation
+  }
 
-      self.last_k = Some(k);
+  pub fn set_generation(&mut self, generation: BigUint) {
+    self.generation = generation;
+  }
 
-      if num_gen == 0 {
-        break;
-      }
+  /// `num_gen` is number of generations.
+  ///
+  /// If the memory limit of the store is exceeded, the universe stays at the
+  /// last generation that could be reached.
+  ///
+  /// Cells that move `2 ^ 61` or more cells away from the origin are removed,
+  /// since cells are addressed by `i64`.
+  pub fn simulate(
+    &mut self,
+    mut num_gen: usize,
+  ) -> Result<(), MemoryLimitExceeded> {
+    while num_gen != 0 {
+      let k = num_gen.trailing_zeros() as u16;
+      num_gen &= num_gen - 1;
+      self.simulate_pow2(k)?;
     }
+    Ok(())
   }
 
-  fn find_node(&mut self, key: NodeKey) -> NodeId {
-    match self.set.get(&key) {
-      Some(node) => {
-        NodeId(&**node as *const Node as u64)
-      }
-      None => {
-        let node = match key {
-          NodeKey::Internal(key) => {
-            Box::new(Node::new_internal(key))
-          }
-          NodeKey::Leaf(key) => {
-            let results = self.compute_level3_results(key.clone());
-            Box::new(Node::new_leaf(key, results))
-          }
-        };
-        let id = NodeId(&*node as *const Node as u64);
-        self.gc_root(id);
-        self.set.insert(node);
-        id
-      }
-    }
+  /// Sets the step size of [`step`](Self::step) to `base ^ exponent`
+  /// generations.
+  ///
+  /// The exponent is clamped to [`max_step_exponent`](Self::max_step_exponent).
+  pub fn set_step(&mut self, base: u32, exponent: u32) {
+    assert!(base >= 2, "step base must be at least 2");
+    self.step_base = base;
+    self.step_exponent = exponent.min(self.max_step_exponent());
   }
 
-  fn compute_level3_results(&self, key: LeafNodeKey) -> [u16; 2] {
-    let n0 = self.level2_results[key.nw as usize];
-    let nn = key.nw << 2 & 0xcccc | key.ne >> 2 & 0x3333;
-    let n1 = self.level2_results[nn as usize];
-    let n2 = self.level2_results[key.ne as usize];
-    let ww = key.nw << 8 | key.sw >> 8;
-    let n3 = self.level2_results[ww as usize];
-    let n4 = self.level2_results[key.center() as usize];
-    let ee = key.ne << 8 | key.se >> 8;
-    let n5 = self.level2_results[ee as usize];
-    let n6 = self.level2_results[key.sw as usize];
-    let ss = key.sw << 2 & 0xcccc | key.se >> 2 & 0x3333;
-    let n7 = self.level2_results[ss as usize];
-    let n8 = self.level2_results[key.se as usize];
-
-    fn level2_square_from_quadrant(
-      nw: u8, ne: u8, sw: u8, se: u8
-    ) -> u16 {
-      (nw as u16) << 10 | (ne as u16) << 8 | (sw as u16) << 2 | (se as u16)
-    }
+  /// Returns the largest step exponent of the step base, for which a step is
+  /// at most `2 ^ 65532` generations.
+  pub fn max_step_exponent(&self) -> u32 {
+    // `base ^ exponent < 2 ^ (exponent * bits)`
+    let bits = u32::BITS - (self.step_base - 1).leading_zeros();
+    MAX_STEP_POW2 / bits
+  }
 
-    let r0 = level2_square_from_quadrant(n0, n1, n3, n4);
-    let r1 = level2_square_from_quadrant(n1, n2, n4, n5);
-    let r2 = level2_square_from_quadrant(n3, n4, n6, n7);
-    let r3 = level2_square_from_quadrant(n4, n5, n7, n8);
+  pub fn step_base(&self) -> u32 {
+    self.step_base
+  }
 
-    let result1_nw = r0 << 5 & 0xcc00;
-    let result1_ne = r1 << 3 & 0x3300;
-    let result1_sw = r2 >> 3 & 0x00cc;
-    let result1_se = r3 >> 5 & 0x0033;
-    let result1 = result1_nw | result1_ne | result1_sw | result1_se;
+  pub fn step_exponent(&self) -> u32 {
+    self.step_exponent
+  }
 
-    let result2_nw = self.level2_results[r0 as usize];
-    let result2_ne = self.level2_results[r1 as usize];
-    let result2_sw = self.level2_results[r2 as usize];
-    let result2_se = self.level2_results[r3 as usize];
-    let result2 = level2_square_from_quadrant(
-      result2_nw, result2_ne, result2_sw, result2_se);
+  /// In hyperspeed mode, the step exponent is raised automatically as the
+  /// pattern grows.
+  pub fn set_hyperspeed(&mut self, hyperspeed: bool) {
+    self.hyperspeed = hyperspeed;
+  }
 
-    [result1, result2]
+  pub fn hyperspeed(&self) -> bool {
+    self.hyperspeed
   }
 
-  pub fn set(&mut self, x: i64, y: i64, alive: bool) {
-    let mut radius = 1 << node_ref(self.root).level() - 1;
-    while x < -radius || x >= radius ||
-      y < -radius || y >= radius
-    {
-      self.expand();
-      radius <<= 1;
-    }
+  /// Steps each generation with `num_threads` threads, which share the
+  /// memoized results of the store. This pays off only for large chaotic
+  /// patterns. 0 or 1 thread disables parallel mode.
+  pub fn set_threads(&mut self, num_threads: usize) {
+    self.thread_pool = if num_threads > 1 {
+      let pool = ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .expect("failed to create thread pool");
+      Some(pool)
+    } else {
+      None
+    };
+  }
 
-    let root = self.set_rec(self.root, x, y, alive);
-    self.root = root;
+  pub fn threads(&self) -> usize {
+    self.thread_pool.as_ref().map_or(1, ThreadPool::current_num_threads)
   }
 
-  /// `(x, y)` are coordinate relative to center of the node.
-  fn set_rec(
-    &mut self,
-    node: NodeId,
-    x: i64,
-    y: i64,
-    alive: bool
-  ) -> NodeId {
-    match node_ref(node) {
-      Node::Leaf(LeafNode { key, .. }) => {
-        debug_assert!(x >= -4 && x < 4 && y >= -4 && y < 4);
-        let mut new_key = key.clone();
-        let bits = if x < 0 {
-          if y < 0 {
-            &mut new_key.nw
-          } else {
-            &mut new_key.sw
-          }
-        } else {
-          if y < 0 {
-            &mut new_key.ne
-          } else {
-            &mut new_key.se
-          }
-        };
-        let mask = 1u16 << (3 - (x & 3)) + 4 * (3 - (y & 3));
-        if alive {
-          *bits |= mask;
-        } else {
-          *bits &= !mask;
-        }
+  /// Advances `step_base ^ step_exponent` generations.
+  ///
+  /// If the base is a power of two, the whole step is done by a single
+  /// macro-cell step with a fixed `k`, so the memoized results stay valid
+  /// across calls.
+  pub fn step(&mut self) -> Result<(), MemoryLimitExceeded> {
+    if self.step_base.is_power_of_two() {
+      let k = self.step_base.trailing_zeros().checked_mul(self.step_exponent)
+        .and_then(|k| u16::try_from(k).ok())
+        .expect("step exponent exceeds the maximum");
+      self.simulate_pow2(k)?;
+    } else {
+      let num_gen = BigUint::from(self.step_base).pow(self.step_exponent);
+      self.simulate_big(num_gen)?;
+    }
 
-        self.find_node(NodeKey::Leaf(new_key))
+    if self.hyperspeed {
+      // a step of `2 ^ (level - 3)` generations fits in the root
+      let max_step = BigUint::one() << (self.level() - 3) as usize;
+      let max_exponent = self.max_step_exponent();
+      let base = BigUint::from(self.step_base);
+      let mut next_step = base.pow(self.step_exponent + 1);
+      while next_step <= max_step && self.step_exponent < max_exponent {
+        self.step_exponent += 1;
+        next_step *= &base;
       }
-      Node::Internal(InternalNode { key, level, .. }) => {
-        let gc_s = self.gc_save();
-        let r = 1i64 << level - 2;
-        let mut new_key = key.clone();
-        if y < 0 {
-          if x < 0 {
-            new_key.nw = self.set_rec(key.nw, x + r, y + r, alive);
-          } else {
-            new_key.ne = self.set_rec(key.ne, x - r, y + r, alive);
-          }
-        } else {
-          if x < 0 {
-            new_key.sw = self.set_rec(key.sw, x + r, y - r, alive);
-          } else {
-            new_key.se = self.set_rec(key.se, x - r, y - r, alive);
-          }
-        }
+    }
+    Ok(())
+  }
 
-        let new_node = self.find_node(NodeKey::Internal(new_key));
-        self.gc_restore(gc_s);
-        self.gc_root(new_node);
-        new_node
-      }
+  fn simulate_big(
+    &mut self,
+    mut num_gen: BigUint,
+  ) -> Result<(), MemoryLimitExceeded> {
+    while let Some(k) = num_gen.trailing_zeros() {
+      num_gen -= BigUint::one() << k as usize;
+      self.simulate_pow2(k as u16)?;
     }
+    Ok(())
   }
 
-  fn expand(&mut self) {
-    let gc_s = self.gc_save();
-    let nw;
-    let ne;
-    let sw;
-    let se;
-    match node_ref(self.root) {
-      Node::Leaf(LeafNode { key, .. }) => {
-        nw = self.find_node(NodeKey::Leaf(LeafNodeKey {
-          se: key.nw,
-          ..Default::default()
-        }));
-        ne = self.find_node(NodeKey::Leaf(LeafNodeKey {
-          sw: key.ne,
-          ..Default::default()
-        }));
-        sw = self.find_node(NodeKey::Leaf(LeafNodeKey {
-          ne: key.sw,
-          ..Default::default()
-        }));
-        se = self.find_node(NodeKey::Leaf(LeafNodeKey {
-          nw: key.se,
-          ..Default::default()
-        }));
+  /// Advances `2 ^ k` generations.
+  fn simulate_pow2(&mut self, k: u16) -> Result<(), MemoryLimitExceeded> {
+    if let Some(interval) = self.timeline_interval {
+      // split the step so that every recorded generation is visited
+      let to_next = interval - (&self.generation % interval).to_u64().unwrap();
+      if k > 0 && (k >= 64 || to_next < 1 << k) {
+        self.simulate_pow2(k - 1)?;
+        return self.simulate_pow2(k - 1);
       }
-      Node::Internal(InternalNode { key, level, .. }) => {
-        let empty = self.find_empty_node(level - 1);
-        nw = self.find_node(NodeKey::Internal(InternalNodeKey {
-          nw: empty,
-          ne: empty,
-          sw: empty,
-          se: key.nw,
-        }));
-        ne = self.find_node(NodeKey::Internal(InternalNodeKey {
-          nw: empty,
-          ne: empty,
-          sw: key.ne,
-          se: empty,
-        }));
-        sw = self.find_node(NodeKey::Internal(InternalNodeKey {
-          nw: empty,
-          ne: key.sw,
-          sw: empty,
-          se: empty,
-        }));
-        se = self.find_node(NodeKey::Internal(InternalNodeKey {
-          nw: key.se,
-          ne: empty,
-          sw: empty,
-          se: empty,
-        }));
+    }
+    if k > self.topology.max_step_exponent() {
+      self.simulate_pow2(k - 1)?;
+      return self.simulate_pow2(k - 1);
+    }
+    let odd = !(&self.generation % 2u32).is_zero();
+    if k > 0 && odd && self.rule().is_alternating() {
+      // multi-generation steps must start at even generations
+      self.simulate_pow2(0)?;
+      for j in (1..k).rev() {
+        self.simulate_pow2(j)?;
       }
+      return self.simulate_pow2(0);
     }
-    self.root = self.find_node(NodeKey::new_internal(nw, ne, sw, se));
-    self.gc_restore(gc_s);
-  }
 
-  fn shrink(&mut self) {
-    let mut level = node_ref(self.root).level();
-    if self.empty_nodes.len() <= (level - 2) as usize {
-      return;
+    let mut store = self.store.borrow_mut();
+    let mut root = store.root(self.id);
+    let grid = self.topology.grid();
+    if grid.is_some() {
+      // the edges of a grid affect cells up to `2 ^ k` cells away
+      root = self.topology.pad(&mut store, root, 1 << k);
+    }
+    root = store.simulate(root, k, odd, self.thread_pool.as_ref())?;
+    if let Some(grid) = grid {
+      root = store.crop(root, &grid);
     }
+    store.set_root(self.id, root);
+    drop(store);
 
-    let gc_s = self.gc_save();
-    while level > 4 {
-      let root = node_ref(self.root).unwrap_internal_ref();
-      let nw = node_ref(root.key.nw).unwrap_internal_ref();
-      let ne = node_ref(root.key.ne).unwrap_internal_ref();
-      let sw = node_ref(root.key.sw).unwrap_internal_ref();
-      let se = node_ref(root.key.se).unwrap_internal_ref();
-      let empty = self.empty_nodes[(level - 2) as usize];
-
-      if nw.key.nw == empty && nw.key.ne == empty && nw.key.sw == empty &&
-        ne.key.nw == empty && ne.key.ne == empty && ne.key.se == empty &&
-        sw.key.nw == empty && sw.key.sw == empty && sw.key.se == empty &&
-        se.key.ne == empty && se.key.sw == empty && se.key.se == empty
-      {
-        self.root = self.find_node(NodeKey::new_internal(
-          nw.key.se, ne.key.sw, sw.key.ne, se.key.nw));
-        level -= 1;
-        self.gc_restore(gc_s);
-      } else {
-        break;
+    self.generation += BigUint::one() << k as usize;
+
+    if let Some(interval) = self.timeline_interval {
+      if (&self.generation % interval).is_zero() {
+        self.record(root);
       }
     }
+    Ok(())
   }
 
-  fn clear_results(&self, k: u16) {
-    if let Some(last_k) = self.last_k {
-      let min_k = last_k.min(k);
-
-      for node in &self.set {
-        let level = node.level();
-        if level >= 4 && min_k < level - 2 {
-          node.unwrap_internal_ref().result.set(INVALID_NODE_ID);
-        }
-      }
+  /// Records `root` as the root of the current generation, which may have
+  /// been recorded before a seek.
+  fn record(&mut self, root: NodeId) {
+    match self.timeline.insert(self.generation.clone(), root) {
+      None => self.store.borrow_mut().attached_roots(self.id).snapshots.push(root),
+      // the pattern was edited after seeking
+      Some(old) if old != root => self.publish_snapshots(),
+      Some(_) => {}
     }
   }
 
-  fn find_empty_node(&mut self, level: u16) -> NodeId {
-    let len = self.empty_nodes.len() ;
-    if len < level as usize + 1 {
-      let gc_s = self.gc_save();
-      for i in len..=level as usize {
-        let prev = self.empty_nodes[i - 1];
-        let node = self.find_node(NodeKey::new_internal(prev, prev, prev, prev));
-        match node_ref(node) {
-          Node::Internal(InternalNode { result, .. }) => {
-            result.set(prev);
-          }
-          _ => unreachable!(),
-        }
-        self.empty_nodes.push(node);
-      }
-      self.gc_restore(gc_s);
+  /// Discards the recorded generations after the current one, which no
+  /// longer follow from the edited pattern, and records the current one again
+  /// if it was recorded.
+  fn truncate_timeline(&mut self) {
+    if self.timeline.keys().next_back() > Some(&self.generation) {
+      self.timeline.split_off(&(&self.generation + 1u32));
+      self.publish_snapshots();
+    }
+    if self.timeline.contains_key(&self.generation) {
+      self.record(self.root());
     }
-    self.empty_nodes[level as usize]
   }
 
-  // Advance `2 ^ min(k, level - 2)` generations.
-  fn step(&mut self, node: NodeId, k: u16) -> NodeId {
-    let node = node_ref(node).unwrap_internal_ref();
-    let result = node.result.get();
-    if result != INVALID_NODE_ID {
-      return result;
+  /// Starts recording the root of every `interval`-th generation (and the
+  /// current generation) while simulating, so that they can be revisited by
+  /// [`seek`](Self::seek).
+  ///
+  /// Recorded nodes are shared with each other, so recording many generations
+  /// costs little memory.
+  pub fn record_timeline(&mut self, interval: u64) {
+    assert!(interval > 0, "timeline interval must be positive");
+    self.timeline_interval = Some(interval);
+    self.timeline.insert(self.generation.clone(), self.root());
+    self.publish_snapshots();
+  }
+
+  /// Stops recording. Already recorded generations are kept.
+  pub fn stop_recording(&mut self) {
+    self.timeline_interval = None;
+  }
+
+  pub fn timeline_interval(&self) -> Option<u64> {
+    self.timeline_interval
+  }
+
+  /// Discards all recorded generations.
+  pub fn clear_timeline(&mut self) {
+    self.timeline.clear();
+    self.publish_snapshots();
+  }
+
+  /// Jumps to `generation` by restoring the nearest recorded generation
+  /// before it and simulating forward from there.
+  ///
+  /// Returns `false` if no generation before `generation` was recorded.
+  pub fn seek(
+    &mut self,
+    generation: &BigUint,
+  ) -> Result<bool, MemoryLimitExceeded> {
+    let (recorded, root) = match self.timeline.range(..=generation).next_back() {
+      Some((recorded, &root)) => (recorded.clone(), root),
+      None => return Ok(false),
+    };
+
+    self.store.borrow_mut().set_root(self.id, root);
+    self.generation = recorded;
+    let num_gen = generation - &self.generation;
+    self.simulate_big(num_gen)?;
+    Ok(true)
+  }
+
+  /// Saves the current state, which can be restored by [`undo`](Self::undo).
+  ///
+  /// Discards the states that can be restored by [`redo`](Self::redo).
+  pub fn checkpoint(&mut self) {
+    let snapshot = self.snapshot();
+    self.undo_history.push_back(snapshot);
+    if self.undo_history.len() > self.history_limit {
+      self.undo_history.pop_front();
     }
+    self.redo_history.clear();
+    self.publish_snapshots();
+  }
 
-    let level = node.level;
-    if level == 4 {
-      return self.leaf_step(node, k);
+  /// Restores the state saved by the last [`checkpoint`](Self::checkpoint).
+  ///
+  /// Returns `false` if there is no such state.
+  pub fn undo(&mut self) -> bool {
+    match self.undo_history.pop_back() {
+      Some(snapshot) => {
+        let current = self.snapshot();
+        self.redo_history.push(current);
+        self.restore(snapshot);
+        self.publish_snapshots();
+        true
+      }
+      None => false,
     }
+  }
 
-    let nw = node_ref(node.key.nw).unwrap_internal_ref();
-    let ne = node_ref(node.key.ne).unwrap_internal_ref();
-    let sw = node_ref(node.key.sw).unwrap_internal_ref();
-    let se = node_ref(node.key.se).unwrap_internal_ref();
-
-    let gc_s = self.gc_save();
-
-    let n0 = self.step(node.key.nw, k);
-    let nn = self.find_node(NodeKey::new_internal(
-      nw.key.ne, ne.key.nw, nw.key.se, ne.key.sw
-    ));
-    let n1 = self.step(nn, k);
-    let n2 = self.step(node.key.ne, k);
-    let ww = self.find_node(NodeKey::new_internal(
-      nw.key.sw, nw.key.se, sw.key.nw, sw.key.ne
-    ));
-    let n3 = self.step(ww, k);
-    let cc = self.find_node(NodeKey::new_internal(
-      nw.key.se, ne.key.sw, sw.key.ne, se.key.nw
-    ));
-    let n4 = self.step(cc, k);
-    let ee = self.find_node(NodeKey::new_internal(
-      ne.key.sw, ne.key.se, se.key.nw, se.key.ne
-    ));
-    let n5 = self.step(ee, k);
-    let n6 = self.step(node.key.sw, k);
-    let ss = self.find_node(NodeKey::new_internal(
-      sw.key.ne, se.key.nw, sw.key.se, se.key.sw
-    ));
-    let n7 = self.step(ss, k);
-    let n8 = self.step(node.key.se, k);
-
-    let nw;
-    let ne;
-    let sw;
-    let se;
-    if k >= level - 2 {
-      let r0 = self.find_node(NodeKey::new_internal(n0, n1, n3, n4));
-      let r1 = self.find_node(NodeKey::new_internal(n1, n2, n4, n5));
-      let r2 = self.find_node(NodeKey::new_internal(n3, n4, n6, n7));
-      let r3 = self.find_node(NodeKey::new_internal(n4, n5, n7, n8));
-      nw = self.step(r0, k);
-      ne = self.step(r1, k);
-      sw = self.step(r2, k);
-      se = self.step(r3, k);
-    } else {
-      match node_ref(n0) {
-        Node::Internal(n0) => {
-          let n1 = node_ref(n1).unwrap_internal_ref();
-          let n2 = node_ref(n2).unwrap_internal_ref();
-          let n3 = node_ref(n3).unwrap_internal_ref();
-          let n4 = node_ref(n4).unwrap_internal_ref();
-          let n5 = node_ref(n5).unwrap_internal_ref();
-          let n6 = node_ref(n6).unwrap_internal_ref();
-          let n7 = node_ref(n7).unwrap_internal_ref();
-          let n8 = node_ref(n8).unwrap_internal_ref();
-          nw = self.find_node(NodeKey::new_internal(
-            n0.key.se, n1.key.sw, n3.key.ne, n4.key.nw
-          ));
-          ne = self.find_node(NodeKey::new_internal(
-            n1.key.se, n2.key.sw, n4.key.ne, n5.key.nw
-          ));
-          sw = self.find_node(NodeKey::new_internal(
-            n3.key.se, n4.key.sw, n6.key.ne, n7.key.nw
-          ));
-          se = self.find_node(NodeKey::new_internal(
-            n4.key.se, n5.key.sw, n7.key.ne, n8.key.nw
-          ));
-        }
-        Node::Leaf(n0) => {
-          let n1 = node_ref(n1).unwrap_leaf_ref();
-          let n2 = node_ref(n2).unwrap_leaf_ref();
-          let n3 = node_ref(n3).unwrap_leaf_ref();
-          let n4 = node_ref(n4).unwrap_leaf_ref();
-          let n5 = node_ref(n5).unwrap_leaf_ref();
-          let n6 = node_ref(n6).unwrap_leaf_ref();
-          let n7 = node_ref(n7).unwrap_leaf_ref();
-          let n8 = node_ref(n8).unwrap_leaf_ref();
-          nw = self.find_node(NodeKey::new_leaf(
-            n0.key.se, n1.key.sw, n3.key.ne, n4.key.nw
-          ));
-          ne = self.find_node(NodeKey::new_leaf(
-            n1.key.se, n2.key.sw, n4.key.ne, n5.key.nw
-          ));
-          sw = self.find_node(NodeKey::new_leaf(
-            n3.key.se, n4.key.sw, n6.key.ne, n7.key.nw
-          ));
-          se = self.find_node(NodeKey::new_leaf(
-            n4.key.se, n5.key.sw, n7.key.ne, n8.key.nw
-          ));
-        }
+  /// Restores the state before the last [`undo`](Self::undo).
+  ///
+  /// Returns `false` if there is no such state.
+  pub fn redo(&mut self) -> bool {
+    match self.redo_history.pop() {
+      Some(snapshot) => {
+        let current = self.snapshot();
+        self.undo_history.push_back(current);
+        self.restore(snapshot);
+        self.publish_snapshots();
+        true
       }
+      None => false,
     }
+  }
 
-    self.gc_restore(gc_s);
-    self.gc_root(nw);
-    self.gc_root(ne);
-    self.gc_root(sw);
-    self.gc_root(se);
-
-    let result = self.find_node(NodeKey::new_internal(nw, ne, sw, se));
-    self.gc_restore(gc_s);
-    self.gc_root(result);
-    node.result.set(result);
-    result
-  }
-
-  fn leaf_step(&mut self, node: &InternalNode, k: u16) -> NodeId {
-    let nw = node_ref(node.key.nw).unwrap_leaf_ref();
-    let ne = node_ref(node.key.ne).unwrap_leaf_ref();
-    let sw = node_ref(node.key.sw).unwrap_leaf_ref();
-    let se = node_ref(node.key.se).unwrap_leaf_ref();
-
-    let quad_result_ix = (k > 0) as usize;
-    let n0 = nw.results[quad_result_ix];
-    let n1 = node_ref(self.find_node(NodeKey::new_leaf(
-      nw.key.ne, ne.key.nw, nw.key.se, ne.key.sw)))
-      .unwrap_leaf_ref()
-      .results[quad_result_ix];
-    let n2 = ne.results[quad_result_ix];
-    let n3 = node_ref(self.find_node(NodeKey::new_leaf(
-      nw.key.sw, nw.key.se, sw.key.nw, sw.key.ne)))
-      .unwrap_leaf_ref()
-      .results[quad_result_ix];
-    let n4 = node_ref(self.find_node(NodeKey::new_leaf(
-      nw.key.se, ne.key.sw, sw.key.ne, se.key.nw)))
-      .unwrap_leaf_ref()
-      .results[quad_result_ix];
-    let n5 = node_ref(self.find_node(NodeKey::new_leaf(
-      ne.key.sw, ne.key.se, se.key.nw, se.key.ne)))
-      .unwrap_leaf_ref()
-      .results[quad_result_ix];
-    let n6 = sw.results[quad_result_ix];
-    let n7 = node_ref(self.find_node(NodeKey::new_leaf(
-      sw.key.ne, se.key.nw, sw.key.se, se.key.sw)))
-      .unwrap_leaf_ref()
-      .results[quad_result_ix];
-    let n8 = se.results[quad_result_ix];
-
-    let nw;
-    let ne;
-    let sw;
-    let se;
-    if k >= 2 {
-      nw = node_ref(self.find_node(NodeKey::new_leaf(n0, n1, n3, n4)))
-        .unwrap_leaf_ref()
-        .results[1];
-      ne = node_ref(self.find_node(NodeKey::new_leaf(n1, n2, n4, n5)))
-        .unwrap_leaf_ref()
-        .results[1];
-      sw = node_ref(self.find_node(NodeKey::new_leaf(n3, n4, n6, n7)))
-        .unwrap_leaf_ref()
-        .results[1];
-      se = node_ref(self.find_node(NodeKey::new_leaf(n4, n5, n7, n8)))
-        .unwrap_leaf_ref()
-        .results[1];
-    } else {
-      nw = LeafNodeKey { nw: n0, ne: n1, sw: n3, se: n4 }.center();
-      ne = LeafNodeKey { nw: n1, ne: n2, sw: n4, se: n5 }.center();
-      sw = LeafNodeKey { nw: n3, ne: n4, sw: n6, se: n7 }.center();
-      se = LeafNodeKey { nw: n4, ne: n5, sw: n7, se: n8 }.center();
+  /// Sets the maximum number of states that can be restored by
+  /// [`undo`](Self::undo). The oldest states are discarded first.
+  pub fn set_history_limit(&mut self, limit: usize) {
+    self.history_limit = limit;
+    while self.undo_history.len() > limit {
+      self.undo_history.pop_front();
     }
+    self.publish_snapshots();
+  }
 
-    let result = self.find_node(NodeKey::new_leaf(nw, ne, sw, se));
-    node.result.set(result);
-    result
+  pub fn history_limit(&self) -> usize {
+    self.history_limit
+  }
+
+  fn snapshot(&self) -> Snapshot {
+    Snapshot {
+      root: self.root(),
+      generation: self.generation.clone(),
+    }
   }
 
-  fn gc_root(&mut self, node: NodeId) {
-    self.gc_roots.push(node);
+  fn restore(&mut self, snapshot: Snapshot) {
+    self.store.borrow_mut().set_root(self.id, snapshot.root);
+    self.generation = snapshot.generation;
   }
 
-  fn gc_save(&mut self) -> usize {
-    self.gc_roots.len()
+  /// Lets the store know which saved states must be kept alive.
+  fn publish_snapshots(&self) {
+    let snapshots = self.undo_history.iter()
+      .chain(&self.redo_history)
+      .map(|snapshot| snapshot.root)
+      .chain(self.timeline.values().copied())
+      .collect();
+    self.store.borrow_mut().attached_roots(self.id).snapshots = snapshots;
   }
 
-  fn gc_restore(&mut self, s: usize) {
-    self.gc_roots.truncate(s);
+  fn is_in_grid(&self, x: i64, y: i64) -> bool {
+    match self.topology.grid() {
+      Some(grid) =>
+        x >= grid.left && x < grid.right && y >= grid.top && y < grid.bottom,
+      None => true,
+    }
   }
 
-  fn gc(&mut self) {
-    self.gc_mark();
-    self.gc_sweep();
-    self.gc_threshold = self.set.len() * GC_THRESHOLD_INCREMENT / 100 + 1;
+  /// Sets the cell at `(x, y)`. Cells outside a bounded grid, or `2 ^ 61` or
+  /// more cells away from the origin, are ignored.
+  pub fn set(&mut self, x: i64, y: i64, alive: bool) {
+    if !self.is_in_grid(x, y) || !is_addressable(x, y) {
+      return;
+    }
+
+    let background = self.background();
+    let mut store = self.store.borrow_mut();
+    let root = store.root(self.id);
+    let root = store.set_cell(root, x, y, alive != background);
+    store.set_root(self.id, root);
+    drop(store);
+    self.truncate_timeline();
   }
 
-  fn gc_mark(&mut self) {
-    let gc_s = self.gc_save();
-    self.gc_roots.push(self.root);
-    self.gc_roots.push(*self.empty_nodes.last().unwrap());
+  /// Returns whether the cell at `(x, y)` is alive. Cells outside a bounded
+  /// grid are dead.
+  pub fn get(&self, x: i64, y: i64) -> bool {
+    if !self.is_in_grid(x, y) {
+      return false;
+    }
 
-    let mut i = 0;
-    while i < self.gc_roots.len() {
-      let node = node_ref(self.gc_roots[i]);
-      if node.mark().get() {
-        i += 1;
-        continue;
+    let background = self.background();
+    // every cell is inside a root whose radius overflows
+    let level = self.level() as u32;
+    if let Some(radius) = 1i64.checked_shl(level - 1).filter(|&r| r > 0) {
+      if x < -radius || x >= radius || y < -radius || y >= radius {
+        return background;
       }
+    }
 
-      match node {
-        Node::Internal(node) => {
-          node.mark.set(true);
-          self.gc_roots.push(node.key.nw);
-          self.gc_roots.push(node.key.ne);
-          self.gc_roots.push(node.key.sw);
-          self.gc_roots.push(node.key.se);
-          if node.result.get() != INVALID_NODE_ID {
-            self.gc_roots.push(node.result.get());
-          }
+    let store = self.store.borrow();
+    store.get_rec(store.root(self.id), x, y) != background
+  }
+
+  /// Returns the cells inside `rect` as a row-major bitmap.
+  ///
+  /// Each row occupies `(width + 7) / 8` bytes. The most significant bit of
+  /// the first byte of a row is the leftmost cell of that row.
+  pub fn get_rect(&self, rect: &Boundary) -> Vec<u8> {
+    let w = (rect.right - rect.left).max(0);
+    let h = (rect.bottom - rect.top).max(0);
+    let bw = ((w + 7) >> 3) as usize;
+    let mut buffer = vec![0u8; bw * h as usize];
+
+    self.write_cells(rect, |nw, ne, sw, se, x0, y0| {
+      for dy in 0..8 {
+        let y = y0 + dy;
+        if y < rect.top || y >= rect.bottom {
+          continue;
         }
-        Node::Leaf(node) => {
-          node.mark.set(true);
+        let (west, east) = if dy < 4 { (nw, ne) } else { (sw, se) };
+        for dx in 0..8 {
+          let x = x0 + dx;
+          if x < rect.left || x >= rect.right {
+            continue;
+          }
+          let bits = if dx < 4 { west } else { east };
+          let mask = 1u16 << ((3 - (dx & 3)) + 4 * (3 - (dy & 3)));
+          if bits & mask != 0 {
+            let i = (x - rect.left) as usize;
+            buffer[(y - rect.top) as usize * bw + (i >> 3)] |= 0x80 >> (i & 7);
+          }
         }
       }
-    }
+    });
 
-    self.gc_restore(gc_s);
+    buffer
   }
 
-  fn gc_sweep(&mut self) {
-    let mut i = 0;
-    while i < self.set.len() {
-      let node = &self.set[i];
-      if node.mark().get() {
-        node.mark().set(false);
-        i += 1;
-      } else {
-        self.set.swap_remove_index(i);
-      }
+  /// Returns the number of alive cells. If the background is alive, only the
+  /// cells inside the boundary are counted.
+  pub fn population(&self) -> BigUint {
+    if self.background() {
+      return self.population_in(&self.boundary());
     }
+
+    let store = self.store.borrow();
+    store.node_population(store.root(self.id), &mut FxHashMap::default())
   }
 
-  pub fn boundary(&self) -> Boundary {
-    self.boundary_rec(self.root, 0, 0)
+  /// Returns the number of alive cells inside `rect`.
+  pub fn population_in(&self, rect: &Boundary) -> BigUint {
+    let store = self.store.borrow();
+    let population = store.population_in_rec(
+      store.root(self.id), rect, 0, 0, &mut FxHashMap::default());
+    if !self.background() {
+      return population;
+    }
+
+    // the stored cells are the dead ones
+    let rect = match self.topology.grid() {
+      Some(grid) => rect.intersection(&grid),
+      None => *rect,
+    };
+    rect.area() - population
   }
 
-  /// Returns (left, top, right, bottom), where right and bottom are exclusive.
-  fn boundary_rec(&self, node: NodeId, ox: i64, oy: i64) -> Boundary {
-    let level = node_ref(node).level();
-    if self.empty_nodes.len() > level as usize &&
-      node == self.empty_nodes[level as usize]
-    {
-      EMPTY_BOUNDARY
-    } else {
-      match node_ref(node) {
-        Node::Leaf(LeafNode { key, .. }) => {
-          let w = key.nw | key.sw;
-          let w = (w >> 8 | w >> 4 | w | w << 4 ) & 0xf0;
-          let e = key.ne | key.se;
-          let e = (e >> 12 | e >> 8 | e >> 4 | e) & 0xf;
-          let row = w | e;
-          let (left, right) = BYTE_RANGE[row as usize];
-
-          let n = key.nw | key.ne;
-          let n = n | n >> 1 | n >> 2 | n >> 3;
-          let s = key.sw | key.se;
-          let s = s | s >> 1 | s >> 2 | s >> 3;
-          let col = n >> 5 & 0x80 | n >> 2 & 0x40 | n << 1 & 0x20 | n << 4 & 0x10 |
-            s >> 9 & 0x8 | s >> 6 & 0x4 | s >> 3 & 0x2 | s & 0x1;
-          let (top, bottom) = BYTE_RANGE[col as usize];
-
-          Boundary {
-            left: left + ox,
-            top: top + oy,
-            right: right + ox,
-            bottom: bottom + oy,
-          }
-        }
-        Node::Internal(InternalNode { key, .. }) => {
-          let r = 1 << level - 2;
-          let nw_bound = self.boundary_rec(key.nw, ox - r, oy - r);
-          let ne_bound = self.boundary_rec(key.ne, ox + r, oy - r);
-          let sw_bound = self.boundary_rec(key.sw, ox - r, oy + r);
-          let se_bound = self.boundary_rec(key.se, ox + r, oy + r);
-          let left = nw_bound.left.min(ne_bound.left)
-            .min(sw_bound.left)
-            .min(se_bound.left);
-          let right = nw_bound.right.max(ne_bound.right)
-            .max(sw_bound.right)
-            .max(se_bound.right);
-          let top = nw_bound.top.min(ne_bound.top)
-            .min(sw_bound.top)
-            .min(se_bound.top);
-          let bottom = nw_bound.bottom.max(ne_bound.bottom)
-            .max(sw_bound.bottom)
-            .max(se_bound.bottom);
-          Boundary { left, top, right, bottom }
-        }
+  /// Returns the boundary of the alive cells. If the background is alive,
+  /// this is the grid if it is bounded, or the boundary of the dead cells.
+  pub fn boundary(&self) -> Boundary {
+    if self.background() {
+      if let Some(grid) = self.topology.grid() {
+        return grid;
       }
     }
-  }
 
-  pub(crate) fn write_cells<F>(&self, viewport: &Boundary, mut f: F)
-  where
-    F: FnMut(u16, u16, u16, u16, i64, i64)
-  {
-    self.write_cells_rec(self.root, viewport, 0, 0, &mut f);
+    let store = self.store.borrow();
+    store.boundary_rec(store.root(self.id), 0, 0)
   }
 
-  fn write_cells_rec<F>(
-    &self,
-    node: NodeId,
-    viewport: &Boundary,
-    ox: i64,
-    oy: i64,
-    f: &mut F,
-  )
+  /// Calls `f` with the quadrants and the top left corner of 8x8 blocks that
+  /// cover the alive cells inside `viewport`.
+  pub(crate) fn write_cells<F>(&self, viewport: &Boundary, mut f: F)
   where
     F: FnMut(u16, u16, u16, u16, i64, i64)
   {
-    let level = node_ref(node).level();
-    if self.empty_nodes.len() > level as usize &&
-      node == self.empty_nodes[level as usize]
-    {
+    let store = self.store.borrow();
+    let root = store.root(self.id);
+    if !self.background() {
+      store.write_cells_rec(root, viewport, 0, 0, &mut f);
       return;
     }
 
-    let r = 1 << level - 1;
-    let left = ox - r;
-    let top = oy - r;
-    let right = ox + r;
-    let bottom = oy + r;
-    if right <= viewport.left
-      || left >= viewport.right
-      || top >= viewport.bottom
-      || bottom <= viewport.top
-    {
+    // every block is written, with the stored cells inverted
+    let mut blocks = FxHashMap::default();
+    store.write_cells_rec(root, viewport, 0, 0, &mut |nw, ne, sw, se, x0, y0| {
+      blocks.insert((x0, y0), [nw, ne, sw, se]);
+    });
+    let grid = self.topology.grid();
+    let viewport = match grid {
+      Some(grid) => viewport.intersection(&grid),
+      None => *viewport,
+    };
+    if viewport.left >= viewport.right || viewport.top >= viewport.bottom {
       return;
     }
-
-    match node_ref(node) {
-      Node::Leaf(node) => {
-        f(node.key.nw, node.key.ne, node.key.sw, node.key.se, left, top);
-      }
-      Node::Internal(node) => {
-        let rr = r >> 1;
-        self.write_cells_rec(node.key.nw, viewport, ox - rr, oy - rr, f);
-        self.write_cells_rec(node.key.ne, viewport, ox + rr, oy - rr, f);
-        self.write_cells_rec(node.key.sw, viewport, ox - rr, oy + rr, f);
-        self.write_cells_rec(node.key.se, viewport, ox + rr, oy + rr, f);
+    // the only leaf of a root of level 3 is centered on the origin
+    let offset = if store.level(root) == 3 { 4 } else { 0 };
+    let align = |v: i64| (v + offset).div_euclid(8) * 8 - offset;
+    let mask = |x0: i64, y0: i64| match grid {
+      Some(grid) => (0..16).filter(|i| {
+        let x = x0 + 3 - (i & 3);
+        let y = y0 + 3 - (i >> 2);
+        x >= grid.left && x < grid.right && y >= grid.top && y < grid.bottom
+      }).fold(0, |mask, i| mask | 1u16 << i),
+      None => u16::MAX,
+    };
+    for y0 in (align(viewport.top)..viewport.bottom).step_by(8) {
+      for x0 in (align(viewport.left)..viewport.right).step_by(8) {
+        let [nw, ne, sw, se] = blocks.get(&(x0, y0)).copied().unwrap_or_default();
+        f(
+          !nw & mask(x0, y0),
+          !ne & mask(x0 + 4, y0),
+          !sw & mask(x0, y0 + 4),
+          !se & mask(x0 + 4, y0 + 4),
+          x0,
+          y0,
+        );
       }
     }
   }
 
   #[cfg(test)]
   pub fn debug_root(&self) -> Vec<u128> {
-    self.debug(self.root)
+    let store = self.store.borrow();
+    store.debug(store.root(self.id))
   }
+}
 
-  #[cfg(test)]
-  pub(crate) fn debug(&self, node: NodeId) -> Vec<u128> {
-    use itertools::Itertools;
-
-    match node_ref(node) {
-      Node::Leaf(LeafNode { key, .. }) => {
-        vec![
-          (key.nw >> 8 & 0xf0 | key.ne >> 12 & 0xf) as u128,
-          (key.nw >> 4 & 0xf0 | key.ne >>  8 & 0xf) as u128,
-          (key.nw >> 0 & 0xf0 | key.ne >>  4 & 0xf) as u128,
-          (key.nw << 4 & 0xf0 | key.ne >>  0 & 0xf) as u128,
-          (key.sw >> 8 & 0xf0 | key.se >> 12 & 0xf) as u128,
-          (key.sw >> 4 & 0xf0 | key.se >>  8 & 0xf) as u128,
-          (key.sw >> 0 & 0xf0 | key.se >>  4 & 0xf) as u128,
-          (key.sw << 4 & 0xf0 | key.se >>  0 & 0xf) as u128,
-        ]
-      }
-      Node::Internal(InternalNode { key, level, .. }) => {
-        let r = 1 << level - 1;
-        let nw = self.debug(key.nw);
-        let ne = self.debug(key.ne);
-        let sw = self.debug(key.sw);
-        let se = self.debug(key.se);
-        nw.into_iter().zip(ne)
-          .chain(sw.into_iter().zip(se))
-          .map(|(x, y)| x << r | y)
-          .collect_vec()
-      }
-    }
+impl Drop for Universe {
+  fn drop(&mut self) {
+    self.store.borrow_mut().detach(self.id);
   }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Boundary {
   pub left: i64,
   pub top: i64,
@@ -753,31 +688,28 @@ pub struct Boundary {
   pub bottom: i64,
 }
 
-const EMPTY_BOUNDARY: Boundary = Boundary {
-  left: i64::MAX,
-  top: i64::MAX,
-  right: i64::MIN,
-  bottom: i64::MIN,
-};
-
-const BYTE_RANGE: [(i64, i64); 256] = compute_byte_range();
-
-const fn compute_byte_range() -> [(i64, i64); 256] {
-  let mut result = [(0i64, 0i64); 256];
-  let mut i = 1;
-  result[0] = (i64::MAX, i64::MIN);
-  while i < 256 {
-    let low = (i as u8).leading_zeros() as i64 - 4;
-    let high = 4 - (i as u8).trailing_zeros() as i64;
-    result[i as usize] = (low, high);
-    i += 1;
-  }
-  result
+impl Boundary {
+  pub(crate) fn intersection(&self, other: &Boundary) -> Boundary {
+    Boundary {
+      left: self.left.max(other.left),
+      top: self.top.max(other.top),
+      right: self.right.min(other.right),
+      bottom: self.bottom.min(other.bottom),
+    }
+  }
+
+  /// Number of cells.
+  pub(crate) fn area(&self) -> BigUint {
+    let w = self.right.saturating_sub(self.left).max(0) as u64;
+    let h = self.bottom.saturating_sub(self.top).max(0) as u64;
+    BigUint::from(w) * h
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::store::GcConfig;
 
   #[test]
   fn test_debug_level3() {
@@ -868,57 +800,6 @@ mod tests {
     });
   }
 
-  #[test]
-  fn test_level2_result() {
-    let uni = Universe::new(GAME_OF_LIFE);
-    assert_eq!(uni.level2_results[0b_0000_0110_1100_0100], 0b_11_0000);
-    assert_eq!(uni.level2_results[0b_1100_0100_0000_0000], 0b_10_0000);
-  }
-
-  #[test]
-  fn test_level3_result1() {
-    let mut uni = Universe::new(GAME_OF_LIFE);
-    let node = uni.find_node(NodeKey::new_leaf(
-      0b_0000_0000_0000_0001,
-      0b_0000_0000_0000_1000,
-      0b_0011_0001_0000_0000,
-      0b_0000_0000_0000_0000,
-    ));
-    let node = node_ref(node).unwrap_leaf_ref();
-    assert_eq!(node.results[0], 0b_0000_1110_1000_1100);
-
-    let node = uni.find_node(NodeKey::new_leaf(
-      0b_0000_0000_0001_0011,
-      0b_0000_0000_0000_0000,
-      0b_0100_0011_0000_0000,
-      0b_1000_0000_0000_0000,
-    ));
-    let node = node_ref(node).unwrap_leaf_ref();
-    assert_eq!(node.results[0], 0b_1100_1110_0010_1100);
-  }
-
-  #[test]
-  fn test_level3_result2() {
-    let mut uni = Universe::new(GAME_OF_LIFE);
-    let node = uni.find_node(NodeKey::new_leaf(
-      0b_0000_0000_0000_0001,
-      0b_0000_0000_0000_1000,
-      0b_0011_0001_0000_0000,
-      0b_0000_0000_0000_0000,
-    ));
-    let node = node_ref(node).unwrap_leaf_ref();
-    assert_eq!(node.results[1], 0b_0100_1100_0010_1100);
-
-    let node = uni.find_node(NodeKey::new_leaf(
-      0b_0000_0000_0000_0000,
-      0b_0000_0000_0000_0000,
-      0b_0000_0000_0001_0000,
-      0b_0100_1100_0010_1100,
-    ));
-    let node = node_ref(node).unwrap_leaf_ref();
-    assert_eq!(node.results[1], 0b_0000_0000_0010_0100);
-  }
-
   #[test]
   fn test_level4_result1() {
     let mut uni = Universe::new(GAME_OF_LIFE);
@@ -927,9 +808,11 @@ mod tests {
     uni.set(-2, 0, true);
     uni.set(-1, 0, true);
     uni.set(-1, 1, true);
-    uni.expand();
-    let node = uni.step(uni.root, 0);
-    assert_eq!(uni.debug(node),
+    let root = uni.root();
+    let mut store = uni.store.borrow_mut();
+    let root = store.expand(root);
+    let node = store.step_rec(root, 0).unwrap();
+    assert_eq!(store.debug(node),
       vec![
         0b_0000_0000,
         0b_0000_0000,
@@ -950,9 +833,11 @@ mod tests {
     uni.set(-2, 0, true);
     uni.set(-1, 0, true);
     uni.set(-1, 1, true);
-    uni.expand();
-    let node = uni.step(uni.root, 1);
-    assert_eq!(uni.debug(node),
+    let root = uni.root();
+    let mut store = uni.store.borrow_mut();
+    let root = store.expand(root);
+    let node = store.step_rec(root, 1).unwrap();
+    assert_eq!(store.debug(node),
       vec![
         0b_0000_0000,
         0b_0000_0000,
@@ -973,9 +858,11 @@ mod tests {
     uni.set(-2, 0, true);
     uni.set(-1, 0, true);
     uni.set(-1, 1, true);
-    uni.expand();
-    let node = uni.step(uni.root, 2);
-    assert_eq!(uni.debug(node),
+    let root = uni.root();
+    let mut store = uni.store.borrow_mut();
+    let root = store.expand(root);
+    let node = store.step_rec(root, 2).unwrap();
+    assert_eq!(store.debug(node),
       vec![
         0b_0000_0000,
         0b_0000_0000,
@@ -1000,11 +887,13 @@ mod tests {
     uni.set(-3, 0, true);
     uni.set(-2, 1, true);
     uni.set(-1, 1, true);
-    uni.expand();
-    uni.expand();
-    uni.root = uni.step(uni.root, 2);
-    uni.shrink();
-    assert_eq!(uni.debug_root(),
+    let root = uni.root();
+    let mut store = uni.store.borrow_mut();
+    let root = store.expand(root);
+    let root = store.expand(root);
+    let root = store.step_rec(root, 2).unwrap();
+    let root = store.shrink(root);
+    assert_eq!(store.debug(root),
       vec![
         0b_0000_0000_0000_0000,
         0b_0000_0000_0000_0000,
@@ -1033,7 +922,7 @@ mod tests {
     uni.set(-2, 0, true);
     uni.set(-1, 0, true);
     uni.set(-1, 1, true);
-    uni.simulate(2);
+    uni.simulate(2).unwrap();
     assert_eq!(uni.debug_root(), vec![
       0b_0000_0000_0000_0000,
       0b_0000_0000_0000_0000,
@@ -1064,7 +953,7 @@ mod tests {
     uni.set(-1, 0, true);
     uni.set(-1, 1, true);
     uni.set(-1, 2, true);
-    uni.simulate(7);
+    uni.simulate(7).unwrap();
     assert_eq!(uni.debug_root(), vec![
       0b_0000_0000_0000_0000,
       0b_0000_0000_0000_0000,
@@ -1095,7 +984,7 @@ mod tests {
     uni.set(-1, 0, true);
     uni.set(-1, 1, true);
     uni.set(-1, 2, true);
-    uni.simulate(8);
+    uni.simulate(8).unwrap();
     assert_eq!(uni.debug_root(), vec![
       0b_0000_0000_0000_0000,
       0b_0000_0000_0000_0000,
@@ -1126,7 +1015,7 @@ mod tests {
     uni.set(-1, 0, true);
     uni.set(-1, 1, true);
     uni.set(-1, 2, true);
-    uni.simulate(16);
+    uni.simulate(16).unwrap();
     assert_eq!(uni.debug_root(), vec![
       0b_0000_0000_0000_0000,
       0b_0000_0000_0000_0000,
@@ -1146,4 +1035,704 @@ mod tests {
       0b_0000_0000_0000_0000,
     ]);
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_get() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    uni.set(-7, -7, true);
+    uni.set(3, 1, true);
+    uni.set(100, -20, true);
+    uni.set(3, 1, false);
+    assert!(uni.get(-7, -7));
+    assert!(uni.get(100, -20));
+    assert!(!uni.get(3, 1));
+    assert!(!uni.get(0, 0));
+    assert!(!uni.get(-1000, 1000));
+  }
+
+  #[test]
+  fn test_get_rect() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    uni.set(-3, -1, true);
+    uni.set(0, -2, true);
+    uni.set(-2, 0, true);
+    uni.set(4, 0, true);
+    uni.set(-6, 3, true);
+    uni.set(20, 3, true);
+    assert_eq!(uni.get_rect(&Boundary {
+      left: -6,
+      top: -2,
+      right: 5,
+      bottom: 4,
+    }), vec![
+      0b_0000_0010, 0b_0000_0000,
+      0b_0001_0000, 0b_0000_0000,
+      0b_0000_1000, 0b_0010_0000,
+      0b_0000_0000, 0b_0000_0000,
+      0b_0000_0000, 0b_0000_0000,
+      0b_1000_0000, 0b_0000_0000,
+    ]);
+  }
+
+  #[test]
+  fn test_population() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    assert_eq!(uni.population(), BigUint::zero());
+    uni.set(-3, -1, true);
+    uni.set(0, -2, true);
+    uni.set(-2, 0, true);
+    uni.set(4, 0, true);
+    uni.set(-6, 3, true);
+    uni.set(20, 3, true);
+    assert_eq!(uni.population(), 6u32.into());
+    assert_eq!(uni.population_in(&Boundary {
+      left: -6,
+      top: -2,
+      right: 5,
+      bottom: 4,
+    }), 5u32.into());
+    assert_eq!(uni.population_in(&Boundary {
+      left: -2,
+      top: -2,
+      right: 21,
+      bottom: 1,
+    }), 3u32.into());
+  }
+
+  #[test]
+  fn test_generation() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    uni.set(0, 0, true);
+    assert_eq!(uni.generation(), &BigUint::zero());
+    uni.simulate(7).unwrap();
+    uni.simulate(usize::MAX).unwrap();
+    assert_eq!(uni.generation(), &(BigUint::from(usize::MAX) + 7u32));
+  }
+
+  #[test]
+  fn test_step() {
+    let mut uni1 = Universe::new(GAME_OF_LIFE);
+    let mut uni2 = Universe::new(GAME_OF_LIFE);
+    for &(x, y) in &[(0, 0), (1, 0), (1, 1), (1, 2), (-1, 0), (-1, 1), (-1, 2)] {
+      uni1.set(x, y, true);
+      uni2.set(x, y, true);
+    }
+
+    uni1.set_step(2, 3);
+    uni1.step().unwrap();
+    uni1.step().unwrap();
+    uni2.simulate(16).unwrap();
+    assert_eq!(uni1.generation(), &BigUint::from(16u32));
+    assert_eq!(uni1.debug_root(), uni2.debug_root());
+
+    uni1.set_step(10, 2);
+    uni1.step().unwrap();
+    uni2.simulate(100).unwrap();
+    assert_eq!(uni1.generation(), &BigUint::from(116u32));
+    assert_eq!(uni1.debug_root(), uni2.debug_root());
+
+    // large exponents are clamped instead of overflowing
+    uni1.set_step(1 << 16, u32::MAX);
+    assert_eq!(uni1.step_exponent(), 65532 / 16);
+    uni1.set_step(3, u32::MAX);
+    assert_eq!(uni1.step_exponent(), 65532 / 2);
+    assert!(BigUint::from(3u32).pow(uni1.step_exponent()).bits() <= 65533);
+
+    // a blinker is back after an even number of generations
+    let mut blinker = Universe::new(GAME_OF_LIFE);
+    for x in -1..2 {
+      blinker.set(x, 0, true);
+    }
+    let rect = blinker.boundary();
+    let cells = blinker.get_rect(&rect);
+    blinker.set_step(2, 200);
+    blinker.step().unwrap();
+    assert_eq!(blinker.generation(), &(BigUint::one() << 200usize));
+    assert_eq!(blinker.boundary(), rect);
+    assert_eq!(blinker.get_rect(&rect), cells);
+  }
+
+  #[test]
+  fn test_large_step() {
+    let glider = || {
+      let mut uni = Universe::new(GAME_OF_LIFE);
+      for &(x, y) in &[(1, 0), (2, 1), (0, 2), (1, 2), (2, 2)] {
+        uni.set(x, y, true);
+      }
+      uni
+    };
+
+    // the glider moves one cell diagonally every 4 generations
+    let mut uni = glider();
+    uni.set_step(2, 59);
+    uni.step().unwrap();
+    let d = 1 << 57;
+    assert_eq!(uni.boundary(), Boundary { left: d, top: d, right: d + 3, bottom: d + 3 });
+    assert!(uni.get(d + 1, d));
+    assert!(!uni.get(0, 0));
+    assert_eq!(uni.population(), BigUint::from(5u32));
+
+    // and is removed once it leaves the cells addressed by `i64`
+    for _ in 0..15 {
+      uni.step().unwrap();
+    }
+    assert_eq!(uni.population(), BigUint::zero());
+    assert!(uni.level() <= MAX_LEVEL);
+
+    let mut uni = glider();
+    uni.set_step(2, 100);
+    uni.step().unwrap();
+    assert!(!uni.get(0, 0));
+    assert_eq!(uni.population(), BigUint::zero());
+    assert!(uni.level() <= MAX_LEVEL);
+
+    uni.set(i64::MAX, 0, true);
+    uni.set(0, i64::MIN, true);
+    assert_eq!(uni.population(), BigUint::zero());
+    assert!(!uni.get(i64::MAX, 0));
+    assert!(!uni.get(i64::MIN, i64::MAX));
+  }
+
+  #[test]
+  fn test_hyperspeed() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    // glider
+    uni.set(1, 0, true);
+    uni.set(2, 1, true);
+    uni.set(0, 2, true);
+    uni.set(1, 2, true);
+    uni.set(2, 2, true);
+    uni.set_hyperspeed(true);
+
+    let mut last_exponent = 0;
+    for _ in 0..20 {
+      uni.step().unwrap();
+      assert!(uni.step_exponent() >= last_exponent);
+      last_exponent = uni.step_exponent();
+    }
+    assert!(last_exponent > 0);
+    assert_eq!(uni.population(), BigUint::from(5u32));
+  }
+
+  #[test]
+  fn test_undo_redo() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    assert!(!uni.undo());
+    uni.set(0, 0, true);
+    uni.set(1, 0, true);
+    uni.set(1, 1, true);
+    uni.set(1, 2, true);
+    uni.set(-1, 0, true);
+    uni.set(-1, 1, true);
+    uni.set(-1, 2, true);
+    let gen0 = uni.debug_root();
+
+    uni.checkpoint();
+    uni.simulate(8).unwrap();
+    let gen8 = uni.debug_root();
+
+    uni.checkpoint();
+    uni.set(100, 100, true);
+    uni.store.borrow_mut().gc();
+
+    assert!(uni.undo());
+    assert_eq!(uni.debug_root(), gen8);
+    assert!(uni.undo());
+    assert_eq!(uni.debug_root(), gen0);
+    assert_eq!(uni.generation(), &BigUint::zero());
+    assert!(!uni.undo());
+
+    uni.store.borrow_mut().gc();
+    assert!(uni.redo());
+    assert_eq!(uni.debug_root(), gen8);
+    assert_eq!(uni.generation(), &BigUint::from(8u32));
+    assert!(uni.redo());
+    assert!(uni.get(100, 100));
+    assert!(!uni.redo());
+
+    uni.undo();
+    uni.checkpoint();
+    assert!(!uni.redo());
+  }
+
+  #[test]
+  fn test_history_limit() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    uni.set_history_limit(2);
+    for i in 0..5 {
+      uni.checkpoint();
+      uni.set(i, 0, true);
+    }
+    assert!(uni.undo());
+    assert!(uni.undo());
+    assert!(!uni.undo());
+    assert!(uni.get(2, 0));
+    assert!(!uni.get(3, 0));
+  }
+
+  #[test]
+  fn test_seek() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    let mut expected = Universe::new(GAME_OF_LIFE);
+    for &(x, y) in &[(0, 0), (1, 0), (1, 1), (1, 2), (-1, 0), (-1, 1), (-1, 2)] {
+      uni.set(x, y, true);
+      expected.set(x, y, true);
+    }
+
+    uni.simulate(3).unwrap();
+    uni.record_timeline(10);
+    uni.simulate(64).unwrap();
+    uni.store.borrow_mut().gc();
+    assert_eq!(uni.generation(), &BigUint::from(67u32));
+
+    assert!(!uni.seek(&BigUint::from(2u32)).unwrap());
+
+    assert!(uni.seek(&BigUint::from(37u32)).unwrap());
+    expected.simulate(37).unwrap();
+    assert_eq!(uni.generation(), &BigUint::from(37u32));
+    assert_eq!(uni.debug_root(), expected.debug_root());
+
+    assert!(uni.seek(&BigUint::from(3u32)).unwrap());
+    assert!(uni.seek(&BigUint::from(70u32)).unwrap());
+    expected.simulate(33).unwrap();
+    assert_eq!(uni.debug_root(), expected.debug_root());
+  }
+
+  #[test]
+  fn test_seek_snapshots() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    for &(x, y) in &[(0, -1), (1, -1), (-1, 0), (0, 0), (0, 1)] {
+      uni.set(x, y, true);
+    }
+    uni.record_timeline(10);
+    uni.simulate(100).unwrap();
+    let num_snapshots = |uni: &Universe| {
+      uni.store.borrow_mut().attached_roots(uni.id).snapshots.len()
+    };
+    assert_eq!(num_snapshots(&uni), 11);
+
+    // revisiting recorded generations does not record them again
+    for _ in 0..5 {
+      assert!(uni.seek(&BigUint::from(5u32)).unwrap());
+      assert!(uni.seek(&BigUint::from(95u32)).unwrap());
+    }
+    assert_eq!(num_snapshots(&uni), 11);
+
+    // an edited pattern replaces the recorded generations after it
+    assert!(uni.seek(&BigUint::from(50u32)).unwrap());
+    for &(x, y) in &[(100, 100), (101, 100), (100, 101), (101, 101)] {
+      uni.set(x, y, true);
+    }
+    assert_eq!(num_snapshots(&uni), 6);
+    uni.simulate(20).unwrap();
+    assert_eq!(num_snapshots(&uni), 8);
+    assert!(uni.seek(&BigUint::from(60u32)).unwrap());
+    assert!(uni.get(100, 100));
+  }
+
+  #[test]
+  fn test_edit_after_seek() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    for &(x, y) in &[(0, -1), (1, -1), (-1, 0), (0, 0), (0, 1)] {
+      uni.set(x, y, true);
+    }
+    uni.record_timeline(10);
+    uni.simulate(30).unwrap();
+
+    // a block far from the R-pentomino, which stays until generation 30
+    assert!(uni.seek(&BigUint::from(5u32)).unwrap());
+    for &(x, y) in &[(100, 100), (101, 100), (100, 101), (101, 101)] {
+      uni.set(x, y, true);
+    }
+    uni.simulate(10).unwrap();
+    for &generation in &[12u32, 25, 30] {
+      assert!(uni.seek(&BigUint::from(generation)).unwrap());
+      assert!(uni.get(100, 100), "{}", generation);
+    }
+    assert!(uni.seek(&BigUint::from(3u32)).unwrap());
+    assert!(!uni.get(100, 100));
+  }
+
+  #[test]
+  fn test_shared_store() {
+    let store = NodeStore::new(GAME_OF_LIFE);
+    let mut uni1 = Universe::with_store(&store);
+    let mut uni2 = Universe::with_store(&store);
+    for &(x, y) in &[(0, 0), (1, 0), (1, 1), (1, 2), (-1, 0), (-1, 1), (-1, 2)] {
+      uni1.set(x, y, true);
+      uni2.set(x, y, true);
+    }
+
+    uni1.simulate(8).unwrap();
+    let num_nodes = store.borrow().num_nodes();
+    uni2.simulate(8).unwrap();
+    assert_eq!(store.borrow().num_nodes(), num_nodes);
+    assert_eq!(uni1.debug_root(), uni2.debug_root());
+
+    let rect = Boundary { left: -16, top: -16, right: 16, bottom: 16 };
+    let gen8 = uni1.get_rect(&rect);
+    uni2.set(10, 10, true);
+    uni1.simulate(1).unwrap();
+    let gen9 = uni1.get_rect(&rect);
+    drop(uni1);
+    store.borrow_mut().gc();
+    assert!(uni2.get(10, 10));
+    uni2.set(10, 10, false);
+    assert_eq!(uni2.get_rect(&rect), gen8);
+    uni2.simulate(1).unwrap();
+    assert_eq!(uni2.get_rect(&rect), gen9);
+  }
+
+  #[test]
+  fn test_gc_during_step() {
+    let config = GcConfig { max_nodes: Some(2000), ..GcConfig::default() };
+    let store = NodeStore::with_config(GAME_OF_LIFE, config);
+    let mut uni = Universe::with_store(&store);
+    let mut expected = Universe::new(GAME_OF_LIFE);
+    // R-pentomino
+    for &(x, y) in &[(0, -1), (1, -1), (-1, 0), (0, 0), (0, 1)] {
+      uni.set(x, y, true);
+      expected.set(x, y, true);
+    }
+
+    uni.simulate(1000).unwrap();
+    expected.simulate(1000).unwrap();
+    let rect = expected.boundary();
+    assert_eq!(uni.get_rect(&rect), expected.get_rect(&rect));
+  }
+
+  #[test]
+  fn test_memory_limit() {
+    let config = GcConfig { max_nodes: Some(500), ..GcConfig::default() };
+    let store = NodeStore::with_config(GAME_OF_LIFE, config);
+    let mut uni = Universe::with_store(&store);
+    for &(x, y) in &[(0, -1), (1, -1), (-1, 0), (0, 0), (0, 1)] {
+      uni.set(x, y, true);
+    }
+
+    uni.simulate(4).unwrap();
+    let rect = Boundary { left: -8, top: -8, right: 8, bottom: 8 };
+    let gen4 = uni.get_rect(&rect);
+    assert_eq!(uni.simulate(1 << 20), Err(MemoryLimitExceeded));
+    assert_eq!(uni.generation(), &BigUint::from(4u32));
+    assert_eq!(uni.get_rect(&rect), gen4);
+    assert!(uni.store.borrow().num_nodes() <= 500);
+  }
+
+  #[test]
+  fn test_stats() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    for &(x, y) in &[(0, -1), (1, -1), (-1, 0), (0, 0), (0, 1)] {
+      uni.set(x, y, true);
+    }
+    uni.simulate(64).unwrap();
+    uni.simulate(64).unwrap();
+
+    let stats = uni.stats();
+    assert_eq!(stats.num_nodes(), uni.store.borrow().num_nodes());
+    assert_eq!(stats.nodes_per_level[..3], [0, 0, 0]);
+    assert!(stats.find_hits > 0);
+    assert!(stats.find_misses >= stats.num_nodes() as u64);
+    assert!(stats.memo_hits > 0);
+    assert_eq!(stats.gc_runs, 0);
+    assert!(stats.heap_bytes > stats.num_nodes() * std::mem::size_of::<Node>());
+
+    uni.store.borrow_mut().gc();
+    let after_gc = uni.stats();
+    assert_eq!(after_gc.gc_runs, 1);
+    assert_eq!(after_gc.gc_freed as usize,
+      stats.num_nodes() - after_gc.num_nodes());
+  }
+
+  #[test]
+  fn test_parallel_step() {
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    let mut expected = Universe::new(GAME_OF_LIFE);
+    for &(x, y) in &[(0, -1), (1, -1), (-1, 0), (0, 0), (0, 1)] {
+      uni.set(x, y, true);
+      expected.set(x, y, true);
+    }
+
+    uni.set_threads(4);
+    assert_eq!(uni.threads(), 4);
+    uni.simulate(1000).unwrap();
+    uni.simulate(1000).unwrap();
+    expected.simulate(2000).unwrap();
+    assert_eq!(uni.population(), expected.population());
+    let rect = expected.boundary();
+    assert_eq!(uni.get_rect(&rect), expected.get_rect(&rect));
+  }
+
+  #[test]
+  fn test_parallel_memo() {
+    let config = GcConfig { initial_threshold: usize::MAX, ..GcConfig::default() };
+    let store = NodeStore::with_config(GAME_OF_LIFE, config);
+    let mut uni1 = Universe::with_store(&store);
+    let mut uni2 = Universe::with_store(&store);
+    for &(x, y) in &[(0, -1), (1, -1), (-1, 0), (0, 0), (0, 1)] {
+      uni1.set(x, y, true);
+      uni2.set(x, y, true);
+    }
+    uni1.set_threads(4);
+    uni2.set_threads(4);
+
+    uni1.simulate(1024).unwrap();
+    let stats = store.stats();
+    uni2.simulate(1024).unwrap();
+    let after = store.stats();
+    assert_eq!(after.find_misses, stats.find_misses);
+    assert!(after.memo_hits > stats.memo_hits);
+    let rect = uni1.boundary();
+    assert_eq!(uni2.get_rect(&rect), uni1.get_rect(&rect));
+  }
+
+  #[test]
+  fn test_strobing_rule() {
+    let mut rule = Rule::new();
+    rule.set_birth(0);
+    rule.set_birth(3);
+    rule.set_survival(2);
+    rule.set_survival(3);
+    assert!(rule.is_strobing());
+
+    // naive simulation of the actual cells, whose background flips
+    const R: i64 = 50;
+    let mut cells = vec![vec![false; 2 * R as usize]; 2 * R as usize];
+    let mut background = false;
+    let mut uni = Universe::new(rule);
+    let mut seed = 1u32;
+    for y in -4..4 {
+      for x in -4..4 {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let alive = seed >> 16 & 1 != 0;
+        cells[(y + R) as usize][(x + R) as usize] = alive;
+        uni.set(x, y, alive);
+      }
+    }
+
+    let mut generation = 0;
+    for &num_gen in &[1, 2, 5, 8, 24] {
+      uni.set_threads(num_gen % 2 + 1);
+      uni.simulate(num_gen).unwrap();
+      for _ in 0..num_gen {
+        let get = |x: i64, y: i64| {
+          if !(0..2 * R).contains(&x) || !(0..2 * R).contains(&y) {
+            background
+          } else {
+            cells[y as usize][x as usize]
+          }
+        };
+        let next = (0..2 * R).map(|y| (0..2 * R).map(|x| {
+          let n = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)]
+            .iter()
+            .filter(|&&(dx, dy)| get(x + dx, y + dy))
+            .count() as u8;
+          let (birth, survival) = (n == 0 || n == 3, n == 2 || n == 3);
+          if get(x, y) { survival } else { birth }
+        }).collect::<Vec<_>>()).collect();
+        cells = next;
+        background = !background;
+      }
+      generation += num_gen;
+
+      assert_eq!(uni.background(), background);
+      assert_eq!(uni.get(10 * R, 0), background);
+      for y in -R..R {
+        for x in -R..R {
+          assert_eq!(uni.get(x, y), cells[(y + R) as usize][(x + R) as usize],
+            "generation {} ({}, {})", generation, x, y);
+        }
+      }
+
+      let rect = Boundary { left: -R, top: -R, right: R, bottom: R };
+      let bw = (2 * R as usize + 7) >> 3;
+      let mut expected = vec![0u8; 2 * R as usize * bw];
+      let mut population = 0u32;
+      for (y, row) in cells.iter().enumerate() {
+        for (x, &alive) in row.iter().enumerate() {
+          if alive {
+            expected[y * bw + (x >> 3)] |= 0x80 >> (x & 7);
+            population += 1;
+          }
+        }
+      }
+      assert_eq!(uni.get_rect(&rect), expected, "generation {}", generation);
+      assert_eq!(uni.population_in(&rect), population.into());
+    }
+  }
+
+  #[test]
+  fn test_margolus_rule() {
+    let rules = [
+      // BBM
+      [0, 8, 4, 3, 2, 5, 9, 7, 1, 6, 10, 11, 12, 13, 14, 15],
+      // Critters, whose background flips
+      [15, 14, 13, 3, 11, 5, 6, 1, 7, 9, 10, 2, 12, 4, 8, 0],
+      // Tron
+      [15, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 0],
+    ];
+    for (i, &blocks) in rules.iter().enumerate() {
+      let rule = Rule::margolus(blocks).unwrap();
+
+      // naive simulation of the actual cells
+      const R: i64 = 50;
+      let mut cells = vec![vec![false; 2 * R as usize]; 2 * R as usize];
+      let mut background = false;
+      let mut uni = Universe::new(rule);
+      let mut seed = i as u32 + 1;
+      for y in -4..4 {
+        for x in -4..4 {
+          seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+          let alive = seed >> 16 & 1 != 0;
+          cells[(y + R) as usize][(x + R) as usize] = alive;
+          uni.set(x, y, alive);
+        }
+      }
+
+      let mut generation = 0;
+      for &num_gen in &[1, 2, 5, 8, 24] {
+        uni.set_threads(num_gen % 2 + 1);
+        uni.simulate(num_gen).unwrap();
+        for _ in 0..num_gen {
+          let get = |x: i64, y: i64| {
+            if !(-R..R).contains(&x) || !(-R..R).contains(&y) {
+              background
+            } else {
+              cells[(y + R) as usize][(x + R) as usize]
+            }
+          };
+          let mut next = cells.clone();
+          // blocks are aligned at odd coordinates on odd generations
+          let offset = generation as i64 % 2;
+          for y0 in (-R - 2 + offset..R).step_by(2) {
+            for x0 in (-R - 2 + offset..R).step_by(2) {
+              let block = get(x0, y0) as usize | (get(x0 + 1, y0) as usize) << 1
+                | (get(x0, y0 + 1) as usize) << 2 | (get(x0 + 1, y0 + 1) as usize) << 3;
+              for j in 0..4 {
+                let (x, y) = (x0 + (j & 1), y0 + (j >> 1));
+                if (-R..R).contains(&x) && (-R..R).contains(&y) {
+                  next[(y + R) as usize][(x + R) as usize] = blocks[block] >> j & 1 != 0;
+                }
+              }
+            }
+          }
+          cells = next;
+          background = blocks[if background { 15 } else { 0 }] != 0;
+          generation += 1;
+        }
+
+        assert_eq!(uni.background(), background);
+        for y in -R..R {
+          for x in -R..R {
+            assert_eq!(uni.get(x, y), cells[(y + R) as usize][(x + R) as usize],
+              "{} generation {} ({}, {})", rule, generation, x, y);
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_set_rule() {
+    let mut highlife = Rule::new();
+    highlife.set_birth(3);
+    highlife.set_birth(6);
+    highlife.set_survival(2);
+    highlife.set_survival(3);
+
+    // R-pentomino
+    let mut uni = Universe::new(GAME_OF_LIFE);
+    for &(x, y) in &[(1, 0), (2, 0), (0, 1), (1, 1), (1, 2)] {
+      uni.set(x, y, true);
+    }
+    uni.simulate(100).unwrap();
+
+    // the same pattern in a new universe of the new rule
+    let mut expected = Universe::new(highlife);
+    let rect = uni.boundary();
+    for y in rect.top..rect.bottom {
+      for x in rect.left..rect.right {
+        expected.set(x, y, uni.get(x, y));
+      }
+    }
+
+    uni.set_rule(highlife).unwrap();
+    assert_eq!(uni.rule().to_string(), "B36/S23");
+    uni.simulate(300).unwrap();
+    expected.simulate(300).unwrap();
+    let rect = expected.boundary();
+    assert_eq!(uni.boundary(), rect);
+    assert_eq!(uni.get_rect(&rect), expected.get_rect(&rect));
+
+    // the background of AntiLife is stored inverted on every generation
+    let mut antilife = Rule::new();
+    (0..=8).filter(|&n| n != 5 && n != 6).for_each(|n| antilife.set_birth(n));
+    (0..=8).filter(|&n| n != 5).for_each(|n| antilife.set_survival(n));
+    assert!(uni.set_rule(antilife).is_err());
+    assert_eq!(uni.rule().to_string(), "B36/S23");
+
+    // the topology of the rule becomes the grid
+    let torus = "B36/S23:T20,10".parse::<Rule>().unwrap();
+    uni.set_rule(torus).unwrap();
+    assert_eq!(uni.topology(), &Topology::Torus { width: 20, height: 10 });
+    assert_eq!(uni.rule(), torus);
+    assert_eq!(uni.rule().to_string(), "B36/S23:T20,10");
+    assert_eq!(uni.store().rule().to_string(), "B36/S23");
+    let grid = uni.topology().grid().unwrap();
+    assert_eq!(uni.population_in(&grid), uni.population());
+    assert_eq!(Universe::new(torus).topology(), uni.topology());
+  }
+
+  #[test]
+  fn test_set_rule_shared_store() {
+    let mut highlife = Rule::new();
+    highlife.set_birth(3);
+    highlife.set_birth(6);
+    highlife.set_survival(2);
+    highlife.set_survival(3);
+
+    // replicator, which grows in HighLife and dies out in Life
+    let cells = [(2, 0), (3, 0), (4, 0), (1, 1), (4, 1), (0, 2), (4, 2),
+      (0, 3), (3, 3), (0, 4), (1, 4), (2, 4)];
+    let store = NodeStore::new(GAME_OF_LIFE);
+    let mut uni = Universe::with_store(&store);
+    let mut other = Universe::with_store(&store);
+    let mut expected = Universe::new(GAME_OF_LIFE);
+    for &(x, y) in &cells {
+      uni.set(x, y, true);
+      other.set(x, y, true);
+      expected.set(x, y, true);
+    }
+    uni.checkpoint();
+    uni.simulate(1).unwrap();
+    other.simulate(1).unwrap();
+    expected.simulate(1).unwrap();
+
+    uni.set_rule(highlife).unwrap();
+    assert_eq!(uni.rule().to_string(), "B36/S23");
+    assert_eq!(other.rule().to_string(), "B3/S23");
+    assert_eq!(store.rule().to_string(), "B3/S23");
+    assert_eq!(uni.get_rect(&uni.boundary()), expected.get_rect(&uni.boundary()));
+
+    // the other universe keeps simulating Life on the old store
+    other.simulate(50).unwrap();
+    expected.simulate(50).unwrap();
+    let rect = expected.boundary();
+    assert_eq!(other.boundary(), rect);
+    assert_eq!(other.get_rect(&rect), expected.get_rect(&rect));
+
+    // the saved states move with the universe
+    let mut replicator = Universe::new(highlife);
+    for &(x, y) in &cells {
+      replicator.set(x, y, true);
+    }
+    assert!(uni.undo());
+    assert_eq!(uni.generation(), &BigUint::zero());
+    uni.simulate(50).unwrap();
+    replicator.simulate(50).unwrap();
+    let rect = replicator.boundary();
+    assert_eq!(uni.boundary(), rect);
+    assert_eq!(uni.get_rect(&rect), replicator.get_rect(&rect));
+    assert!(uni.population() > other.population());
+  }
+}
@@ -6,7 +6,7 @@ fn gen10000() {
   let mut uni = algo::rle::read(src).unwrap();
   let expected = fs::read_to_string("tests/fixtures/Breeder_gen10000.rle").unwrap();
 
-  uni.simulate(10000);
+  uni.simulate(10000).unwrap();
 
   let actual = algo::rle::write(&uni);
 
@@ -19,7 +19,7 @@ fn gen515() {
   let mut uni = algo::rle::read(src).unwrap();
   let expected = fs::read_to_string("tests/fixtures/Breeder_gen515.rle").unwrap();
 
-  uni.simulate(515);
+  uni.simulate(515).unwrap();
 
   let actual = algo::rle::write(&uni);
 
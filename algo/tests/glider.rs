@@ -7,23 +7,23 @@ fn multi_simulate() {
   let glider_3 = "x = 3, y = 3, rule = B3/S23\no$b2o$2o!\n";
   let mut uni = algo::rle::read(glider_0).unwrap();
 
-  uni.simulate(39);
+  uni.simulate(39).unwrap();
   let actual = algo::rle::write(&uni);
 
-  assert_eq!(glider_3, &actual);
+  assert_eq!(format!("#CXRLE Gen=39\n{}", glider_3), actual);
 
-  uni.simulate(1);
+  uni.simulate(1).unwrap();
   let actual = algo::rle::write(&uni);
 
-  assert_eq!(glider_0, &actual);
+  assert_eq!(format!("#CXRLE Gen=40\n{}", glider_0), actual);
 
-  uni.simulate(1);
+  uni.simulate(1).unwrap();
   let actual = algo::rle::write(&uni);
 
-  assert_eq!(glider_1, &actual);
+  assert_eq!(format!("#CXRLE Gen=41\n{}", glider_1), actual);
 
-  uni.simulate(1);
+  uni.simulate(1).unwrap();
   let actual = algo::rle::write(&uni);
 
-  assert_eq!(glider_2, &actual);
+  assert_eq!(format!("#CXRLE Gen=42\n{}", glider_2), actual);
 }
\ No newline at end of file
@@ -8,13 +8,13 @@ const univPattern = document.querySelector('#universe-pattern') as HTMLSelectEle
 const zoomInput = document.querySelector('#zoom-input') as HTMLInputElement
 const zoomLabel = document.querySelector('#zoom-label') as HTMLSpanElement
 const generationLabel = document.querySelector('#generation-label') as HTMLSpanElement
+const memoryLabel = document.querySelector('#memory-label') as HTMLSpanElement
 const stepInput = document.querySelector('#step-input') as HTMLInputElement
 
 const DEFAULT_ZOOM = 4
 
 let universe: Universe | null = null
 let curPattern = ''
-let generation = 0
 let zoom = 1
 let step = 1
 
@@ -41,7 +41,7 @@ function loadPattern(patternRle: string) {
   }
 
   curPattern = patternRle
-  setGeneration(0)
+  updateStatus()
 }
 
 function setZoom(newZoom: number) {
@@ -51,9 +51,9 @@ function setZoom(newZoom: number) {
   zoomLabel.textContent = (newZoom * 100 + 0.5 | 0) + '%'
 }
 
-function setGeneration(newGen: number) {
-  generation = newGen
-  generationLabel.textContent = String(newGen)
+function updateStatus() {
+  generationLabel.textContent = universe!.generation()
+  memoryLabel.textContent = (universe!.heap_bytes() / (1 << 20)).toFixed(1) + ' MiB'
 }
 
 function resetTranslate() {
@@ -67,7 +67,7 @@ function reset() {
 
 function evolve() {
   universe!.simulate(step)
-  setGeneration(generation + step)
+  updateStatus()
   renderUniverse()
 }
 
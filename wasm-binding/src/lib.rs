@@ -5,6 +5,12 @@ use algo::universe::Boundary;
 #[wasm_bindgen]
 pub struct Universe(universe::Universe);
 
+impl Default for Universe {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 #[wasm_bindgen]
 impl Universe {
   #[wasm_bindgen(constructor)]
@@ -20,8 +26,41 @@ impl Universe {
     self.0.set(x as i64, y as i64, alive)
   }
 
-  pub fn simulate(&mut self, num_gen: usize) {
-    self.0.simulate(num_gen)
+  pub fn simulate(&mut self, num_gen: usize) -> Result<(), JsValue> {
+    self.0.simulate(num_gen).map_err(|err| err.to_string().into())
+  }
+
+  pub fn set_step(&mut self, base: u32, exponent: u32) {
+    self.0.set_step(base, exponent)
+  }
+
+  pub fn set_hyperspeed(&mut self, hyperspeed: bool) {
+    self.0.set_hyperspeed(hyperspeed)
+  }
+
+  pub fn step(&mut self) -> Result<(), JsValue> {
+    self.0.step().map_err(|err| err.to_string().into())
+  }
+
+  pub fn checkpoint(&mut self) {
+    self.0.checkpoint()
+  }
+
+  pub fn undo(&mut self) -> bool {
+    self.0.undo()
+  }
+
+  pub fn redo(&mut self) -> bool {
+    self.0.redo()
+  }
+
+  pub fn generation(&self) -> String {
+    self.0.generation().to_string()
+  }
+
+  /// Approximate heap bytes used by the nodes.
+  pub fn heap_bytes(&self) -> usize {
+    self.0.stats().heap_bytes
   }
 
   pub fn write_cells(&self, viewport: &Viewport, f: &js_sys::Function) {
@@ -37,7 +76,7 @@ impl Universe {
         | (cell.ne as u64) << 32
         | (cell.sw as u64) << 16
         | (cell.se as u64);
-      let b = unsafe { std::mem::transmute::<_, f64>(b) };
+      let b = f64::from_bits(b);
       let x = cell.x as i32;
       let y = cell.y as i32;
       f.call3(&null, &JsValue::from(x), &JsValue::from(y), &JsValue::from(b))